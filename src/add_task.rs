@@ -0,0 +1,782 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use iced::{Task as Command, widget::text_editor};
+use reqwest::Url;
+
+use crate::{
+  app_state::{AppDir, AppState},
+  bz_downloader::Message,
+  bz_task::{
+    BzContainer, BzDashSelection, BzRecordLimit, BzRenditionSelection,
+    BzTaskInfo, BzTaskMessage, BzTaskStatus, BzTaskType, BzVariantPolicy,
+    DEFAULT_CONCURRENCY, MAX_CONCURRENCY,
+  },
+  cookies::{self, BzCookie},
+  dash::{self, DashRepresentation, DashTrackKind},
+  error::BzResult,
+  http::{self, BzProxyConfig, BzRequestConfig, BzRetryPolicy, BzTaskProxy},
+  m3u8::{self, M3u8Rendition, M3u8RenditionKind},
+  rate_limit::BzSpeedLimit,
+};
+
+// 识别任务类型时读取的内容长度
+const PROBE_BYTES: usize = 1024;
+
+// 新建任务的表单
+#[derive(Debug, Default)]
+pub struct AddTaskForm {
+  pub url: String,
+  // 根据链接自动识别 识别不了的时候提交时请求一次链接再判断
+  pub kind: Option<BzTaskType>,
+  pub folder: String,
+  pub file_name: String,
+  // 用户修改过文件名之后不再根据链接自动生成
+  pub file_name_edited: bool,
+  // TS 分片是否转换为 mp4
+  pub container: BzContainer,
+  // 每行一个 Name: value
+  pub headers: text_editor::Content,
+  // 为空时使用默认的 User-Agent
+  pub user_agent: String,
+  pub cookies: Vec<BzCookie>,
+  pub proxy: BzTaskProxy,
+  pub speed_limit: BzSpeedLimit,
+  pub start_now: bool,
+  // 为空时不等待 格式 2025-03-03 01:00
+  pub start_at: String,
+  // 直播录制的上限 为空时不限制
+  pub record_minutes: String,
+  pub record_megabytes: String,
+  // 为空时选择最高码率 例如 最低 1920x1080 avc1
+  pub variant: String,
+  // 同时下载的分片数量 为空时使用默认值
+  pub concurrency: String,
+  pub errors: Vec<String>,
+  // 正在请求链接判断任务类型
+  pub probing: bool,
+  // DASH 可以选择的 Representation 没有获取时自动选择
+  pub representations: Option<Vec<DashRepresentation>>,
+  pub loading_representations: bool,
+  pub dash: BzDashSelection,
+  // HLS 可以选择的音轨和字幕 没有获取时使用默认的音轨
+  pub renditions: Option<Vec<M3u8Rendition>>,
+  pub loading_renditions: bool,
+  pub rendition_selection: BzRenditionSelection,
+}
+
+#[derive(Debug, Clone)]
+pub enum AddTaskMessage {
+  Open,
+  Cancel,
+  UrlChanged(String),
+  FolderChanged(String),
+  PickFolder,
+  FolderPicked(Option<PathBuf>),
+  FileNameChanged(String),
+  ContainerSelected(BzContainer),
+  HeadersEdited(text_editor::Action),
+  UserAgentChanged(String),
+  ImportCookies,
+  CookiesImported(Option<Result<Vec<BzCookie>, String>>),
+  ClearCookies,
+  ProxySelected(BzTaskProxy),
+  ProxyUrlChanged(String),
+  NoProxyChanged(String),
+  SpeedLimitSelected(BzSpeedLimit),
+  StartNowToggled(bool),
+  StartAtChanged(String),
+  RecordMinutesChanged(String),
+  RecordMegabytesChanged(String),
+  VariantChanged(String),
+  ConcurrencyChanged(String),
+  Submit,
+  Probed(Result<BzTaskType, String>),
+  LoadRepresentations,
+  RepresentationsLoaded(Result<Vec<DashRepresentation>, String>),
+  RepresentationSelected(DashRepresentation),
+  LoadRenditions,
+  RenditionsLoaded(Result<Vec<M3u8Rendition>, String>),
+  RenditionSelected(M3u8Rendition),
+}
+
+// 检查通过的表单字段
+struct BzValidatedForm {
+  url: Url,
+  dest: PathBuf,
+  request: BzRequestConfig,
+  record_limit: BzRecordLimit,
+  start_at: Option<DateTime<Local>>,
+  variant: BzVariantPolicy,
+  concurrency: usize,
+}
+
+impl AddTaskForm {
+  pub fn new() -> Self {
+    let folder = directories::UserDirs::new()
+      .and_then(|dirs| dirs.download_dir().map(Path::to_path_buf))
+      .unwrap_or_else(|| PathBuf::from("."));
+    Self {
+      folder: folder.display().to_string(),
+      start_now: true,
+      ..Default::default()
+    }
+  }
+
+  fn set_url(&mut self, url: String) {
+    let parsed = Url::parse(url.trim()).ok();
+    self.kind = parsed.as_ref().and_then(BzTaskType::from_url);
+    self.probing = false;
+    self.representations = None;
+    self.loading_representations = false;
+    self.dash = BzDashSelection::default();
+    self.renditions = None;
+    self.loading_renditions = false;
+    self.rendition_selection = BzRenditionSelection::default();
+    if !self.file_name_edited {
+      self.file_name = parsed
+        .as_ref()
+        .map(|url| suggest_file_name(url, self.container.extension()))
+        .unwrap_or_default();
+    }
+    self.url = url;
+  }
+
+  fn parse_headers(&self) -> Result<Vec<(String, String)>, Vec<String>> {
+    let mut headers = Vec::new();
+    let mut errors = Vec::new();
+    for line in self.headers.lines() {
+      let line = line.trim();
+      if line.is_empty() {
+        continue;
+      }
+      let Some((name, value)) = line.split_once(':') else {
+        errors.push(format!("请求头格式应为 Name: value : {}", line));
+        continue;
+      };
+      match http::parse_header(name, value) {
+        Ok(_) => {
+          headers.push((name.trim().to_string(), value.trim().to_string()))
+        }
+        Err(err) => errors.push(format!("{}", err)),
+      }
+    }
+    match errors.is_empty() {
+      true => Ok(headers),
+      false => Err(errors),
+    }
+  }
+
+  // 代理跟随全局设置时使用 global
+  fn parse_request(
+    &self, global: &BzProxyConfig,
+  ) -> Result<BzRequestConfig, Vec<String>> {
+    let headers = self.parse_headers()?;
+    let user_agent = match self.user_agent.trim() {
+      "" => None,
+      user_agent => match http::parse_header("User-Agent", user_agent) {
+        Ok(_) => Some(user_agent.to_string()),
+        Err(err) => return Err(vec![format!("{}", err)]),
+      },
+    };
+    let proxy = self.proxy.resolve(global);
+    if let BzTaskProxy::Custom(config) = &proxy {
+      config
+        .to_proxy()
+        .map_err(|err| vec![format!("代理无效: {}", err)])?;
+    }
+    Ok(BzRequestConfig {
+      headers,
+      user_agent,
+      cookies: self.cookies.clone(),
+      proxy,
+    })
+  }
+
+  fn parse_record_limit(&self) -> Result<BzRecordLimit, Vec<String>> {
+    let parse = |value: &str, name: &str| match value.trim() {
+      "" => Ok(None),
+      value => match value.parse::<u64>() {
+        Ok(value) if value > 0 => Ok(Some(value)),
+        _ => Err(format!("{}应为正整数: {}", name, value)),
+      },
+    };
+    let minutes = parse(&self.record_minutes, "录制时长");
+    let megabytes = parse(&self.record_megabytes, "录制大小");
+    match (minutes, megabytes) {
+      (Ok(minutes), Ok(megabytes)) => Ok(BzRecordLimit {
+        max_duration_secs: minutes.map(|minutes| minutes * 60),
+        max_bytes: megabytes.map(|megabytes| megabytes * 1024 * 1024),
+      }),
+      (minutes, megabytes) => {
+        Err(minutes.err().into_iter().chain(megabytes.err()).collect())
+      }
+    }
+  }
+
+  // 宽x高 按照分辨率选择 其余的按照 CODECS 选择
+  fn parse_variant(&self) -> Result<BzVariantPolicy, String> {
+    let variant = self.variant.trim();
+    let resolution =
+      variant.split_once(['x', 'X']).and_then(|(width, height)| {
+        Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+      });
+    match (variant, resolution) {
+      ("" | "最高", _) => Ok(BzVariantPolicy::HighestBandwidth),
+      ("最低", _) => Ok(BzVariantPolicy::LowestBandwidth),
+      (_, Some((width, height))) => {
+        Ok(BzVariantPolicy::Resolution { width, height })
+      }
+      (codec, None)
+        if codec.chars().all(|c| c.is_ascii_alphanumeric() || c == '.') =>
+      {
+        Ok(BzVariantPolicy::Codec(codec.to_string()))
+      }
+      _ => Err(format!("码流选择无效: {}", variant)),
+    }
+  }
+
+  fn parse_concurrency(&self) -> Result<usize, String> {
+    match self.concurrency.trim() {
+      "" => Ok(DEFAULT_CONCURRENCY),
+      value => match value.parse::<usize>() {
+        Ok(value) if (1..=MAX_CONCURRENCY).contains(&value) => Ok(value),
+        _ => Err(format!("并发数应为 1 到 {}: {}", MAX_CONCURRENCY, value)),
+      },
+    }
+  }
+
+  // 本地时间 夏令时切换时不存在或者有歧义的时间视为无效
+  fn parse_start_at(&self) -> Result<Option<DateTime<Local>>, String> {
+    let start_at = self.start_at.trim();
+    if start_at.is_empty() {
+      return Ok(None);
+    }
+    NaiveDateTime::parse_from_str(start_at, "%Y-%m-%d %H:%M")
+      .ok()
+      .and_then(|start_at| Local.from_local_datetime(&start_at).single())
+      .map(Some)
+      .ok_or_else(|| format!("开始时间无效: {}", start_at))
+  }
+
+  // 检查除了任务类型以外的所有字段
+  fn validate(
+    &self, app_state: &AppState,
+  ) -> Result<BzValidatedForm, Vec<String>> {
+    let mut errors = Vec::new();
+    let url = match Url::parse(self.url.trim()) {
+      Ok(url) if matches!(url.scheme(), "http" | "https") => Some(url),
+      Ok(url) => {
+        errors.push(format!("不支持的协议: {}", url.scheme()));
+        None
+      }
+      Err(err) => {
+        errors.push(format!("链接无效: {}", err));
+        None
+      }
+    };
+
+    let folder = PathBuf::from(self.folder.trim());
+    if !folder.is_dir() {
+      errors.push(format!("保存目录不存在: {}", folder.display()));
+    }
+    let file_name = self.file_name.trim();
+    if file_name.is_empty() {
+      errors.push("文件名不能为空".to_string());
+    } else if file_name.contains(['/', '\\']) {
+      errors.push(format!("文件名不能包含路径分隔符: {}", file_name));
+    }
+    let dest = folder.join(file_name);
+    if !file_name.is_empty() && dest.exists() {
+      errors.push(format!("文件已存在: {}", dest.display()));
+    }
+    if app_state.tasks.values().any(|task| task.info.dest == dest) {
+      errors.push(format!("已有任务保存到: {}", dest.display()));
+    }
+
+    let request = self
+      .parse_request(&app_state.settings.proxy)
+      .unwrap_or_else(|request_errors| {
+        errors.extend(request_errors);
+        BzRequestConfig::default()
+      });
+
+    let record_limit =
+      self.parse_record_limit().unwrap_or_else(|limit_errors| {
+        errors.extend(limit_errors);
+        BzRecordLimit::default()
+      });
+
+    let start_at = self.parse_start_at().unwrap_or_else(|error| {
+      errors.push(error);
+      None
+    });
+
+    let variant = self.parse_variant().unwrap_or_else(|error| {
+      errors.push(error);
+      BzVariantPolicy::default()
+    });
+
+    let concurrency = self.parse_concurrency().unwrap_or_else(|error| {
+      errors.push(error);
+      DEFAULT_CONCURRENCY
+    });
+
+    match (url, errors.is_empty()) {
+      (Some(url), true) => Ok(BzValidatedForm {
+        url,
+        dest,
+        request,
+        record_limit,
+        start_at,
+        variant,
+        concurrency,
+      }),
+      _ => Err(errors),
+    }
+  }
+
+  // 当前选择的 Representation None 表示自动选择
+  pub fn selected_representation(
+    &self, kind: DashTrackKind,
+  ) -> Option<&DashRepresentation> {
+    let id = match kind {
+      DashTrackKind::Video => self.dash.video.as_ref(),
+      DashTrackKind::Audio => self.dash.audio.as_ref(),
+    }?;
+    self
+      .representations
+      .iter()
+      .flatten()
+      .find(|representation| &representation.id == id)
+  }
+
+  pub fn selected_rendition(
+    &self, kind: M3u8RenditionKind,
+  ) -> Option<&M3u8Rendition> {
+    let name = match kind {
+      M3u8RenditionKind::Audio => self.rendition_selection.audio.as_ref(),
+      M3u8RenditionKind::Subtitles => {
+        self.rendition_selection.subtitles.as_ref()
+      }
+    }?;
+    self
+      .renditions
+      .iter()
+      .flatten()
+      .find(|rendition| rendition.kind == kind && &rendition.name == name)
+  }
+
+  // 获取码流或者音轨列表时需要的链接和请求头
+  fn request(
+    &mut self, global: &BzProxyConfig,
+  ) -> Option<(Url, BzRequestConfig)> {
+    let url = match Url::parse(self.url.trim()) {
+      Ok(url) => url,
+      Err(err) => {
+        self.errors = vec![format!("链接无效: {}", err)];
+        return None;
+      }
+    };
+    match self.parse_request(global) {
+      Ok(request) => {
+        self.errors.clear();
+        Some((url, request))
+      }
+      Err(errors) => {
+        self.errors = errors;
+        None
+      }
+    }
+  }
+
+  // 自动生成的文件名跟随选择的格式修改扩展名
+  fn set_container(&mut self, container: BzContainer) {
+    self.container = container;
+    if !self.file_name_edited && !self.file_name.is_empty() {
+      self.file_name = Path::new(&self.file_name)
+        .with_extension(container.extension())
+        .to_string_lossy()
+        .to_string();
+    }
+  }
+}
+
+// 取链接中最后一段作为文件名
+fn suggest_file_name(url: &Url, extension: &str) -> String {
+  let stem = url
+    .path_segments()
+    .and_then(|segments| segments.filter(|s| !s.is_empty()).last())
+    .map(|segment| match segment.rsplit_once('.') {
+      Some((stem, _)) => stem,
+      None => segment,
+    })
+    .filter(|stem| !stem.is_empty())
+    .unwrap_or("video");
+  format!("{}.{}", stem, extension)
+}
+
+// 普通文件使用链接中原本的文件名
+fn original_file_name(url: &Url) -> Option<String> {
+  url
+    .path_segments()?
+    .rfind(|segment| !segment.is_empty())
+    .map(str::to_string)
+}
+
+// 每个任务使用单独的缓存目录
+fn cache_dir(dest: &Path) -> PathBuf {
+  let stem = dest
+    .file_stem()
+    .map(|stem| stem.to_string_lossy().to_string())
+    .unwrap_or_default();
+  let millis = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_millis();
+  AppDir().cache_dir().join(format!("{}-{}", stem, millis))
+}
+
+async fn probe_task_type(
+  url: Url, request: BzRequestConfig,
+) -> Result<BzTaskType, String> {
+  let client = http::build_client(&request).map_err(|err| err.to_string())?;
+  let (content_type, head) = http::get_content_head(&client, url, PROBE_BYTES)
+    .await
+    .map_err(|err| format!("无法访问链接: {}", err))?;
+  BzTaskType::detect(content_type.as_deref(), &head).ok_or_else(|| {
+    format!(
+      "无法识别任务类型 Content-Type: {}",
+      content_type.as_deref().unwrap_or("无")
+    )
+  })
+}
+
+fn submit(app_state: &mut AppState) -> Command<Message> {
+  let Some(form) = app_state.add_task_form.as_ref() else {
+    return Command::none();
+  };
+  let BzValidatedForm {
+    url: src,
+    dest,
+    request,
+    record_limit,
+    start_at,
+    variant,
+    concurrency,
+  } = match form.validate(app_state) {
+    Ok(fields) => fields,
+    Err(errors) => {
+      app_state.add_task_form.as_mut().unwrap().errors = errors;
+      return Command::none();
+    }
+  };
+  let Some(kind) = form.kind.clone() else {
+    let form = app_state.add_task_form.as_mut().unwrap();
+    form.errors.clear();
+    form.probing = true;
+    return Command::perform(probe_task_type(src, request), |res| {
+      Message::AddTask(AddTaskMessage::Probed(res))
+    });
+  };
+  // 不立即开始的任务添加为暂停状态
+  let status = match form.start_now {
+    true => BzTaskStatus::Queued,
+    false => BzTaskStatus::Stopped,
+  };
+  let task_info = BzTaskInfo {
+    src,
+    cache: cache_dir(&dest),
+    dest,
+    kind,
+    status,
+    variant,
+    concurrency,
+    retry: BzRetryPolicy::default(),
+    headers: request.headers,
+    user_agent: request.user_agent,
+    cookies: request.cookies,
+    // 保存用户的选择 全局设置修改后跟随变化
+    proxy: form.proxy.clone(),
+    speed_limit: form.speed_limit,
+    start_at,
+    record_limit,
+    container: form.container,
+    dash: form.dash.clone(),
+    renditions: form.rendition_selection.clone(),
+  };
+  app_state.add_task_form = None;
+  Command::done(Message::BzTask(BzTaskMessage::AddTask(task_info)))
+}
+
+pub fn deal_add_task_message(
+  app_state: &mut AppState, message: AddTaskMessage,
+) -> BzResult<Command<Message>> {
+  if let AddTaskMessage::Open = message {
+    app_state.add_task_form = Some(AddTaskForm::new());
+    return Ok(Command::none());
+  }
+  // 表单已经关闭 忽略之前的异步结果
+  let Some(form) = app_state.add_task_form.as_mut() else {
+    return Ok(Command::none());
+  };
+  let cmd = match message {
+    AddTaskMessage::Open => Command::none(),
+    AddTaskMessage::Cancel => {
+      app_state.add_task_form = None;
+      Command::none()
+    }
+    AddTaskMessage::UrlChanged(url) => {
+      form.set_url(url);
+      Command::none()
+    }
+    AddTaskMessage::FolderChanged(folder) => {
+      form.folder = folder;
+      Command::none()
+    }
+    AddTaskMessage::PickFolder => {
+      let folder = form.folder.clone();
+      Command::perform(
+        async move {
+          rfd::AsyncFileDialog::new()
+            .set_directory(folder)
+            .pick_folder()
+            .await
+            .map(|handle| handle.path().to_path_buf())
+        },
+        |folder| Message::AddTask(AddTaskMessage::FolderPicked(folder)),
+      )
+    }
+    AddTaskMessage::FolderPicked(folder) => {
+      if let Some(folder) = folder {
+        form.folder = folder.display().to_string();
+      }
+      Command::none()
+    }
+    AddTaskMessage::FileNameChanged(file_name) => {
+      form.file_name = file_name;
+      form.file_name_edited = true;
+      Command::none()
+    }
+    AddTaskMessage::ContainerSelected(container) => {
+      form.set_container(container);
+      Command::none()
+    }
+    AddTaskMessage::HeadersEdited(action) => {
+      form.headers.perform(action);
+      Command::none()
+    }
+    AddTaskMessage::UserAgentChanged(user_agent) => {
+      form.user_agent = user_agent;
+      Command::none()
+    }
+    AddTaskMessage::ImportCookies => Command::perform(
+      async {
+        let handle = rfd::AsyncFileDialog::new()
+          .add_filter("cookies.txt", &["txt"])
+          .pick_file()
+          .await?;
+        let content = match tokio::fs::read_to_string(handle.path()).await {
+          Ok(content) => content,
+          Err(err) => {
+            return Some(Err(format!("无法读取 cookie 文件: {}", err)));
+          }
+        };
+        Some(
+          cookies::parse_netscape_cookies(&content)
+            .map_err(|err| format!("cookie 文件格式错误: {}", err)),
+        )
+      },
+      |res| Message::AddTask(AddTaskMessage::CookiesImported(res)),
+    ),
+    AddTaskMessage::CookiesImported(res) => {
+      match res {
+        Some(Ok(cookies)) => {
+          form.errors.clear();
+          form.cookies = cookies;
+        }
+        Some(Err(error)) => form.errors = vec![error],
+        // 没有选择文件
+        None => {}
+      }
+      Command::none()
+    }
+    AddTaskMessage::ClearCookies => {
+      form.cookies.clear();
+      Command::none()
+    }
+    AddTaskMessage::ProxySelected(proxy) => {
+      form.proxy = proxy;
+      Command::none()
+    }
+    AddTaskMessage::ProxyUrlChanged(url) => {
+      if let BzTaskProxy::Custom(config) = &mut form.proxy {
+        config.url = url;
+      }
+      Command::none()
+    }
+    AddTaskMessage::NoProxyChanged(no_proxy) => {
+      if let BzTaskProxy::Custom(config) = &mut form.proxy {
+        config.no_proxy = no_proxy;
+      }
+      Command::none()
+    }
+    AddTaskMessage::SpeedLimitSelected(speed_limit) => {
+      form.speed_limit = speed_limit;
+      Command::none()
+    }
+    AddTaskMessage::StartNowToggled(start_now) => {
+      form.start_now = start_now;
+      Command::none()
+    }
+    AddTaskMessage::StartAtChanged(start_at) => {
+      form.start_at = start_at;
+      Command::none()
+    }
+    AddTaskMessage::RecordMinutesChanged(minutes) => {
+      form.record_minutes = minutes;
+      Command::none()
+    }
+    AddTaskMessage::RecordMegabytesChanged(megabytes) => {
+      form.record_megabytes = megabytes;
+      Command::none()
+    }
+    AddTaskMessage::VariantChanged(variant) => {
+      form.variant = variant;
+      Command::none()
+    }
+    AddTaskMessage::ConcurrencyChanged(concurrency) => {
+      form.concurrency = concurrency;
+      Command::none()
+    }
+    AddTaskMessage::Submit => submit(app_state),
+    // 请求期间链接被修改过
+    AddTaskMessage::Probed(_) if !form.probing => Command::none(),
+    AddTaskMessage::Probed(res) => {
+      form.probing = false;
+      match res {
+        Ok(kind) => {
+          if kind == BzTaskType::Http && !form.file_name_edited {
+            let url = Url::parse(form.url.trim()).ok();
+            if let Some(file_name) = url.as_ref().and_then(original_file_name) {
+              form.file_name = file_name;
+            }
+          }
+          form.kind = Some(kind);
+          submit(app_state)
+        }
+        Err(error) => {
+          form.errors = vec![error];
+          Command::none()
+        }
+      }
+    }
+    AddTaskMessage::LoadRepresentations => {
+      let Some((url, request)) = form.request(&app_state.settings.proxy) else {
+        return Ok(Command::none());
+      };
+      form.loading_representations = true;
+      Command::perform(dash::list_representations(url, request), |res| {
+        Message::AddTask(AddTaskMessage::RepresentationsLoaded(res))
+      })
+    }
+    // 请求期间链接被修改过
+    AddTaskMessage::RepresentationsLoaded(_)
+      if !form.loading_representations =>
+    {
+      Command::none()
+    }
+    AddTaskMessage::RepresentationsLoaded(res) => {
+      form.loading_representations = false;
+      match res {
+        Ok(representations) => form.representations = Some(representations),
+        Err(error) => form.errors = vec![error],
+      }
+      Command::none()
+    }
+    AddTaskMessage::RepresentationSelected(representation) => {
+      match representation.kind {
+        DashTrackKind::Video => form.dash.video = Some(representation.id),
+        DashTrackKind::Audio => form.dash.audio = Some(representation.id),
+      }
+      Command::none()
+    }
+    AddTaskMessage::LoadRenditions => {
+      let Some((url, request)) = form.request(&app_state.settings.proxy) else {
+        return Ok(Command::none());
+      };
+      form.loading_renditions = true;
+      Command::perform(m3u8::list_renditions(url, request), |res| {
+        Message::AddTask(AddTaskMessage::RenditionsLoaded(res))
+      })
+    }
+    // 请求期间链接被修改过
+    AddTaskMessage::RenditionsLoaded(_) if !form.loading_renditions => {
+      Command::none()
+    }
+    AddTaskMessage::RenditionsLoaded(res) => {
+      form.loading_renditions = false;
+      match res {
+        Ok(renditions) => form.renditions = Some(renditions),
+        Err(error) => form.errors = vec![error],
+      }
+      Command::none()
+    }
+    AddTaskMessage::RenditionSelected(rendition) => {
+      let selection = &mut form.rendition_selection;
+      match rendition.kind {
+        M3u8RenditionKind::Audio => selection.audio = Some(rendition.name),
+        M3u8RenditionKind::Subtitles => {
+          selection.subtitles = Some(rendition.name)
+        }
+      }
+      Command::none()
+    }
+  };
+  Ok(cmd)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_suggest_file_name() {
+    let url =
+      Url::parse("https://example.com/movie/hls/mixed.m3u8?t=1").unwrap();
+    assert_eq!(suggest_file_name(&url, "mp4"), "mixed.mp4");
+    let url = Url::parse("https://example.com/").unwrap();
+    assert_eq!(suggest_file_name(&url, "ts"), "video.ts");
+  }
+
+  #[test]
+  fn test_parse_variant() {
+    let parse = |variant: &str| {
+      AddTaskForm {
+        variant: variant.to_string(),
+        ..Default::default()
+      }
+      .parse_variant()
+    };
+    assert_eq!(parse(""), Ok(BzVariantPolicy::HighestBandwidth));
+    assert_eq!(parse("最低"), Ok(BzVariantPolicy::LowestBandwidth));
+    assert_eq!(
+      parse("1920x1080"),
+      Ok(BzVariantPolicy::Resolution {
+        width: 1920,
+        height: 1080
+      })
+    );
+    assert_eq!(
+      parse("avc1"),
+      Ok(BzVariantPolicy::Codec("avc1".to_string()))
+    );
+    assert!(parse("1920 x").is_err());
+    let form = AddTaskForm {
+      concurrency: "17".to_string(),
+      ..Default::default()
+    };
+    assert!(form.parse_concurrency().is_err());
+  }
+}
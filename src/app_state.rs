@@ -1,27 +1,140 @@
+use crate::add_task::AddTaskForm;
 use crate::bz_task::{
-  BzTask, BzTaskExtraInfo, BzTaskFeedBack, BzTaskId, BzTaskInfo,
+  self, BzTask, BzTaskControl, BzTaskExtraInfo, BzTaskFeedBack, BzTaskId,
+  BzTaskInfo, BzTaskRuntimeInfo, BzTaskStatus,
 };
+use crate::http::BzProxyConfig;
+use crate::persist;
+use crate::rate_limit::{self, BzSpeedLimit};
+use crate::time_window::{self, BzTimeWindow};
+use chrono::{DateTime, Local};
 use directories::ProjectDirs;
-use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::Path;
+
+// 同时运行的任务数量上限
+pub const MAX_ACTIVE_TASKS: usize = 10;
+// 所有任务同时下载的连接数量上限
+pub const MAX_CONNECTIONS: usize = 64;
+
+// 应用设置 保存在 settings.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BzSettings {
+  // 同时运行的任务数量 其余的任务在队列中等待
+  pub max_active_tasks: usize,
+  // 所有任务同时下载的分片和连接数量 每个任务自己的并发数之外再限制一次
+  pub max_connections: usize,
+  // 为空时使用系统代理 任务可以单独设置
+  pub proxy: BzProxyConfig,
+  // 所有任务共用的限速
+  pub speed_limit: BzSpeedLimit,
+  // 只在这些时间段内下载 为空时不限制
+  pub download_windows: Vec<BzTimeWindow>,
+}
+
+impl Default for BzSettings {
+  fn default() -> Self {
+    Self {
+      max_active_tasks: 3,
+      max_connections: rate_limit::DEFAULT_MAX_CONNECTIONS,
+      proxy: BzProxyConfig::default(),
+      speed_limit: BzSpeedLimit::default(),
+      download_windows: Vec::new(),
+    }
+  }
+}
+
+// 侧边栏的任务过滤
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum BzTaskFilter {
+  #[default]
+  All,
+  // 有 worker 的任务 包括准备中和暂停的任务
+  Running,
+  // 队列中等待 以及已经停止的任务
+  NotStarted,
+  Completed,
+  Failed,
+}
+
+impl BzTaskFilter {
+  pub const ALL: [BzTaskFilter; 5] = [
+    BzTaskFilter::All,
+    BzTaskFilter::Running,
+    BzTaskFilter::NotStarted,
+    BzTaskFilter::Completed,
+    BzTaskFilter::Failed,
+  ];
+
+  pub fn matches(&self, task: &BzTask) -> bool {
+    match self {
+      BzTaskFilter::All => true,
+      BzTaskFilter::Running => {
+        task.info.status == BzTaskStatus::Running || task.runtime.is_some()
+      }
+      BzTaskFilter::NotStarted => {
+        matches!(
+          task.info.status,
+          BzTaskStatus::Queued | BzTaskStatus::Stopped
+        ) && task.runtime.is_none()
+      }
+      BzTaskFilter::Completed => task.info.status == BzTaskStatus::Completed,
+      BzTaskFilter::Failed => task.info.status == BzTaskStatus::Failed,
+    }
+  }
+}
+
+impl std::fmt::Display for BzTaskFilter {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BzTaskFilter::All => write!(f, "全部"),
+      BzTaskFilter::Running => write!(f, "进行中"),
+      BzTaskFilter::NotStarted => write!(f, "未开始"),
+      BzTaskFilter::Completed => write!(f, "已完成"),
+      BzTaskFilter::Failed => write!(f, "错误"),
+    }
+  }
+}
 
 #[derive(Clone)]
 pub struct AppPreState {
   pub tray_state: crate::tray::TrayState,
   pub task_infos: Option<Vec<BzTaskInfo>>,
+  pub settings: Option<BzSettings>,
   pub feedback_sender: Option<tokio::sync::mpsc::Sender<BzTaskFeedBack>>,
+  pub notices: Vec<String>,
 }
 
 impl AppPreState {
   pub fn is_ready(&self) -> bool {
-    self.task_infos.is_some() && self.feedback_sender.is_some()
+    self.task_infos.is_some()
+      && self.settings.is_some()
+      && self.feedback_sender.is_some()
   }
 }
 
-
 pub struct AppState {
   pub tray_state: crate::tray::TrayState,
   pub tasks: BTreeMap<BzTaskId, BzTask>,
   pub feedback_sender: tokio::sync::mpsc::Sender<BzTaskFeedBack>,
+  pub settings: BzSettings,
+  // 等待下载的任务 有空闲的位置时从队首开始启动
+  pub queue: VecDeque<BzTaskId>,
+  // 需要提示给用户的问题 比如任务列表文件损坏
+  pub notices: Vec<String>,
+  // 打开的新建任务表单
+  pub add_task_form: Option<AddTaskForm>,
+  // 任务列表中显示哪些任务
+  pub filter: BzTaskFilter,
+  // 设置中正在编辑的下载时段 格式错误时不保存
+  pub download_windows_input: String,
+  pub download_windows_error: Option<String>,
+  // 上次检查时是否在下载时段内
+  pub download_window_open: bool,
+  // 下载时段结束时暂停的任务 时段开始时继续
+  pub window_paused: BTreeSet<BzTaskId>,
 }
 
 impl From<AppPreState> for AppState {
@@ -31,23 +144,215 @@ impl From<AppPreState> for AppState {
       .iter()
       .map(|task_info| {
         let id = BzTaskId::unique();
+        let mut task_info = task_info.clone();
+        // 程序异常退出时 任务可能还是 Running 或者 Paused 状态
+        if matches!(
+          task_info.status,
+          BzTaskStatus::Running | BzTaskStatus::Paused
+        ) {
+          task_info.status = BzTaskStatus::Stopped;
+        }
         let task = BzTask {
           id: id.clone(),
-          info: task_info.clone(),
+          info: task_info,
           extra: BzTaskExtraInfo::default(),
           runtime: None,
         };
         (id, task)
       })
+      .collect::<BTreeMap<BzTaskId, BzTask>>();
+    let queue = tasks
+      .values()
+      .filter(|task| task.info.status == BzTaskStatus::Queued)
+      .map(|task| task.id)
       .collect();
+    let settings = app_pre_state.settings.unwrap();
+    rate_limit::global().set_limit(settings.speed_limit);
+    rate_limit::connections().set_limit(settings.max_connections);
+    app_pre_state
+      .tray_state
+      .check_speed_limit(settings.speed_limit);
     Self {
       tray_state: app_pre_state.tray_state,
       tasks: tasks,
       feedback_sender: app_pre_state.feedback_sender.unwrap(),
+      download_windows_input: time_window::format_time_windows(
+        &settings.download_windows,
+      ),
+      download_windows_error: None,
+      download_window_open: time_window::is_open(
+        &settings.download_windows,
+        Local::now().naive_local(),
+      ),
+      window_paused: BTreeSet::new(),
+      settings,
+      queue,
+      notices: app_pre_state.notices,
+      add_task_form: None,
+      filter: BzTaskFilter::default(),
+    }
+  }
+}
+
+// 已经启动了worker的任务 包括还在 prepare 阶段的任务
+// 暂停的任务保留了 worker 随时可以继续 同样占用位置
+fn active_tasks(tasks: &BTreeMap<BzTaskId, BzTask>) -> usize {
+  tasks.values().filter(|task| task.runtime.is_some()).count()
+}
+
+// 有空闲的位置时从队首开始找到可以启动的任务
+// 还没到开始时间的任务留在队列中 已经删除或者不再等待的任务移出队列
+// 下载时段结束时停止的任务 worker 退出之后才能重新启动
+fn next_queued_task(
+  tasks: &BTreeMap<BzTaskId, BzTask>, queue: &mut VecDeque<BzTaskId>,
+  max_active_tasks: usize, now: DateTime<Local>,
+) -> Option<BzTaskId> {
+  while active_tasks(tasks) < max_active_tasks {
+    let position = queue.iter().position(|task_id| {
+      tasks.get(task_id).is_none_or(|task| {
+        task.runtime.is_none()
+          && task.info.start_at.is_none_or(|start_at| start_at <= now)
+      })
+    })?;
+    let task_id = queue.remove(position)?;
+    if tasks.get(&task_id).is_some_and(|task| {
+      task.runtime.is_none() && task.info.status == BzTaskStatus::Queued
+    }) {
+      return Some(task_id);
     }
   }
+  None
 }
 
+fn move_queued_task(
+  queue: &mut VecDeque<BzTaskId>, task_id: BzTaskId, offset: isize,
+) {
+  if let Some(position) = queue.iter().position(|id| *id == task_id) {
+    let target = position.saturating_add_signed(offset).min(queue.len() - 1);
+    let task_id = queue.remove(position).unwrap();
+    queue.insert(target, task_id);
+  }
+}
+
+impl AppState {
+  pub fn enqueue(&mut self, task_id: BzTaskId) {
+    if !self.queue.contains(&task_id) {
+      self.queue.push_back(task_id);
+    }
+  }
+
+  // 有空闲的位置时按照队列顺序启动任务
+  // 任务状态在收到 Started 反馈之后才会变成 Running
+  // 不在下载时段内或者还没到开始时间的任务留在队列中
+  pub fn schedule(&mut self) {
+    let now = Local::now();
+    if !time_window::is_open(&self.settings.download_windows, now.naive_local())
+    {
+      return;
+    }
+    while let Some(task_id) = next_queued_task(
+      &self.tasks,
+      &mut self.queue,
+      self.settings.max_active_tasks,
+      now,
+    ) {
+      let feedback_sender = self.feedback_sender.clone();
+      let Some(task) = self.tasks.get_mut(&task_id) else {
+        continue;
+      };
+      log::info!("[schedule] start task: {:?}", task_id);
+      // 保存的任务信息中保留跟随全局设置 只在启动时替换
+      let mut info = task.info.clone();
+      info.proxy = info.proxy.resolve(&self.settings.proxy);
+      let (control_sender, join_handle) =
+        bz_task::run_task(task.id, info, feedback_sender);
+      task.runtime = Some(BzTaskRuntimeInfo {
+        sender: control_sender,
+        join_handle,
+      });
+    }
+  }
+
+  // 定时检查 下载时段结束时暂停运行中的任务 重新开始时继续
+  pub fn check_download_windows(&mut self) {
+    let open = time_window::is_open(
+      &self.settings.download_windows,
+      Local::now().naive_local(),
+    );
+    // 已经停止的任务不再继续
+    self.window_paused.retain(|task_id| {
+      self.tasks.get(task_id).is_some_and(|task| {
+        task.runtime.is_some()
+          && matches!(
+            task.info.status,
+            BzTaskStatus::Running | BzTaskStatus::Paused
+          )
+      })
+    });
+    if open != self.download_window_open {
+      log::info!("[schedule] download window open: {}", open);
+      self.download_window_open = open;
+      let control = match open {
+        true => BzTaskControl::Resume,
+        false => BzTaskControl::Pause,
+      };
+      for task in self.tasks.values() {
+        let Some(runtime) = task.runtime.as_ref() else {
+          continue;
+        };
+        // 准备中的任务在开始下载时暂停
+        let affected = match open {
+          true => self.window_paused.contains(&task.id),
+          false => task.info.status == BzTaskStatus::Running,
+        };
+        if affected {
+          let _ = runtime.sender.try_send(control.clone());
+          if !open {
+            self.window_paused.insert(task.id);
+          }
+        }
+      }
+    }
+    self.schedule();
+  }
+
+  pub fn queue_position(&self, task_id: BzTaskId) -> Option<usize> {
+    self.queue.iter().position(|id| *id == task_id)
+  }
+
+  // offset 为负数时向队首移动
+  pub fn move_queued_task(&mut self, task_id: BzTaskId, offset: isize) {
+    move_queued_task(&mut self.queue, task_id, offset);
+  }
+
+  pub fn move_queued_task_to_top(&mut self, task_id: BzTaskId) {
+    move_queued_task(&mut self.queue, task_id, isize::MIN);
+  }
+
+  pub fn filtered_tasks(
+    &self, filter: BzTaskFilter,
+  ) -> impl Iterator<Item = &BzTask> {
+    self.tasks.values().filter(move |task| filter.matches(task))
+  }
+
+  // 保存的时候队列中的任务按照队列顺序排列 下次启动时恢复队列顺序
+  pub fn task_infos(&self) -> Vec<BzTaskInfo> {
+    let mut queued = self.queue.iter();
+    self
+      .tasks
+      .values()
+      .map(|task| match self.queue.contains(&task.id) {
+        true => queued
+          .next()
+          .and_then(|task_id| self.tasks.get(task_id))
+          .unwrap_or(task)
+          .info
+          .clone(),
+        false => task.info.clone(),
+      })
+      .collect()
+  }
+}
 
 #[allow(non_snake_case)]
 pub fn AppDir() -> ProjectDirs {
@@ -73,29 +378,200 @@ pub fn init_dirs() {
   }
 }
 
-pub async fn load_data() -> Vec<BzTaskInfo> {
-  let task_list = AppDir().data_local_dir().join("task_list.json");
-  if !task_list.exists() {
-    return Vec::new();
+// 文件和备份都无法解析时 保留损坏的文件并使用默认值 返回给界面显示的提示
+// 使用备份时同样提示 最后一次的修改已经丢失
+fn load_or_default<T: DeserializeOwned + Default>(
+  path: &Path, name: &str,
+) -> (T, Option<String>) {
+  match persist::load_json_or_backup(path) {
+    Ok(Some((value, false))) => (value, None),
+    Ok(Some((value, true))) => {
+      let notice = format!("{}文件损坏 已从备份恢复", name);
+      (value, Some(notice))
+    }
+    Ok(None) => (T::default(), None),
+    Err(err) => {
+      log::error!("failed to load {}: {}", path.display(), err);
+      let corrupted = persist::keep_corrupted(path);
+      let notice = format!(
+        "{}无法读取: {} 原文件已保存为 {}",
+        name,
+        err,
+        corrupted.display()
+      );
+      (T::default(), Some(notice))
+    }
   }
-  let task_infos: Vec<BzTaskInfo> =
-    serde_json::from_reader(std::fs::File::open(task_list).unwrap()).unwrap();
-  task_infos
+}
+
+pub async fn load_data() -> (Vec<BzTaskInfo>, Option<String>) {
+  let task_list = AppDir().data_local_dir().join("task_list.json");
+  load_or_default(&task_list, "任务列表")
 }
 
 pub async fn save_data(task_infos: Vec<BzTaskInfo>) {
   let task_list = AppDir().data_local_dir().join("task_list.json");
-  serde_json::to_writer_pretty(
-    std::fs::File::create(task_list).unwrap(),
-    &task_infos,
-  )
-  .unwrap();
+  if let Err(err) = persist::save_json(&task_list, &task_infos) {
+    log::error!("failed to save task list: {}", err);
+  }
+}
+
+pub async fn load_settings() -> (BzSettings, Option<String>) {
+  let settings = AppDir().data_local_dir().join("settings.json");
+  load_or_default(&settings, "设置")
+}
+
+pub async fn save_settings(settings: BzSettings) {
+  let settings_file = AppDir().data_local_dir().join("settings.json");
+  if let Err(err) = persist::save_json(&settings_file, &settings) {
+    log::error!("failed to save settings: {}", err);
+  }
 }
 
 #[cfg(test)]
 mod test {
   use directories::ProjectDirs;
 
+  use super::*;
+  use crate::bz_task::{
+    BzContainer, BzDashSelection, BzRecordLimit, BzRenditionSelection,
+    BzTaskType, BzVariantPolicy,
+  };
+  use crate::http::{BzRetryPolicy, BzTaskProxy};
+  use crate::rate_limit::BzSpeedLimit;
+
+  fn test_task(status: BzTaskStatus) -> BzTask {
+    BzTask::from_info(BzTaskInfo {
+      src: reqwest::Url::parse("https://example.com/index.m3u8").unwrap(),
+      dest: "./tmp/1.mp4".into(),
+      cache: "./tmp".into(),
+      kind: BzTaskType::M3u8,
+      status,
+      variant: BzVariantPolicy::default(),
+      concurrency: 4,
+      retry: BzRetryPolicy::default(),
+      headers: Vec::new(),
+      user_agent: None,
+      cookies: Vec::new(),
+      proxy: BzTaskProxy::default(),
+      speed_limit: BzSpeedLimit::default(),
+      start_at: None,
+      record_limit: BzRecordLimit::default(),
+      container: BzContainer::default(),
+      dash: BzDashSelection::default(),
+      renditions: BzRenditionSelection::default(),
+    })
+  }
+
+  // 不会真正下载的 worker
+  fn test_runtime() -> BzTaskRuntimeInfo {
+    let (sender, _) = tokio::sync::mpsc::channel(1);
+    BzTaskRuntimeInfo {
+      sender,
+      join_handle: tokio::spawn(async {}),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_next_queued_task() {
+    let mut tasks = BTreeMap::new();
+    let mut queue = VecDeque::new();
+    let mut ids = Vec::new();
+    for status in [
+      BzTaskStatus::Queued,
+      BzTaskStatus::Stopped,
+      BzTaskStatus::Queued,
+      BzTaskStatus::Queued,
+      BzTaskStatus::Queued,
+    ] {
+      let task = test_task(status);
+      ids.push(task.id);
+      queue.push_back(task.id);
+      tasks.insert(task.id, task);
+    }
+    let now = Local::now();
+    // 还没到开始时间
+    tasks.get_mut(&ids[2]).unwrap().info.start_at =
+      Some(now + chrono::Duration::hours(1));
+    let start = |tasks: &mut BTreeMap<BzTaskId, BzTask>,
+                 queue: &mut VecDeque<BzTaskId>| {
+      let task_id = next_queued_task(tasks, queue, 2, now)?;
+      tasks.get_mut(&task_id).unwrap().runtime = Some(test_runtime());
+      Some(task_id)
+    };
+
+    assert_eq!(start(&mut tasks, &mut queue), Some(ids[0]));
+    // 不再等待的任务移出队列 没到时间的任务留在原来的位置
+    assert_eq!(start(&mut tasks, &mut queue), Some(ids[3]));
+    assert_eq!(queue, VecDeque::from([ids[2], ids[4]]));
+    // 暂停的任务同样占用位置
+    tasks.get_mut(&ids[0]).unwrap().info.status = BzTaskStatus::Paused;
+    assert_eq!(start(&mut tasks, &mut queue), None);
+
+    tasks.get_mut(&ids[0]).unwrap().runtime = None;
+    assert_eq!(start(&mut tasks, &mut queue), Some(ids[4]));
+    assert_eq!(queue, VecDeque::from([ids[2]]));
+
+    // worker 还没退出的任务留在队列中
+    tasks.get_mut(&ids[3]).unwrap().runtime = None;
+    tasks.get_mut(&ids[2]).unwrap().info.start_at = None;
+    tasks.get_mut(&ids[2]).unwrap().runtime = Some(test_runtime());
+    assert_eq!(start(&mut tasks, &mut queue), None);
+    assert_eq!(queue, VecDeque::from([ids[2]]));
+  }
+
+  #[test]
+  fn test_move_queued_task() {
+    let ids = (0..4).map(|_| BzTaskId::unique()).collect::<Vec<_>>();
+    let mut queue = ids.iter().copied().collect::<VecDeque<_>>();
+    move_queued_task(&mut queue, ids[2], -1);
+    assert_eq!(queue, VecDeque::from([ids[0], ids[2], ids[1], ids[3]]));
+    move_queued_task(&mut queue, ids[0], 1);
+    assert_eq!(queue, VecDeque::from([ids[2], ids[0], ids[1], ids[3]]));
+    // 已经在两端的任务不再移动
+    move_queued_task(&mut queue, ids[3], 1);
+    move_queued_task(&mut queue, ids[2], -1);
+    assert_eq!(queue, VecDeque::from([ids[2], ids[0], ids[1], ids[3]]));
+    move_queued_task(&mut queue, ids[1], isize::MIN);
+    assert_eq!(queue, VecDeque::from([ids[1], ids[2], ids[0], ids[3]]));
+    // 不在队列中的任务
+    move_queued_task(&mut queue, BzTaskId::unique(), -1);
+    assert_eq!(queue.len(), 4);
+  }
+
+  #[tokio::test]
+  async fn test_task_filter() {
+    let mut task = test_task(BzTaskStatus::Stopped);
+    let matched = |task: &BzTask| {
+      BzTaskFilter::ALL
+        .into_iter()
+        .filter(|filter| filter.matches(task))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      matched(&task),
+      vec![BzTaskFilter::All, BzTaskFilter::NotStarted]
+    );
+    task.info.status = BzTaskStatus::Running;
+    assert_eq!(
+      matched(&task),
+      vec![BzTaskFilter::All, BzTaskFilter::Running]
+    );
+    // 暂停的任务 worker 还在运行
+    task.info.status = BzTaskStatus::Paused;
+    task.runtime = Some(test_runtime());
+    assert_eq!(
+      matched(&task),
+      vec![BzTaskFilter::All, BzTaskFilter::Running]
+    );
+    task.runtime = None;
+    task.info.status = BzTaskStatus::Failed;
+    assert_eq!(
+      matched(&task),
+      vec![BzTaskFilter::All, BzTaskFilter::Failed]
+    );
+  }
+
   #[test]
   fn test_dirs() {
     let app_dir =
@@ -1,25 +1,53 @@
-use crate::app_state::{AppPreState, AppState};
-use crate::bz_task::{BzTaskFeedBack, BzTaskInfo};
+use crate::add_task::AddTaskMessage;
+use crate::app_state::{
+  AppPreState, AppState, BzSettings, BzTaskFilter, MAX_ACTIVE_TASKS,
+  MAX_CONNECTIONS,
+};
+use crate::bz_task::{BzTaskFeedBack, BzTaskInfo, BzTaskStatus};
 use crate::bz_task::{BzTaskInfoFeedBackMessage, BzTaskMessage};
 use crate::error::BzResult;
+use crate::http::BzProxyConfig;
+use crate::rate_limit::BzSpeedLimit;
 use crate::tray::{self, BzMenuType};
 use iced::{
   Element, Subscription, Task as Command,
   widget::{Text, column, horizontal_rule},
   window::{self, Mode},
 };
+use std::time::Duration;
 use tray_icon::menu::MenuEvent;
 
+// 退出时等待worker停止的最长时间
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+// 检查下载时段和开始时间的间隔
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Clone)]
 pub enum Message {
   // Initializing
-  Loaded(Vec<BzTaskInfo>),
+  // 加载失败时附带提示信息
+  Loaded((Vec<BzTaskInfo>, Option<String>)),
+  SettingsLoaded((BzSettings, Option<String>)),
   FeedbackChannelCreated(tokio::sync::mpsc::Sender<BzTaskFeedBack>),
 
   // Running
   TrayMenuEvent(MenuEvent),
   TaskInfoFeedBack(BzTaskInfoFeedBackMessage),
   BzTask(BzTaskMessage),
+  AddTask(AddTaskMessage),
+  SetMaxActiveTasks(usize),
+  SetMaxConnections(usize),
+  // 全局代理 新启动的任务生效
+  SetProxy(BzProxyConfig),
+  // 全局限速 运行中的任务立即生效
+  SetSpeedLimit(BzSpeedLimit),
+  // 设置中输入的下载时段 回车之后才生效
+  SetDownloadWindows(String),
+  ApplyDownloadWindows,
+  // 定时检查下载时段和任务的开始时间
+  ScheduleTick,
+  SetFilter(BzTaskFilter),
+  DismissNotices,
   WindowCloseRequest,
   SaveCompleted, //真正的关闭
 }
@@ -40,9 +68,17 @@ impl BzDownloader {
       Self::Initializing(AppPreState {
         tray_state,
         task_infos: None,
+        settings: None,
         feedback_sender: None,
+        notices: Vec::new(),
       }),
-      Command::perform(crate::app_state::load_data(), Message::Loaded),
+      Command::batch([
+        Command::perform(crate::app_state::load_data(), Message::Loaded),
+        Command::perform(
+          crate::app_state::load_settings(),
+          Message::SettingsLoaded,
+        ),
+      ]),
     )
   }
 
@@ -51,21 +87,19 @@ impl BzDownloader {
       BzDownloader::Initializing(app_pre_state) => {
         log::debug!("[initializing] : {:?}", message);
         match message {
-          Message::Loaded(task_infos) => {
+          Message::Loaded((task_infos, notice)) => {
             log::debug!("Loaded");
             app_pre_state.task_infos = Some(task_infos);
-            if app_pre_state.is_ready() {
-              *self =
-                BzDownloader::Running(AppState::from(app_pre_state.clone()));
-            }
+            app_pre_state.notices.extend(notice);
+          }
+          Message::SettingsLoaded((settings, notice)) => {
+            log::debug!("SettingsLoaded");
+            app_pre_state.settings = Some(settings);
+            app_pre_state.notices.extend(notice);
           }
           Message::FeedbackChannelCreated(sender) => {
             log::debug!("FeedbackChannelCreated");
             app_pre_state.feedback_sender = Some(sender);
-            if app_pre_state.is_ready() {
-              *self =
-                BzDownloader::Running(AppState::from(app_pre_state.clone()));
-            }
           }
           _ => {
             log::error!(
@@ -74,6 +108,12 @@ impl BzDownloader {
             );
           }
         }
+        if app_pre_state.is_ready() {
+          let mut app_state = AppState::from(app_pre_state.clone());
+          // 启动上次退出时还在队列中的任务
+          app_state.schedule();
+          *self = BzDownloader::Running(app_state);
+        }
         Command::none()
       }
       BzDownloader::Running(app_state) => {
@@ -94,10 +134,22 @@ impl BzDownloader {
     match self {
       BzDownloader::Initializing(_) => Element::new(Text::new("Loading...")),
       BzDownloader::Running(app_state) => {
-        let header = self.view_header();
+        let header = self.view_header(app_state);
+        let notices = self.view_notices(app_state);
+        let add_task = app_state
+          .add_task_form
+          .as_ref()
+          .map(|form| self.view_add_task(form));
         let h = horizontal_rule(5);
         let body = self.view_body(app_state);
-        column![header, h, body].spacing(10).padding(30).into()
+        column![header]
+          .push_maybe(notices)
+          .push_maybe(add_task)
+          .push(h)
+          .push(body)
+          .spacing(10)
+          .padding(30)
+          .into()
       }
     }
   }
@@ -112,10 +164,14 @@ impl BzDownloader {
     let task_feedback_subscription =
       Subscription::run(crate::bz_task::feed_back_subscription);
 
+    let schedule_tick =
+      iced::time::every(SCHEDULE_INTERVAL).map(|_| Message::ScheduleTick);
+
     Subscription::batch(vec![
       tray_subscription,
       window_close_requests,
       task_feedback_subscription,
+      schedule_tick,
     ])
   }
 }
@@ -140,16 +196,96 @@ fn deal_running_message(
 
     Message::BzTask(task_meaasge) => {
       log::debug!("[Message::BzTask] BzTaskMessage: {:?}", task_meaasge);
-      let _ = crate::bz_task::deal_bztask_message(app_state, task_meaasge);
+      crate::bz_task::deal_bztask_message(app_state, task_meaasge)?
+    }
+    Message::AddTask(add_task_message) => {
+      crate::add_task::deal_add_task_message(app_state, add_task_message)?
+    }
+    Message::SetMaxActiveTasks(max_active_tasks) => {
+      log::debug!("SetMaxActiveTasks: {}", max_active_tasks);
+      app_state.settings.max_active_tasks =
+        max_active_tasks.clamp(1, MAX_ACTIVE_TASKS);
+      app_state.schedule();
+      Command::future(crate::app_state::save_settings(
+        app_state.settings.clone(),
+      ))
+      .discard()
+    }
+    Message::SetMaxConnections(max_connections) => {
+      log::debug!("SetMaxConnections: {}", max_connections);
+      app_state.settings.max_connections =
+        max_connections.clamp(1, MAX_CONNECTIONS);
+      crate::rate_limit::connections()
+        .set_limit(app_state.settings.max_connections);
+      Command::future(crate::app_state::save_settings(
+        app_state.settings.clone(),
+      ))
+      .discard()
+    }
+    Message::SetProxy(proxy) => {
+      // 链接中可能带有密码 只记录主机
+      let host = reqwest::Url::parse(&proxy.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string));
+      log::debug!("SetProxy: {:?} no_proxy: {}", host, proxy.no_proxy);
+      app_state.settings.proxy = proxy;
+      Command::future(crate::app_state::save_settings(
+        app_state.settings.clone(),
+      ))
+      .discard()
+    }
+    Message::SetSpeedLimit(speed_limit) => {
+      log::debug!("SetSpeedLimit: {}", speed_limit);
+      app_state.settings.speed_limit = speed_limit;
+      crate::rate_limit::global().set_limit(speed_limit);
+      app_state.tray_state.check_speed_limit(speed_limit);
+      Command::future(crate::app_state::save_settings(
+        app_state.settings.clone(),
+      ))
+      .discard()
+    }
+    Message::SetDownloadWindows(input) => {
+      app_state.download_windows_input = input;
+      Command::none()
+    }
+    Message::ApplyDownloadWindows => {
+      let input = &app_state.download_windows_input;
+      match crate::time_window::parse_time_windows(input) {
+        Ok(windows) => {
+          app_state.download_windows_error = None;
+          app_state.settings.download_windows = windows;
+          app_state.check_download_windows();
+          Command::future(crate::app_state::save_settings(
+            app_state.settings.clone(),
+          ))
+          .discard()
+        }
+        Err(err) => {
+          app_state.download_windows_error = Some(err.to_string());
+          Command::none()
+        }
+      }
+    }
+    Message::ScheduleTick => {
+      app_state.check_download_windows();
+      Command::none()
+    }
+    Message::SetFilter(filter) => {
+      app_state.filter = filter;
+      Command::none()
+    }
+    Message::DismissNotices => {
+      app_state.notices.clear();
       Command::none()
     }
     Message::TaskInfoFeedBack(feedback) => {
-      let task_id = feedback.task_id;
-      let progress = feedback.progress;
-
-      app_state.tasks.get_mut(&task_id).map(|task| {
-        task.extra.progress = progress;
-      });
+      if let Some(task) = app_state.tasks.get_mut(&feedback.task_id) {
+        task.extra.progress = feedback.progress;
+        task.extra.current_size = feedback.current_size;
+        task.extra.total_size = feedback.total_size;
+        task.extra.speed = feedback.speed;
+        task.extra.average_speed = feedback.average_speed;
+      }
       Command::none()
     }
     _ => Command::none(),
@@ -172,19 +308,40 @@ fn deal_trayevent(
       window::get_latest()
         .and_then(|window| window::change_mode(window, Mode::Hidden))
     }
+    BzMenuType::SpeedLimit(speed_limit) => {
+      log::debug!("TrayMenuEvent: SpeedLimit {}", speed_limit);
+      Command::done(Message::SetSpeedLimit(*speed_limit))
+    }
     BzMenuType::Exit => {
       log::debug!("TrayMenuEvent: Exit");
       // 给每个worker发送退出消息
       // 等待所有worker退出
-      // 退出前保存任务列表
-      let task_infos: Vec<BzTaskInfo> = app_state
+      // 退出前保存任务列表 下载中和暂停的任务保存为已停止 下次开始时从断点继续
+      // 等待下载时段的任务保存为等待中 下次启动后在时段内继续
+      let window_paused = std::mem::take(&mut app_state.window_paused);
+      let runtimes = app_state
         .tasks
-        .values()
-        .map(|task| task.info.clone())
-        .collect();
-      Command::perform(crate::app_state::save_data(task_infos), |_| {
-        Message::SaveCompleted
-      })
+        .values_mut()
+        .filter_map(|task| {
+          if window_paused.contains(&task.id) {
+            task.info.status = BzTaskStatus::Queued;
+          } else if matches!(
+            task.info.status,
+            BzTaskStatus::Running | BzTaskStatus::Paused
+          ) {
+            task.info.status = BzTaskStatus::Stopped;
+          }
+          task.runtime.take()
+        })
+        .collect::<Vec<_>>();
+      let task_infos: Vec<BzTaskInfo> = app_state.task_infos();
+      Command::perform(
+        async move {
+          crate::bz_task::stop_tasks(runtimes, SHUTDOWN_TIMEOUT).await;
+          crate::app_state::save_data(task_infos).await;
+        },
+        |_| Message::SaveCompleted,
+      )
     }
     _ => Command::none(),
   };
@@ -0,0 +1,217 @@
+use std::future::Future;
+
+use tokio::sync::{mpsc, watch};
+
+use super::{
+  BzTaskControl, BzTaskControlFeedBack, BzTaskControlFeedBackMessage,
+  BzTaskFeedBack, BzTaskId, BzTaskInfoFeedBackMessage,
+};
+
+// 暂停时不再轮询正在进行的请求 连接保持打开 继续后接着读取
+#[derive(Debug, Clone)]
+pub struct BzPauseGate {
+  receiver: watch::Receiver<bool>,
+}
+
+impl BzPauseGate {
+  // 等待状态变为 paused worker 已经退出时返回 false
+  async fn wait_for(&mut self, paused: bool) -> bool {
+    self
+      .receiver
+      .wait_for(|value| *value == paused)
+      .await
+      .is_ok()
+  }
+
+  // 等待继续 worker 已经退出时直接返回
+  pub async fn wait(&mut self) {
+    self.wait_for(false).await;
+  }
+
+  pub async fn hold<F: Future>(mut self, future: F) -> F::Output {
+    tokio::pin!(future);
+    loop {
+      self.wait().await;
+      // 请求完成的同时收到暂停时 先暂停
+      tokio::select! {
+        biased;
+        paused = self.wait_for(true) => {
+          if !paused {
+            return future.await;
+          }
+        }
+        output = &mut future => return output,
+      }
+    }
+  }
+}
+
+// 停止保留缓存 下次继续下载 取消会删除缓存
+// 程序退出时等待的时间有限 直播录制不合并 下次继续
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BzStopReason {
+  Stop,
+  Cancel,
+  Shutdown,
+}
+
+// worker 中处理控制消息 并把进度反馈给界面
+pub struct BzTaskController {
+  task_id: BzTaskId,
+  receiver: mpsc::Receiver<BzTaskControl>,
+  feedback_sender: mpsc::Sender<BzTaskFeedBack>,
+  paused: watch::Sender<bool>,
+  stop: Option<BzStopReason>,
+}
+
+impl BzTaskController {
+  pub fn new(
+    task_id: BzTaskId, receiver: mpsc::Receiver<BzTaskControl>,
+    feedback_sender: mpsc::Sender<BzTaskFeedBack>,
+  ) -> Self {
+    Self {
+      task_id,
+      receiver,
+      feedback_sender,
+      paused: watch::Sender::new(false),
+      stop: None,
+    }
+  }
+
+  pub fn task_id(&self) -> BzTaskId {
+    self.task_id
+  }
+
+  pub fn gate(&self) -> BzPauseGate {
+    BzPauseGate {
+      receiver: self.paused.subscribe(),
+    }
+  }
+
+  pub fn is_paused(&self) -> bool {
+    *self.paused.borrow()
+  }
+
+  pub fn stop_reason(&self) -> Option<BzStopReason> {
+    self.stop
+  }
+
+  pub fn is_stopping(&self) -> bool {
+    self.stop.is_some()
+  }
+
+  // 处理下一个控制消息 界面关闭了通道时按照停止处理
+  // 在 select 中使用 收到停止消息之后不要再调用
+  pub async fn recv(&mut self) {
+    let control = self.receiver.recv().await.unwrap_or(BzTaskControl::Stop);
+    let feedback = match control {
+      BzTaskControl::Pause => {
+        self.paused.send_replace(true);
+        BzTaskControlFeedBack::Paused
+      }
+      BzTaskControl::Resume => {
+        self.paused.send_replace(false);
+        BzTaskControlFeedBack::Resumed
+      }
+      BzTaskControl::Stop => {
+        self.stop = Some(BzStopReason::Stop);
+        // 暂停中的请求需要继续才能结束
+        self.paused.send_replace(false);
+        return;
+      }
+      BzTaskControl::Cancel => {
+        self.stop = Some(BzStopReason::Cancel);
+        self.paused.send_replace(false);
+        return;
+      }
+      BzTaskControl::Shutdown => {
+        self.stop = Some(BzStopReason::Shutdown);
+        self.paused.send_replace(false);
+        return;
+      }
+    };
+    self.send_control(feedback).await;
+  }
+
+  // 暂停时一直等待 直到继续或者停止
+  pub async fn wait_resumed(&mut self) {
+    while self.is_paused() && !self.is_stopping() {
+      self.recv().await;
+    }
+  }
+
+  pub async fn send_control(&self, control: BzTaskControlFeedBack) {
+    let _ = self
+      .feedback_sender
+      .send(BzTaskFeedBack::TaskConrol(BzTaskControlFeedBackMessage {
+        task_id: self.task_id,
+        control,
+      }))
+      .await;
+  }
+
+  pub async fn send_info(&self, message: BzTaskInfoFeedBackMessage) {
+    let _ = self
+      .feedback_sender
+      .send(BzTaskFeedBack::TaskInfo(message))
+      .await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn test_pause_gate() {
+    let (control_sender, control_receiver) = mpsc::channel(10);
+    let (feedback_sender, mut feedback_receiver) = mpsc::channel(10);
+    let mut controller = BzTaskController::new(
+      BzTaskId::unique(),
+      control_receiver,
+      feedback_sender,
+    );
+    let gate = controller.gate();
+    let (sender, receiver) = tokio::sync::oneshot::channel::<u32>();
+    let held = tokio::spawn(gate.hold(receiver));
+
+    control_sender.send(BzTaskControl::Pause).await.unwrap();
+    controller.recv().await;
+    assert!(controller.is_paused());
+    assert!(matches!(
+      feedback_receiver.recv().await,
+      Some(BzTaskFeedBack::TaskConrol(BzTaskControlFeedBackMessage {
+        control: BzTaskControlFeedBack::Paused,
+        ..
+      }))
+    ));
+    // 暂停中请求已经完成也不会返回
+    sender.send(1).unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!held.is_finished());
+
+    control_sender.send(BzTaskControl::Cancel).await.unwrap();
+    controller.wait_resumed().await;
+    assert_eq!(controller.stop_reason(), Some(BzStopReason::Cancel));
+    assert_eq!(held.await.unwrap(), Ok(1));
+  }
+
+  #[tokio::test]
+  async fn test_shutdown() {
+    let (control_sender, control_receiver) = mpsc::channel(10);
+    let (feedback_sender, _feedback_receiver) = mpsc::channel(10);
+    let mut controller = BzTaskController::new(
+      BzTaskId::unique(),
+      control_receiver,
+      feedback_sender,
+    );
+    control_sender.send(BzTaskControl::Pause).await.unwrap();
+    control_sender.send(BzTaskControl::Shutdown).await.unwrap();
+    controller.recv().await;
+    controller.wait_resumed().await;
+    assert!(!controller.is_paused());
+    assert_eq!(controller.stop_reason(), Some(BzStopReason::Shutdown));
+  }
+}
@@ -1,15 +1,33 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, Local};
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 
 use super::BzTaskId;
+use crate::cookies::BzCookie;
+use crate::http::{self, BzRequestConfig, BzRetryPolicy, BzTaskProxy};
+use crate::rate_limit::BzSpeedLimit;
+
+// 单个任务同时下载的分片数量上限 所有任务合计再受设置中的连接数限制
+pub const MAX_CONCURRENCY: usize = 16;
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+// 进度文件的最短保存间隔 停止和完成时再保存一次
+pub const DUMP_INTERVAL: std::time::Duration =
+  std::time::Duration::from_secs(1);
+
+fn default_concurrency() -> usize {
+  DEFAULT_CONCURRENCY
+}
 
 // 用于展示和存储的状态
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum BzTaskStatus {
   Queued,
   Running,
+  // worker 保持运行 继续时不需要重新 prepare
+  Paused,
   Stopped,
   Completed,
   Failed,
@@ -23,11 +41,157 @@ pub enum TaskInnerStatus {
   Failed,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 
 pub enum BzTaskType {
   M3u8,
   Zfs,
+  Dash,
+  // 普通文件
+  Http,
+}
+
+impl BzTaskType {
+  // 根据链接的扩展名判断任务类型
+  pub fn from_url(url: &Url) -> Option<Self> {
+    let path = url.path().to_ascii_lowercase();
+    if path.ends_with(".m3u8") || path.ends_with(".m3u") {
+      return Some(BzTaskType::M3u8);
+    }
+    if path.ends_with(".mpd") {
+      return Some(BzTaskType::Dash);
+    }
+    None
+  }
+
+  // 扩展名无法判断时根据服务器返回的 Content-Type 判断
+  pub fn from_content_type(content_type: &str) -> Option<Self> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    match mime.as_str() {
+      "application/vnd.apple.mpegurl"
+      | "application/x-mpegurl"
+      | "audio/mpegurl"
+      | "audio/x-mpegurl" => Some(BzTaskType::M3u8),
+      "application/dash+xml" => Some(BzTaskType::Dash),
+      // 网页不是要下载的内容
+      "" | "text/html" => None,
+      _ => Some(BzTaskType::Http),
+    }
+  }
+
+  // 根据内容的开头判断 索引文件可能以 text/plain 等类型返回
+  pub fn from_content(head: &[u8]) -> Option<Self> {
+    let head = String::from_utf8_lossy(head);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    if head.starts_with("#EXTM3U") {
+      return Some(BzTaskType::M3u8);
+    }
+    // 前面可能有 <?xml ?> 和注释
+    if head.starts_with('<') && head.contains("<MPD") {
+      return Some(BzTaskType::Dash);
+    }
+    None
+  }
+
+  // Content-Type 不能确定是索引文件时 先检查内容再作为普通文件
+  pub fn detect(content_type: Option<&str>, head: &[u8]) -> Option<Self> {
+    let by_type = content_type.and_then(Self::from_content_type);
+    match by_type {
+      Some(BzTaskType::M3u8 | BzTaskType::Dash) => by_type,
+      _ => Self::from_content(head).or(by_type),
+    }
+  }
+}
+
+// master playlist 中选择哪个码流
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub enum BzVariantPolicy {
+  #[default]
+  HighestBandwidth,
+  LowestBandwidth,
+  // 分辨率最接近的
+  Resolution {
+    width: u64,
+    height: u64,
+  },
+  // CODECS 中包含指定编码 例如 avc1 hvc1
+  Codec(String),
+}
+
+// DASH 选择的 Representation id None 表示自动选择
+// 视频按照 variant 选择 音频选择码率最高的
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct BzDashSelection {
+  pub video: Option<String>,
+  pub audio: Option<String>,
+}
+
+// master playlist 中 EXT-X-MEDIA 的音轨和字幕 按照 NAME 选择
+// 音轨为 None 时使用码流对应分组中默认的音轨 字幕为 None 时不下载
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct BzRenditionSelection {
+  pub audio: Option<String>,
+  pub subtitles: Option<String>,
+}
+
+// 直播录制的上限 达到任意一个时停止录制 None 表示不限制
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct BzRecordLimit {
+  pub max_duration_secs: Option<u64>,
+  pub max_bytes: Option<u64>,
+}
+
+// 输出文件的格式
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum BzContainer {
+  // 根据保存文件的扩展名决定
+  #[default]
+  Auto,
+  // 直接拼接分片
+  Ts,
+  // TS 分片转换为 MP4
+  Mp4,
+}
+
+impl BzContainer {
+  pub const ALL: [BzContainer; 3] =
+    [BzContainer::Auto, BzContainer::Ts, BzContainer::Mp4];
+
+  // .ts 之类的扩展名保持原样 其余的都转换为 mp4
+  pub fn resolve(self, dest: &Path) -> BzContainer {
+    match self {
+      BzContainer::Auto => {
+        let ext = dest
+          .extension()
+          .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+        match ext.as_deref() {
+          Some("ts" | "m2ts" | "mts") => BzContainer::Ts,
+          _ => BzContainer::Mp4,
+        }
+      }
+      container => container,
+    }
+  }
+
+  pub fn extension(self) -> &'static str {
+    match self {
+      BzContainer::Ts => "ts",
+      _ => "mp4",
+    }
+  }
+}
+
+impl std::fmt::Display for BzContainer {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BzContainer::Auto => write!(f, "根据扩展名"),
+      BzContainer::Ts => write!(f, "TS"),
+      BzContainer::Mp4 => write!(f, "MP4"),
+    }
+  }
 }
 
 pub struct BzTask {
@@ -44,14 +208,47 @@ pub struct BzTaskInfo {
   // #[serde(default = "BzTaskId::zero")]
   // pub id: BzTaskId, // 系统内部使用 不会被dump 每次启动程序从0开始累加
   #[serde(
-    serialize_with = "serialize_url",
-    deserialize_with = "deserialize_url"
+    serialize_with = "http::serialize_url",
+    deserialize_with = "http::deserialize_url"
   )]
   pub src: Url,
   pub dest: PathBuf,  // 下载目录
   pub cache: PathBuf, // 临时文件
   pub kind: BzTaskType,
   pub status: BzTaskStatus,
+  #[serde(default)]
+  pub variant: BzVariantPolicy,
+  // 同时下载的分片数量 不超过 MAX_CONCURRENCY
+  #[serde(default = "default_concurrency")]
+  pub concurrency: usize,
+  #[serde(default)]
+  pub retry: BzRetryPolicy,
+  // 请求时附带的自定义请求头
+  #[serde(default)]
+  pub headers: Vec<(String, String)>,
+  // None 时使用 reqwest 默认的 User-Agent
+  #[serde(default)]
+  pub user_agent: Option<String>,
+  // 从 cookies.txt 导入
+  #[serde(default)]
+  pub cookies: Vec<BzCookie>,
+  #[serde(default)]
+  pub proxy: BzTaskProxy,
+  // 任务单独的限速 同时受全局限速限制
+  #[serde(default)]
+  pub speed_limit: BzSpeedLimit,
+  // 到这个时间之后才会从队列中启动
+  #[serde(default)]
+  pub start_at: Option<DateTime<Local>>,
+  // 没有 EXT-X-ENDLIST 的直播流会一直录制 直到停止或者达到上限
+  #[serde(default)]
+  pub record_limit: BzRecordLimit,
+  #[serde(default)]
+  pub container: BzContainer,
+  #[serde(default)]
+  pub dash: BzDashSelection,
+  #[serde(default)]
+  pub renditions: BzRenditionSelection,
   // 创建时间 完成时间等
   // TODO 简易的序列化和反序列化
 }
@@ -61,7 +258,32 @@ pub struct BzTaskInfo {
 pub struct BzTaskExtraInfo {
   pub progress: f32,
   pub current_size: u64,
-  pub total_size: u64,
+  pub total_size: Option<u64>,
+  // 字节每秒 任务没有运行时为 0
+  pub speed: f64,
+  pub average_speed: f64,
+  // 最近一次失败的原因
+  pub error: Option<String>,
+  // 本次运行中内容损坏的分片
+  pub corrupted_segments: Vec<String>,
+}
+
+impl BzTaskExtraInfo {
+  pub fn stop_speed(&mut self) {
+    self.speed = 0.0;
+    self.average_speed = 0.0;
+  }
+
+  // 剩余时间 瞬时速度为 0 时使用平均速度
+  pub fn eta(&self) -> Option<std::time::Duration> {
+    let remaining = self.total_size?.saturating_sub(self.current_size);
+    let speed = match self.speed > 0.0 {
+      true => self.speed,
+      false => self.average_speed,
+    };
+    (speed > 0.0)
+      .then(|| std::time::Duration::from_secs_f64(remaining as f64 / speed))
+  }
 }
 
 #[derive(Debug)]
@@ -72,6 +294,17 @@ pub struct BzTaskRuntimeInfo {
 
 // ==============================================
 
+impl BzTaskInfo {
+  pub fn request_config(&self) -> BzRequestConfig {
+    BzRequestConfig {
+      headers: self.headers.clone(),
+      user_agent: self.user_agent.clone(),
+      cookies: self.cookies.clone(),
+      proxy: self.proxy.clone(),
+    }
+  }
+}
+
 impl BzTask {
   pub fn from_info(info: BzTaskInfo) -> Self {
     Self {
@@ -84,26 +317,31 @@ impl BzTask {
 }
 // ==============================================
 
-// #[derive(Debug, Clone)]
-// pub enum Control {
-//   Pause,
-//   Start,
-//   Stop,
-// }
-
 #[derive(Debug, Clone)]
 pub enum BzTaskControl {
-  // Start, // 目前看后端任务没有暂停的状态 所以暂时不需要这个
+  Pause,
+  Resume,
+  // 保留缓存 下次继续下载
   Stop,
+  // 停止并删除缓存
+  Cancel,
+  // 程序退出 和停止一样保留缓存 但是不做合并等耗时的收尾
+  Shutdown,
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum BzTaskControlFeedBack {
   Started,
+  Paused,
+  Resumed,
   Stoped,
+  Cancelled,
   Finished,
-  Failed,
+  Failed(String),
+  // 下载的分片内容损坏 任务继续运行
+  SegmentCorrupted(String),
+  // 需要用户知道的情况 例如进度从备份恢复
+  Notice(String),
 }
 
 #[derive(Debug, Clone)]
@@ -116,6 +354,12 @@ pub struct BzTaskControlFeedBackMessage {
 pub struct BzTaskInfoFeedBackMessage {
   pub task_id: BzTaskId,
   pub progress: f32,
+  // 已经下载的字节数 总字节数未知时根据已经下载的分片估计
+  pub current_size: u64,
+  pub total_size: Option<u64>,
+  // 字节每秒 瞬时速度和本次启动之后的平均速度
+  pub speed: f64,
+  pub average_speed: f64,
 }
 
 #[derive(Debug, Clone)]
@@ -126,30 +370,27 @@ pub enum BzTaskFeedBack {
 
 // ==============================================
 
-fn serialize_url<S>(url: &Url, serializer: S) -> Result<S::Ok, S::Error>
-where
-  S: serde::Serializer,
-{
-  serializer.serialize_str(&url.to_string())
-}
-
-fn deserialize_url<'de, D>(deserializer: D) -> Result<Url, D::Error>
-where
-  D: serde::Deserializer<'de>,
-{
-  let s = String::deserialize(deserializer)?;
-  Url::parse(&s).map_err(serde::de::Error::custom)
-}
-
 // ==============================================
 impl std::fmt::Display for BzTaskStatus {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
       BzTaskStatus::Queued => write!(f, "队列中"),
       BzTaskStatus::Running => write!(f, "下载中"),
+      BzTaskStatus::Paused => write!(f, "已暂停"),
       BzTaskStatus::Completed => write!(f, "已完成"),
       BzTaskStatus::Failed => write!(f, "下载失败"),
-      BzTaskStatus::Stopped => write!(f, "暂停中"),
+      BzTaskStatus::Stopped => write!(f, "已停止"),
+    }
+  }
+}
+
+impl std::fmt::Display for BzTaskType {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BzTaskType::M3u8 => write!(f, "M3U8"),
+      BzTaskType::Zfs => write!(f, "ZFS"),
+      BzTaskType::Dash => write!(f, "DASH"),
+      BzTaskType::Http => write!(f, "HTTP"),
     }
   }
 }
@@ -266,6 +507,7 @@ mod tests {
   use std::path::PathBuf;
 
   use super::*;
+  use crate::http::BzProxyConfig;
 
   #[test]
   fn test_serlize() {
@@ -275,6 +517,43 @@ mod tests {
       cache: PathBuf::from("./tmp"),
       kind: BzTaskType::Zfs,
       status: BzTaskStatus::Queued,
+      variant: BzVariantPolicy::default(),
+      concurrency: 4,
+      retry: BzRetryPolicy::default(),
+      headers: vec![("Referer".to_string(), "https://example.com/".to_string())],
+      user_agent: Some("Mozilla/5.0".to_string()),
+      cookies: vec![BzCookie {
+        domain: ".example.com".to_string(),
+        include_subdomains: true,
+        path: "/".to_string(),
+        secure: false,
+        expires: None,
+        name: "session".to_string(),
+        value: "abc".to_string(),
+      }],
+      proxy: BzTaskProxy::Custom(BzProxyConfig {
+        url: "http://127.0.0.1:8080".to_string(),
+        no_proxy: "localhost".to_string(),
+      }),
+      speed_limit: BzSpeedLimit::Limited(1024 * 1024),
+      start_at: Some(
+        DateTime::parse_from_rfc3339("2025-03-03T01:00:00+08:00")
+          .unwrap()
+          .with_timezone(&Local),
+      ),
+      record_limit: BzRecordLimit {
+        max_duration_secs: Some(3600),
+        max_bytes: None,
+      },
+      container: BzContainer::Mp4,
+      dash: BzDashSelection {
+        video: Some("v1080".to_string()),
+        audio: None,
+      },
+      renditions: BzRenditionSelection {
+        audio: Some("English".to_string()),
+        subtitles: None,
+      },
     };
     let serialized = serde_json::to_string(&task_info).unwrap();
     println!("serialized = {}", serialized);
@@ -282,4 +561,43 @@ mod tests {
     let deserialized: BzTaskInfo = serde_json::from_str(&serialized).unwrap();
     println!("deserialized = {:?}", deserialized)
   }
+
+  #[test]
+  fn test_detect_task_type() {
+    let url = Url::parse("https://example.com/hls/Index.M3U8?token=1").unwrap();
+    assert_eq!(BzTaskType::from_url(&url), Some(BzTaskType::M3u8));
+    let url = Url::parse("https://example.com/play?id=1").unwrap();
+    assert_eq!(BzTaskType::from_url(&url), None);
+    assert_eq!(
+      BzTaskType::from_content_type(
+        "application/vnd.apple.mpegurl; charset=utf-8"
+      ),
+      Some(BzTaskType::M3u8)
+    );
+    assert_eq!(BzTaskType::from_content_type("text/html"), None);
+    assert_eq!(
+      BzTaskType::from_content_type("application/zip"),
+      Some(BzTaskType::Http)
+    );
+    let url = Url::parse("https://example.com/dash/manifest.mpd").unwrap();
+    assert_eq!(BzTaskType::from_url(&url), Some(BzTaskType::Dash));
+
+    assert_eq!(
+      BzTaskType::detect(Some("text/plain"), b"#EXTM3U\n#EXT-X-VERSION:3"),
+      Some(BzTaskType::M3u8)
+    );
+    assert_eq!(
+      BzTaskType::detect(
+        Some("application/octet-stream"),
+        b"<?xml version=\"1.0\"?>\n<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\">"
+      ),
+      Some(BzTaskType::Dash)
+    );
+    assert_eq!(BzTaskType::detect(None, b"#EXTM3U"), Some(BzTaskType::M3u8));
+    assert_eq!(
+      BzTaskType::detect(Some("application/octet-stream"), b"PK\x03\x04"),
+      Some(BzTaskType::Http)
+    );
+    assert_eq!(BzTaskType::detect(Some("text/html"), b"<html>"), None);
+  }
 }
@@ -1,5 +1,8 @@
 use crate::{
-  app_state::AppState, bz_downloader::Message, bz_task::{self, BzTask, BzTaskRuntimeInfo, BzTaskStatus}, error::{BzError, BzResult}
+  app_state::AppState,
+  bz_downloader::Message,
+  bz_task::{self, BzTask, BzTaskRuntimeInfo, BzTaskStatus},
+  error::{BzError, BzResult},
 };
 use iced::Task as Command;
 
@@ -11,11 +14,25 @@ pub enum BzTaskMessage {
   AddTask(BzTaskInfo),
   TryStartTask(BzTaskId),
   StartTask(BzTaskId),
+  TryPauseTask(BzTaskId),
+  PauseTask(BzTaskId),
+  TryResumeTask(BzTaskId),
+  ResumeTask(BzTaskId),
   TryStopTask(BzTaskId),
   StopTask(BzTaskId),
+  // 停止任务并删除缓存
+  TryCancelTask(BzTaskId),
+  CancelTask(BzTaskId),
   RemoveTask(BzTaskId),
   FinishTask(BzTaskId),
-  FailTask(BzTaskId),
+  FailTask(BzTaskId, String),
+  SegmentCorrupted(BzTaskId, String),
+  // 显示在界面顶部的提示
+  TaskNotice(BzTaskId, String),
+  // 调整队列中任务的顺序
+  MoveTaskUp(BzTaskId),
+  MoveTaskDown(BzTaskId),
+  MoveTaskToTop(BzTaskId),
 }
 
 impl std::fmt::Display for BzTaskMessage {
@@ -30,18 +47,51 @@ impl std::fmt::Display for BzTaskMessage {
       BzTaskMessage::StartTask(task_id) => {
         write!(f, "StartTask: {:?}", task_id)
       }
+      BzTaskMessage::TryPauseTask(task_id) => {
+        write!(f, "TryPauseTask: {:?}", task_id)
+      }
+      BzTaskMessage::PauseTask(task_id) => {
+        write!(f, "PauseTask: {:?}", task_id)
+      }
+      BzTaskMessage::TryResumeTask(task_id) => {
+        write!(f, "TryResumeTask: {:?}", task_id)
+      }
+      BzTaskMessage::ResumeTask(task_id) => {
+        write!(f, "ResumeTask: {:?}", task_id)
+      }
       BzTaskMessage::TryStopTask(task_id) => {
         write!(f, "TryStopTask: {:?}", task_id)
       }
       BzTaskMessage::StopTask(task_id) => write!(f, "StopTask: {:?}", task_id),
+      BzTaskMessage::TryCancelTask(task_id) => {
+        write!(f, "TryCancelTask: {:?}", task_id)
+      }
+      BzTaskMessage::CancelTask(task_id) => {
+        write!(f, "CancelTask: {:?}", task_id)
+      }
       BzTaskMessage::RemoveTask(task_id) => {
         write!(f, "RemoveTask: {:?}", task_id)
       }
       BzTaskMessage::FinishTask(task_id) => {
         write!(f, "FinishTask: {:?}", task_id)
       }
-      BzTaskMessage::FailTask(task_id) => {
-        write!(f, "FailTask: {:?}", task_id)
+      BzTaskMessage::FailTask(task_id, error) => {
+        write!(f, "FailTask: {:?} {}", task_id, error)
+      }
+      BzTaskMessage::SegmentCorrupted(task_id, segment) => {
+        write!(f, "SegmentCorrupted: {:?} {}", task_id, segment)
+      }
+      BzTaskMessage::TaskNotice(task_id, notice) => {
+        write!(f, "TaskNotice: {:?} {}", task_id, notice)
+      }
+      BzTaskMessage::MoveTaskUp(task_id) => {
+        write!(f, "MoveTaskUp: {:?}", task_id)
+      }
+      BzTaskMessage::MoveTaskDown(task_id) => {
+        write!(f, "MoveTaskDown: {:?}", task_id)
+      }
+      BzTaskMessage::MoveTaskToTop(task_id) => {
+        write!(f, "MoveTaskToTop: {:?}", task_id)
       }
     }
   }
@@ -85,41 +135,69 @@ pub fn deal_bztask_message(
   let cmd = match task_message {
     BzTaskMessage::AddTask(task_info) => {
       log::debug!("[BzTaskMessage::AddTask] : {:?}", task_info);
-      let task = BzTask::from_info(task_info);
+      let mut task = BzTask::from_info(task_info);
       let task_id = task.id;
-      app_state.tasks.insert(task.id, task);
-      Command::done(Message::BzTask(BzTaskMessage::TryStartTask(task_id)))
+      // 添加时选择不立即开始的任务保持暂停状态
+      if task.info.status == BzTaskStatus::Stopped {
+        app_state.tasks.insert(task_id, task);
+        return Ok(Command::none());
+      }
+      task.info.status = BzTaskStatus::Queued;
+      app_state.tasks.insert(task_id, task);
+      app_state.enqueue(task_id);
+      app_state.schedule();
+      Command::none()
     }
     BzTaskMessage::TryStartTask(task_id) => {
+      // 放入队列 等待调度器启动
       log::debug!("[BzTaskMessage::TryStartTask]: {:?}", task_id);
-      let feedback_sender = app_state.feedback_sender.clone();
       let task = assert_task_status(
         app_state,
         task_id,
-        &vec![BzTaskStatus::Queued, BzTaskStatus::Stopped],
+        &vec![
+          BzTaskStatus::Queued,
+          BzTaskStatus::Stopped,
+          BzTaskStatus::Failed,
+        ],
         &task_message,
       )?;
-      let (control_sender, join_handle) =
-        bz_task::run_task(task.id, task.info.clone(), feedback_sender);
-      task.runtime = Some(BzTaskRuntimeInfo {
-        sender: control_sender,
-        join_handle,
-      });
+      if task.runtime.is_some() {
+        return Err(BzError::TaskStatusError(
+          task.info.status,
+          task_message.clone(),
+        ));
+      }
+      task.info.status = BzTaskStatus::Queued;
+      app_state.enqueue(task_id);
+      app_state.schedule();
       Command::none()
     }
     BzTaskMessage::StartTask(task_id) => {
       log::debug!("[BzTaskMessage::StartTask]: {:?}", task_id);
+      let window_open = app_state.download_window_open;
       let task = assert_task_status(
         app_state,
         task_id,
-        &vec![BzTaskStatus::Queued, BzTaskStatus::Stopped],
+        &vec![
+          BzTaskStatus::Queued,
+          BzTaskStatus::Stopped,
+          BzTaskStatus::Failed,
+        ],
         &task_message,
       )?;
       task.info.status = BzTaskStatus::Running;
+      task.extra.error = None;
+      task.extra.corrupted_segments.clear();
+      // 准备完成时下载时段已经结束 暂停等待下次时段开始
+      if !window_open {
+        let runtime = get_runtime_from_task(task)?;
+        let _ = runtime.sender.try_send(bz_task::BzTaskControl::Pause);
+        app_state.window_paused.insert(task_id);
+      }
       Command::none()
     }
-    BzTaskMessage::TryStopTask(task_id) => {
-      log::debug!("[BzTaskMessage::StopTask]: {:?}", task_id);
+    BzTaskMessage::TryPauseTask(task_id) => {
+      log::debug!("[BzTaskMessage::TryPauseTask]: {:?}", task_id);
       let task = assert_task_status(
         app_state,
         task_id,
@@ -127,53 +205,234 @@ pub fn deal_bztask_message(
         &task_message,
       )?;
       let runtime = get_runtime_from_task(task)?;
+      let _ = runtime.sender.try_send(bz_task::BzTaskControl::Pause)?;
+      Command::none()
+    }
+    BzTaskMessage::PauseTask(task_id) => {
+      log::debug!("[BzTaskMessage::PauseTask]: {:?}", task_id);
+      let task = assert_task_status(
+        app_state,
+        task_id,
+        &vec![BzTaskStatus::Running],
+        &task_message,
+      )?;
+      task.info.status = bz_task::BzTaskStatus::Paused;
+      task.extra.stop_speed();
+      Command::none()
+    }
+    BzTaskMessage::TryResumeTask(task_id) => {
+      log::debug!("[BzTaskMessage::TryResumeTask]: {:?}", task_id);
+      let task = assert_task_status(
+        app_state,
+        task_id,
+        &vec![BzTaskStatus::Paused],
+        &task_message,
+      )?;
+      let runtime = get_runtime_from_task(task)?;
+      let _ = runtime.sender.try_send(bz_task::BzTaskControl::Resume)?;
+      Command::none()
+    }
+    BzTaskMessage::ResumeTask(task_id) => {
+      log::debug!("[BzTaskMessage::ResumeTask]: {:?}", task_id);
+      let task = assert_task_status(
+        app_state,
+        task_id,
+        &vec![BzTaskStatus::Paused],
+        &task_message,
+      )?;
+      task.info.status = bz_task::BzTaskStatus::Running;
+      app_state.window_paused.remove(&task_id);
+      Command::none()
+    }
+    BzTaskMessage::TryStopTask(task_id) => {
+      log::debug!("[BzTaskMessage::TryStopTask]: {:?}", task_id);
+      // 准备中的任务状态还是 Queued
+      let task = assert_task_status(
+        app_state,
+        task_id,
+        &vec![
+          BzTaskStatus::Queued,
+          BzTaskStatus::Running,
+          BzTaskStatus::Paused,
+        ],
+        &task_message,
+      )?;
+      // 队列中还没开始的任务直接移出队列 保留缓存
+      if task.runtime.is_none() && task.info.status == BzTaskStatus::Queued {
+        task.info.status = bz_task::BzTaskStatus::Stopped;
+        app_state.queue.retain(|id| *id != task_id);
+        return Ok(Command::none());
+      }
+      let runtime = get_runtime_from_task(task)?;
       let _ = runtime.sender.try_send(bz_task::BzTaskControl::Stop)?;
       Command::none()
     }
     BzTaskMessage::StopTask(task_id) => {
       log::debug!("[BzTaskMessage::StopTask]: {:?}", task_id);
+      // 准备中停止的任务状态还是 Queued
       let task = assert_task_status(
         app_state,
         task_id,
-        &vec![BzTaskStatus::Running],
+        &vec![
+          BzTaskStatus::Queued,
+          BzTaskStatus::Running,
+          BzTaskStatus::Paused,
+        ],
+        &task_message,
+      )?;
+      // worker 发送反馈之后就退出了 直接丢弃 join_handle
+      task.info.status = bz_task::BzTaskStatus::Stopped;
+      task.runtime = None;
+      task.extra.stop_speed();
+      app_state.schedule();
+      Command::none()
+    }
+    BzTaskMessage::TryCancelTask(task_id) => {
+      log::debug!("[BzTaskMessage::TryCancelTask]: {:?}", task_id);
+      let task = assert_task_status(
+        app_state,
+        task_id,
+        &vec![
+          BzTaskStatus::Queued,
+          BzTaskStatus::Running,
+          BzTaskStatus::Paused,
+          BzTaskStatus::Stopped,
+          BzTaskStatus::Failed,
+        ],
+        &task_message,
+      )?;
+      // 有 worker 时由 worker 停止后删除缓存
+      if let Some(runtime) = &task.runtime {
+        // 还在准备中的任务不能取消
+        if task.info.status == BzTaskStatus::Queued {
+          return Err(BzError::TaskStatusError(
+            task.info.status,
+            task_message.clone(),
+          ));
+        }
+        let _ = runtime.sender.try_send(bz_task::BzTaskControl::Cancel)?;
+        return Ok(Command::none());
+      }
+      task.info.status = BzTaskStatus::Stopped;
+      let cache = task.info.cache.clone();
+      app_state.queue.retain(|id| *id != task_id);
+      Command::perform(
+        async move { bz_task::discard_cache(&cache).await },
+        move |_| Message::BzTask(BzTaskMessage::CancelTask(task_id)),
+      )
+    }
+    BzTaskMessage::CancelTask(task_id) => {
+      log::debug!("[BzTaskMessage::CancelTask]: {:?}", task_id);
+      let task = assert_task_status(
+        app_state,
+        task_id,
+        &vec![
+          BzTaskStatus::Running,
+          BzTaskStatus::Paused,
+          BzTaskStatus::Stopped,
+        ],
         &task_message,
       )?;
-      // TODO 这里需要考虑joinhandle的问题
+      // 缓存已经删除 下次从头开始下载
       task.info.status = bz_task::BzTaskStatus::Stopped;
+      task.runtime = None;
+      task.extra = bz_task::BzTaskExtraInfo::default();
+      app_state.schedule();
       Command::none()
     }
     BzTaskMessage::RemoveTask(task_id) => {
       log::debug!("[BzTaskMessage::RemoveTask]: {:?}", task_id);
-      let _task = assert_task_status(
+      let task = assert_task_status(
         app_state,
         task_id,
-        &vec![BzTaskStatus::Stopped],
+        &vec![
+          BzTaskStatus::Queued,
+          BzTaskStatus::Stopped,
+          BzTaskStatus::Completed,
+          BzTaskStatus::Failed,
+        ],
         &task_message,
       )?;
+      // 已经启动但是还没有进入 Running 的任务不能删除
+      if task.runtime.is_some() {
+        return Err(BzError::TaskStatusError(
+          task.info.status,
+          task_message.clone(),
+        ));
+      }
       app_state.tasks.remove(&task_id);
+      app_state.queue.retain(|id| *id != task_id);
       Command::none()
     }
     BzTaskMessage::FinishTask(task_id) => {
       log::debug!("[BzTaskMessage::FinishTask]: {:?}", task_id);
+      // 暂停前已经下载完成的任务
       let task = assert_task_status(
         app_state,
         task_id,
-        &vec![BzTaskStatus::Running],
+        &vec![BzTaskStatus::Running, BzTaskStatus::Paused],
         &task_message,
       )?;
       task.info.status = bz_task::BzTaskStatus::Completed;
       task.extra.progress = 1.0;
+      task.runtime = None;
+      task.extra.stop_speed();
+      app_state.schedule();
       Command::none()
     }
-    BzTaskMessage::FailTask(task_id) => {
-      log::debug!("[BzTaskMessage::FailTask]: {:?}", task_id);
+    BzTaskMessage::FailTask(task_id, ref error) => {
+      log::debug!("[BzTaskMessage::FailTask]: {:?} {}", task_id, error);
+      let error = error.clone();
+      // prepare 阶段失败的时候任务还没有进入 Running
       let task = assert_task_status(
         app_state,
         task_id,
-        &vec![BzTaskStatus::Running],
+        &vec![
+          BzTaskStatus::Queued,
+          BzTaskStatus::Running,
+          BzTaskStatus::Paused,
+          BzTaskStatus::Stopped,
+          BzTaskStatus::Failed,
+        ],
         &task_message,
       )?;
       task.info.status = bz_task::BzTaskStatus::Failed;
+      task.extra.error = Some(error);
+      task.runtime = None;
+      task.extra.stop_speed();
+      app_state.schedule();
+      Command::none()
+    }
+    BzTaskMessage::SegmentCorrupted(task_id, segment) => {
+      log::debug!(
+        "[BzTaskMessage::SegmentCorrupted]: {:?} {}",
+        task_id,
+        segment
+      );
+      let task = get_task_from_btreemap(app_state, task_id)?;
+      task.extra.corrupted_segments.push(segment);
+      Command::none()
+    }
+    BzTaskMessage::TaskNotice(task_id, notice) => {
+      log::debug!("[BzTaskMessage::TaskNotice]: {:?} {}", task_id, notice);
+      let task = get_task_from_btreemap(app_state, task_id)?;
+      let name = task.info.dest.display().to_string();
+      app_state.notices.push(format!("{}: {}", name, notice));
+      Command::none()
+    }
+    BzTaskMessage::MoveTaskUp(task_id) => {
+      log::debug!("[BzTaskMessage::MoveTaskUp]: {:?}", task_id);
+      app_state.move_queued_task(task_id, -1);
+      Command::none()
+    }
+    BzTaskMessage::MoveTaskDown(task_id) => {
+      log::debug!("[BzTaskMessage::MoveTaskDown]: {:?}", task_id);
+      app_state.move_queued_task(task_id, 1);
+      Command::none()
+    }
+    BzTaskMessage::MoveTaskToTop(task_id) => {
+      log::debug!("[BzTaskMessage::MoveTaskToTop]: {:?}", task_id);
+      app_state.move_queued_task_to_top(task_id);
       Command::none()
     }
   };
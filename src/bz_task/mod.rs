@@ -1,15 +1,24 @@
+mod control;
 mod id;
 mod info;
 pub mod message;
+mod speed;
 mod task;
 
 pub use info::{
-  BzTask, BzTaskControl, BzTaskControlFeedBack, BzTaskControlFeedBackMessage,
+  BzContainer, BzDashSelection, BzRecordLimit, BzRenditionSelection, BzTask,
+  BzTaskControl, BzTaskControlFeedBack, BzTaskControlFeedBackMessage,
   BzTaskExtraInfo, BzTaskFeedBack, BzTaskInfo, BzTaskInfoFeedBackMessage,
-  BzTaskRuntimeInfo, BzTaskStatus, BzTaskType,
+  BzTaskRuntimeInfo, BzTaskStatus, BzTaskType, BzVariantPolicy,
+  DEFAULT_CONCURRENCY, DUMP_INTERVAL, MAX_CONCURRENCY,
 };
 
+pub use control::{BzStopReason, BzTaskController};
 pub use id::BzTaskId;
 pub use message::BzTaskMessage;
 pub use message::deal_bztask_message;
-pub use task::{Task, TaskProgress, feed_back_subscription, run_task};
+pub use speed::{BzSpeedMeter, REPORT_INTERVAL};
+pub use task::{
+  Task, TaskProgress, discard_cache, feed_back_subscription, run_task,
+  stop_tasks,
+};
@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::{BzTaskId, BzTaskInfoFeedBackMessage};
+
+// 瞬时速度按照最近几秒内下载的字节数计算
+const SPEED_WINDOW: Duration = Duration::from_secs(3);
+// 下载分片时定时汇报进度的间隔 避免界面刷新过于频繁
+pub const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+// 统计本次启动之后的下载速度
+#[derive(Debug)]
+pub struct BzSpeedMeter {
+  started: Instant,
+  bytes: u64,
+  // (时间, 到这个时间为止下载的字节数)
+  samples: VecDeque<(Instant, u64)>,
+}
+
+impl Default for BzSpeedMeter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl BzSpeedMeter {
+  pub fn new() -> Self {
+    let now = Instant::now();
+    Self {
+      started: now,
+      bytes: 0,
+      samples: VecDeque::from([(now, 0)]),
+    }
+  }
+
+  pub fn add(&mut self, bytes: u64) {
+    self.add_at(bytes, Instant::now());
+  }
+
+  fn add_at(&mut self, bytes: u64, now: Instant) {
+    self.bytes += bytes;
+    self.samples.push_back((now, self.bytes));
+    // 保留一个窗口之外的样本作为起点
+    while self
+      .samples
+      .get(1)
+      .is_some_and(|(time, _)| now.duration_since(*time) > SPEED_WINDOW)
+    {
+      self.samples.pop_front();
+    }
+  }
+
+  // 字节每秒
+  pub fn speed(&self) -> f64 {
+    self.speed_at(Instant::now())
+  }
+
+  fn speed_at(&self, now: Instant) -> f64 {
+    let Some((time, bytes)) = self.samples.front() else {
+      return 0.0;
+    };
+    let elapsed = now.duration_since(*time).as_secs_f64();
+    match elapsed > 0.0 {
+      true => (self.bytes - bytes) as f64 / elapsed,
+      false => 0.0,
+    }
+  }
+
+  pub fn average_speed(&self) -> f64 {
+    self.average_speed_at(Instant::now())
+  }
+
+  fn average_speed_at(&self, now: Instant) -> f64 {
+    let elapsed = now.duration_since(self.started).as_secs_f64();
+    match elapsed > 0.0 {
+      true => self.bytes as f64 / elapsed,
+      false => 0.0,
+    }
+  }
+
+  // 暂停时没有下载 速度为 0
+  pub fn feedback(
+    &self, task_id: BzTaskId, progress: f32, current_size: u64,
+    total_size: Option<u64>, paused: bool,
+  ) -> BzTaskInfoFeedBackMessage {
+    let (speed, average_speed) = match paused {
+      true => (0.0, 0.0),
+      false => (self.speed(), self.average_speed()),
+    };
+    BzTaskInfoFeedBackMessage {
+      task_id,
+      progress,
+      current_size,
+      total_size,
+      speed,
+      average_speed,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_speed_meter() {
+    let mut meter = BzSpeedMeter::new();
+    let start = meter.started;
+    for second in 1..=10 {
+      meter.add_at(1000, start + Duration::from_secs(second));
+    }
+    let now = start + Duration::from_secs(10);
+    assert_eq!(meter.average_speed_at(now), 1000.0);
+    assert_eq!(meter.speed_at(now), 1000.0);
+    // 停止下载后瞬时速度下降 平均速度按照总时间计算
+    let now = start + Duration::from_secs(14);
+    meter.add_at(0, now);
+    assert_eq!(meter.speed_at(now), 0.0);
+    assert_eq!(meter.average_speed_at(now), 10000.0 / 14.0);
+    let feedback = meter.feedback(BzTaskId::unique(), 0.5, 10000, None, true);
+    assert_eq!((feedback.speed, feedback.average_speed), (0.0, 0.0));
+  }
+}
@@ -2,18 +2,24 @@ use iced::{
   futures::{SinkExt, Stream},
   stream,
 };
+use std::path::Path;
+use std::time::Duration;
+
 use tokio::{sync::mpsc, task::JoinHandle};
 
 use crate::{
   bz_downloader::Message,
   bz_task::{BzTaskControl, BzTaskFeedBack, BzTaskInfo},
+  dash::DashTask,
+  error::{BzError, BzResult},
+  http_file::HttpTask,
   m3u8::M3u8Task,
   zfs::ZfsTask,
 };
 
 use super::{
-  BzTaskControlFeedBackMessage, BzTaskId, BzTaskInfoFeedBackMessage,
-  BzTaskMessage, BzTaskType, info::BzTaskControlFeedBack,
+  BzStopReason, BzTaskControlFeedBack, BzTaskController, BzTaskId,
+  BzTaskMessage, BzTaskRuntimeInfo, BzTaskType,
 };
 
 // Task Progress
@@ -31,15 +37,58 @@ pub trait TaskProgress {
 }
 
 // 后端所代表的任务
+// start 收到停止或者取消消息时返回 BzError::Cancelled
+// 暂停时保留 client 和内存中的进度 正在进行的请求通过 controller.gate() 挂起
 pub trait Task {
   fn new_task(task_info: BzTaskInfo) -> Self;
-  async fn prepare(&mut self);
-  async fn start(
-    &mut self, task_id: BzTaskId,
-    control_receiver: mpsc::Receiver<BzTaskControl>,
-    feedback_sender: mpsc::Sender<BzTaskFeedBack>,
-  ) -> bool;
-  async fn finish(&mut self);
+  // 每个阶段结束后取出需要提示用户的信息
+  fn take_notices(&mut self) -> Vec<String> {
+    Vec::new()
+  }
+  async fn prepare(&mut self) -> BzResult<()>;
+  async fn start(&mut self, controller: &mut BzTaskController) -> BzResult<()>;
+  async fn finish(&mut self) -> BzResult<()>;
+}
+
+async fn send_notices<T: Task>(task: &mut T, controller: &BzTaskController) {
+  for notice in task.take_notices() {
+    controller
+      .send_control(BzTaskControlFeedBack::Notice(notice))
+      .await;
+  }
+}
+
+// 准备阶段也要响应停止 索引文件很慢时不用等到超时
+async fn prepare_or_stop<T: Task>(
+  task: &mut T, controller: &mut BzTaskController,
+) -> BzResult<()> {
+  let prepare = task.prepare();
+  tokio::pin!(prepare);
+  loop {
+    tokio::select! {
+      res = &mut prepare => return res,
+      _ = controller.recv() => {
+        if controller.is_stopping() {
+          return Err(BzError::Cancelled);
+        }
+      }
+    }
+  }
+}
+
+async fn run_task_stages<T: Task>(
+  task: &mut T, controller: &mut BzTaskController,
+) -> BzResult<()> {
+  let res = prepare_or_stop(task, controller).await;
+  send_notices(task, controller).await;
+  res?;
+  controller
+    .send_control(BzTaskControlFeedBack::Started)
+    .await;
+  task.start(controller).await?;
+  let res = task.finish().await;
+  send_notices(task, controller).await;
+  res
 }
 
 pub async fn run_task_impl<T: Task>(
@@ -47,25 +96,38 @@ pub async fn run_task_impl<T: Task>(
   control_receiver: mpsc::Receiver<BzTaskControl>,
   feedback_sender: mpsc::Sender<BzTaskFeedBack>,
 ) {
+  let cache = task_info.cache.clone();
   let mut task: T = T::new_task(task_info);
-  task.prepare().await;
-  let _ = feedback_sender
-    .send(BzTaskFeedBack::TaskConrol(BzTaskControlFeedBackMessage {
-      task_id,
-      control: BzTaskControlFeedBack::Started,
-    }))
-    .await;
-  let is_finished = task
-    .start(task_id, control_receiver, feedback_sender.clone())
-    .await;
-  if is_finished {
-    task.finish().await;
-    let _ = feedback_sender
-      .send(BzTaskFeedBack::TaskConrol(BzTaskControlFeedBackMessage {
-        task_id,
-        control: BzTaskControlFeedBack::Finished,
-      }))
-      .await;
+  let mut controller =
+    BzTaskController::new(task_id, control_receiver, feedback_sender);
+  let res = run_task_stages(&mut task, &mut controller).await;
+  let control = match res {
+    Ok(()) => BzTaskControlFeedBack::Finished,
+    Err(BzError::Cancelled) => match controller.stop_reason() {
+      Some(BzStopReason::Cancel) => {
+        // 先释放任务中打开的文件 再删除缓存
+        drop(task);
+        discard_cache(&cache).await;
+        BzTaskControlFeedBack::Cancelled
+      }
+      _ => BzTaskControlFeedBack::Stoped,
+    },
+    Err(err) => {
+      log::error!("task {} failed: {}", task_id, err);
+      BzTaskControlFeedBack::Failed(err.to_string())
+    }
+  };
+  controller.send_control(control).await;
+}
+
+// 取消任务时删除已经下载的内容 下次从头开始
+pub async fn discard_cache(cache: &Path) {
+  match tokio::fs::remove_dir_all(cache).await {
+    Ok(()) => log::info!("discard cache: {}", cache.display()),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+    Err(err) => {
+      log::error!("failed to discard cache {}: {}", cache.display(), err)
+    }
   }
 }
 
@@ -95,11 +157,48 @@ pub fn run_task(
         )
         .await
       }
+      BzTaskType::Dash => {
+        run_task_impl::<DashTask>(
+          task_id,
+          task_info,
+          control_receiver,
+          feedback_sender,
+        )
+        .await
+      }
+      BzTaskType::Http => {
+        run_task_impl::<HttpTask>(
+          task_id,
+          task_info,
+          control_receiver,
+          feedback_sender,
+        )
+        .await
+      }
     };
   });
   return (control_sender, handle);
 }
 
+// 退出程序时停止所有的worker
+// 先给所有worker发送停止消息 再等待它们退出 超时的worker直接abort
+pub async fn stop_tasks(runtimes: Vec<BzTaskRuntimeInfo>, timeout: Duration) {
+  for runtime in &runtimes {
+    let _ = runtime.sender.try_send(BzTaskControl::Shutdown);
+  }
+  let deadline = tokio::time::Instant::now() + timeout;
+  for runtime in runtimes {
+    let mut join_handle = runtime.join_handle;
+    if tokio::time::timeout_at(deadline, &mut join_handle)
+      .await
+      .is_err()
+    {
+      log::warn!("worker not stopped in {:?}, abort it", timeout);
+      join_handle.abort();
+    }
+  }
+}
+
 // 供iced subscription使用 用于接受任务下载时候反馈的信息
 pub fn feed_back_subscription() -> impl Stream<Item = Message> {
   stream::channel(100, |mut output| async move {
@@ -125,6 +224,27 @@ pub fn feed_back_subscription() -> impl Stream<Item = Message> {
                 );
                 Message::BzTask(BzTaskMessage::StopTask(task_id))
               }
+              BzTaskControlFeedBack::Paused => {
+                log::debug!(
+                  "[subscription] Task Paused: {:?}",
+                  control_message.task_id
+                );
+                Message::BzTask(BzTaskMessage::PauseTask(task_id))
+              }
+              BzTaskControlFeedBack::Resumed => {
+                log::debug!(
+                  "[subscription] Task Resumed: {:?}",
+                  control_message.task_id
+                );
+                Message::BzTask(BzTaskMessage::ResumeTask(task_id))
+              }
+              BzTaskControlFeedBack::Cancelled => {
+                log::debug!(
+                  "[subscription] Task Cancelled: {:?}",
+                  control_message.task_id
+                );
+                Message::BzTask(BzTaskMessage::CancelTask(task_id))
+              }
               BzTaskControlFeedBack::Finished => {
                 log::debug!(
                   "[subscription] Task Finished: {:?}",
@@ -132,23 +252,38 @@ pub fn feed_back_subscription() -> impl Stream<Item = Message> {
                 );
                 Message::BzTask(BzTaskMessage::FinishTask(task_id))
               }
-              BzTaskControlFeedBack::Failed => {
+              BzTaskControlFeedBack::Failed(error) => {
                 log::debug!(
-                  "[subscription] Task Failed: {:?}",
-                  control_message.task_id
+                  "[subscription] Task Failed: {:?} {}",
+                  control_message.task_id,
+                  error
                 );
-                Message::BzTask(BzTaskMessage::FailTask(task_id))
+                Message::BzTask(BzTaskMessage::FailTask(task_id, error))
+              }
+              BzTaskControlFeedBack::SegmentCorrupted(segment) => {
+                log::debug!(
+                  "[subscription] Segment Corrupted: {:?} {}",
+                  control_message.task_id,
+                  segment
+                );
+                Message::BzTask(BzTaskMessage::SegmentCorrupted(
+                  task_id, segment,
+                ))
+              }
+              BzTaskControlFeedBack::Notice(notice) => {
+                log::debug!(
+                  "[subscription] Task Notice: {:?} {}",
+                  control_message.task_id,
+                  notice
+                );
+                Message::BzTask(BzTaskMessage::TaskNotice(task_id, notice))
               }
             };
 
             let _ = output.send(message).await;
           }
           BzTaskFeedBack::TaskInfo(info_message) => {
-            let message =
-              Message::TaskInfoFeedBack(BzTaskInfoFeedBackMessage {
-                task_id: info_message.task_id,
-                progress: info_message.progress,
-              });
+            let message = Message::TaskInfoFeedBack(info_message);
             let _ = output.send(message).await;
           }
         }
@@ -156,3 +291,132 @@ pub fn feed_back_subscription() -> impl Stream<Item = Message> {
     }
   })
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bz_task::{
+    BzContainer, BzDashSelection, BzRecordLimit, BzRenditionSelection,
+    BzTaskStatus, BzVariantPolicy,
+  };
+  use crate::http::{BzRetryPolicy, BzTaskProxy};
+  use crate::rate_limit::BzSpeedLimit;
+
+  // start 阶段返回错误 src 为 /cancel 时模拟用户停止
+  struct ErrorTask {
+    cancelled: bool,
+  }
+
+  impl Task for ErrorTask {
+    fn new_task(task_info: BzTaskInfo) -> Self {
+      ErrorTask {
+        cancelled: task_info.src.path() == "/cancel",
+      }
+    }
+
+    async fn prepare(&mut self) -> BzResult<()> {
+      Ok(())
+    }
+
+    async fn start(
+      &mut self, _controller: &mut BzTaskController,
+    ) -> BzResult<()> {
+      match self.cancelled {
+        true => Err(BzError::Cancelled),
+        false => Err(BzError::Parse {
+          reason: "bad playlist".to_string(),
+        }),
+      }
+    }
+
+    async fn finish(&mut self) -> BzResult<()> {
+      Ok(())
+    }
+  }
+
+  async fn run_error_task(src: &str) -> Vec<BzTaskControlFeedBack> {
+    let (feedback_sender, mut feedback_receiver) = mpsc::channel(10);
+    let (_control_sender, control_receiver) = mpsc::channel(1);
+    let task_info = BzTaskInfo {
+      src: reqwest::Url::parse(src).unwrap(),
+      dest: "./tmp/1.mp4".into(),
+      cache: "./tmp".into(),
+      kind: BzTaskType::Zfs,
+      status: BzTaskStatus::Queued,
+      variant: BzVariantPolicy::default(),
+      concurrency: 4,
+      retry: BzRetryPolicy::default(),
+      headers: Vec::new(),
+      user_agent: None,
+      cookies: Vec::new(),
+      proxy: BzTaskProxy::default(),
+      speed_limit: BzSpeedLimit::default(),
+      start_at: None,
+      record_limit: BzRecordLimit::default(),
+      container: BzContainer::default(),
+      dash: BzDashSelection::default(),
+      renditions: BzRenditionSelection::default(),
+    };
+    run_task_impl::<ErrorTask>(
+      BzTaskId::unique(),
+      task_info,
+      control_receiver,
+      feedback_sender,
+    )
+    .await;
+    let mut controls = Vec::new();
+    while let Some(BzTaskFeedBack::TaskConrol(message)) =
+      feedback_receiver.recv().await
+    {
+      controls.push(message.control);
+    }
+    controls
+  }
+
+  #[tokio::test]
+  async fn test_stop_tasks() {
+    // 收到停止消息后退出
+    let (sender, mut receiver) = mpsc::channel(1);
+    let join_handle = tokio::spawn(async move {
+      receiver.recv().await;
+    });
+    let stoppable = BzTaskRuntimeInfo {
+      sender,
+      join_handle,
+    };
+    // 不处理停止消息
+    let (sender, _receiver) = mpsc::channel(1);
+    let join_handle = tokio::spawn(async {
+      tokio::time::sleep(Duration::from_secs(60)).await;
+    });
+    let abort_handle = join_handle.abort_handle();
+    let stuck = BzTaskRuntimeInfo {
+      sender,
+      join_handle,
+    };
+    stop_tasks(vec![stoppable, stuck], Duration::from_millis(100)).await;
+    tokio::task::yield_now().await;
+    assert!(abort_handle.is_finished());
+  }
+
+  #[tokio::test]
+  async fn test_run_task_impl_feedback() {
+    let controls = run_error_task("https://example.com/cancel").await;
+    assert!(matches!(
+      controls.as_slice(),
+      [
+        BzTaskControlFeedBack::Started,
+        BzTaskControlFeedBack::Stoped
+      ]
+    ));
+
+    let controls = run_error_task("https://example.com/index.m3u8").await;
+    assert!(matches!(
+      controls.as_slice(),
+      [
+        BzTaskControlFeedBack::Started,
+        BzTaskControlFeedBack::Failed(error),
+      ] if error.contains("bad playlist")
+    ));
+  }
+}
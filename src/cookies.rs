@@ -0,0 +1,165 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use reqwest::Url;
+use reqwest::cookie::Jar;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BzError, BzResult};
+
+// 从浏览器导出的 cookie
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BzCookie {
+  pub domain: String,
+  // 子域名是否同样发送
+  pub include_subdomains: bool,
+  pub path: String,
+  // 只通过 https 发送
+  pub secure: bool,
+  // unix 时间戳 None 表示会话 cookie
+  pub expires: Option<u64>,
+  pub name: String,
+  pub value: String,
+}
+
+impl BzCookie {
+  fn is_expired(&self, now: u64) -> bool {
+    self.expires.is_some_and(|expires| expires <= now)
+  }
+
+  // 添加到 cookie jar 时需要一个对应的链接
+  fn url(&self) -> Option<Url> {
+    let scheme = match self.secure {
+      true => "https",
+      false => "http",
+    };
+    let host = self.domain.trim_start_matches('.');
+    Url::parse(&format!("{}://{}{}", scheme, host, self.path)).ok()
+  }
+
+  fn set_cookie(&self) -> String {
+    let mut cookie =
+      format!("{}={}; Path={}", self.name, self.value, self.path);
+    if self.include_subdomains {
+      cookie.push_str(&format!("; Domain={}", self.domain));
+    }
+    if self.secure {
+      cookie.push_str("; Secure");
+    }
+    cookie
+  }
+}
+
+// Netscape 格式的 cookies.txt 每行用 tab 分隔
+// domain include_subdomains path secure expires name value
+// curl 和浏览器插件导出的 HttpOnly cookie 以 #HttpOnly_ 开头
+pub fn parse_netscape_cookies(content: &str) -> BzResult<Vec<BzCookie>> {
+  let mut cookies = Vec::new();
+  for (index, line) in content.lines().enumerate() {
+    let line = line.trim_end_matches('\r');
+    let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+    if line.trim().is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let fields = line.split('\t').collect::<Vec<_>>();
+    let parse_error = |reason: &str| BzError::Parse {
+      reason: format!("cookies.txt line {}: {}", index + 1, reason),
+    };
+    let parse_bool = |value: &str| match value {
+      "TRUE" => Ok(true),
+      "FALSE" => Ok(false),
+      _ => Err(parse_error(&format!("invalid flag {}", value))),
+    };
+    let [
+      domain,
+      include_subdomains,
+      path,
+      secure,
+      expires,
+      name,
+      rest @ ..,
+    ] = fields.as_slice()
+    else {
+      return Err(parse_error("expect 7 fields separated by tab"));
+    };
+    let expires = expires
+      .parse::<u64>()
+      .map_err(|_| parse_error(&format!("invalid expires {}", expires)))?;
+    cookies.push(BzCookie {
+      domain: domain.to_string(),
+      include_subdomains: parse_bool(include_subdomains)?,
+      path: path.to_string(),
+      secure: parse_bool(secure)?,
+      expires: (expires > 0).then_some(expires),
+      name: name.to_string(),
+      // 值为空时可能没有最后一个字段
+      value: rest.join("\t"),
+    });
+  }
+  Ok(cookies)
+}
+
+// 已经过期的 cookie 不再发送
+pub fn cookie_jar(cookies: &[BzCookie]) -> Jar {
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs();
+  let jar = Jar::default();
+  for cookie in cookies.iter().filter(|cookie| !cookie.is_expired(now)) {
+    match cookie.url() {
+      Some(url) => jar.add_cookie_str(&cookie.set_cookie(), &url),
+      None => log::warn!("skip cookie with invalid domain: {}", cookie.domain),
+    }
+  }
+  jar
+}
+
+#[cfg(test)]
+mod tests {
+  use reqwest::cookie::CookieStore;
+
+  use super::*;
+
+  #[test]
+  fn test_parse_netscape_cookies() {
+    let content = "# Netscape HTTP Cookie File\n\
+      \n\
+      .example.com\tTRUE\t/\tFALSE\t0\tsession\tabc\n\
+      #HttpOnly_cdn.example.com\tFALSE\t/hls\tTRUE\t4102444800\ttoken\tx=1\n\
+      .example.com\tTRUE\t/\tFALSE\t1\texpired\told\n\
+      .example.com\tTRUE\t/\tFALSE\t0\tempty\n";
+    let cookies = parse_netscape_cookies(content).unwrap();
+    assert_eq!(cookies.len(), 4);
+    assert_eq!(cookies[0].expires, None);
+    assert_eq!(cookies[1].domain, "cdn.example.com");
+    assert_eq!(cookies[1].value, "x=1");
+    assert_eq!(cookies[3].value, "");
+
+    let jar = cookie_jar(&cookies);
+    // jar 中 cookie 的顺序不固定
+    let cookie_of = |url: &str| {
+      jar.cookies(&Url::parse(url).unwrap()).map(|value| {
+        let mut cookies =
+          value.to_str().unwrap().split("; ").collect::<Vec<_>>();
+        cookies.sort();
+        cookies.join("; ")
+      })
+    };
+    assert_eq!(
+      cookie_of("https://cdn.example.com/hls/index.m3u8").as_deref(),
+      Some("empty=; session=abc; token=x=1")
+    );
+    // 只发送给对应的域名和路径 secure 的只通过 https 发送
+    assert_eq!(
+      cookie_of("https://www.example.com/").as_deref(),
+      Some("empty=; session=abc")
+    );
+    assert_eq!(
+      cookie_of("http://cdn.example.com/hls/1.ts").as_deref(),
+      Some("empty=; session=abc")
+    );
+    assert_eq!(cookie_of("https://other.com/"), None);
+
+    assert!(parse_netscape_cookies("example.com\tTRUE\t/").is_err());
+  }
+}
@@ -0,0 +1,1159 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use roxmltree::Node;
+use tokio::task::JoinSet;
+
+use reqwest::Url;
+
+use crate::bz_task::{
+  BzDashSelection, BzSpeedMeter, BzTaskController, BzTaskInfo, BzVariantPolicy,
+  MAX_CONCURRENCY, REPORT_INTERVAL,
+};
+use crate::bz_task::{Task, TaskProgress};
+use crate::error::{BzError, BzResult};
+use crate::http::{self, BzRequestConfig};
+use crate::m3u8::{
+  M3u8ByteRange, M3u8TaskProgress, M3u8TaskProgressMessage, download_segment,
+};
+use crate::rate_limit::BzRateLimiter;
+use crate::remux::{self, FragmentedSegment, FragmentedTrack};
+
+fn parse_error(reason: String) -> BzError {
+  BzError::Parse { reason }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DashTrackKind {
+  Video,
+  Audio,
+}
+
+impl DashTrackKind {
+  fn name(&self) -> &'static str {
+    match self {
+      DashTrackKind::Video => "video",
+      DashTrackKind::Audio => "audio",
+    }
+  }
+}
+
+// 界面上展示的 Representation
+#[derive(Debug, Clone, PartialEq)]
+pub struct DashRepresentation {
+  pub id: String,
+  pub kind: DashTrackKind,
+  pub bandwidth: u64,
+  pub width: Option<u64>,
+  pub height: Option<u64>,
+  pub codecs: Option<String>,
+}
+
+impl fmt::Display for DashRepresentation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let (Some(width), Some(height)) = (self.width, self.height) {
+      write!(f, "{}x{} ", width, height)?;
+    }
+    if let Some(codecs) = &self.codecs {
+      write!(f, "{} ", codecs)?;
+    }
+    write!(f, "{}kbps", self.bandwidth / 1000)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct DashTimelineEntry {
+  time: Option<u64>,
+  duration: u64,
+  repeat: i64,
+}
+
+// 分片的地址信息 Representation 上没有的属性从 AdaptationSet 和 Period 继承
+#[derive(Debug, Clone, PartialEq)]
+enum DashAddressing {
+  Template {
+    media: String,
+    initialization: Option<String>,
+    start_number: u64,
+    timescale: u64,
+    duration: Option<u64>,
+    timeline: Option<Vec<DashTimelineEntry>>,
+  },
+  List {
+    initialization: Option<(Option<String>, Option<M3u8ByteRange>)>,
+    segments: Vec<(Option<String>, Option<M3u8ByteRange>)>,
+    timescale: u64,
+    duration: Option<u64>,
+  },
+  // 整个文件 通过 sidx 划分分片
+  Base {
+    initialization: Option<M3u8ByteRange>,
+    index_range: Option<M3u8ByteRange>,
+  },
+}
+
+#[derive(Debug, Clone)]
+pub struct MpdRepresentation {
+  pub info: DashRepresentation,
+  base_url: Url,
+  addressing: DashAddressing,
+  // Period 的时长 单位秒
+  period_duration: Option<f64>,
+  // 有 ContentProtection 的内容无法解密
+  protected: bool,
+}
+
+#[derive(Debug, Clone)]
+struct DashResource {
+  url: Url,
+  byte_range: Option<M3u8ByteRange>,
+}
+
+// 初始化分片 和媒体分片以及开始时间
+type DashSegments = (Option<DashResource>, Vec<(DashResource, f64)>);
+
+#[derive(Debug, Clone)]
+struct DashSegment {
+  sequence: u64,
+  resource: DashResource,
+  // 开始时间 单位秒
+  time: f64,
+}
+
+impl DashSegment {
+  fn file_name(&self) -> String {
+    format!("{}.seg", self.sequence)
+  }
+}
+
+struct DashTrack {
+  kind: DashTrackKind,
+  init: DashResource,
+  segments: Vec<DashSegment>,
+}
+
+impl DashTrack {
+  fn init_file_name(&self) -> String {
+    format!("{}.init", self.kind.name())
+  }
+}
+
+fn child<'a, 'input>(
+  node: Node<'a, 'input>, name: &str,
+) -> Option<Node<'a, 'input>> {
+  node
+    .children()
+    .find(|child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn children<'a, 'input: 'a>(
+  node: Node<'a, 'input>, name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+  node
+    .children()
+    .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn join_base(base: &Url, node: Node) -> BzResult<Url> {
+  match child(node, "BaseURL").and_then(|base_url| base_url.text()) {
+    Some(uri) => http::join_url(base, uri.trim()),
+    None => Ok(base.clone()),
+  }
+}
+
+// ISO 8601 时长 例如 PT1H2M3.5S
+fn parse_duration(value: &str) -> Option<f64> {
+  let value = value.trim().strip_prefix('P')?;
+  let (days, time) = value.split_once('T').unwrap_or((value, ""));
+  let mut seconds = match days.strip_suffix('D') {
+    Some(days) => days.parse::<f64>().ok()? * 86400.0,
+    None if days.is_empty() => 0.0,
+    None => return None,
+  };
+  let mut number = String::new();
+  for c in time.chars() {
+    let unit = match c {
+      'H' => 3600.0,
+      'M' => 60.0,
+      'S' => 1.0,
+      c => {
+        number.push(c);
+        continue;
+      }
+    };
+    seconds += number.parse::<f64>().ok()? * unit;
+    number.clear();
+  }
+  Some(seconds)
+}
+
+// "<first>-<last>" 两端都包含
+fn parse_range(value: &str) -> Option<M3u8ByteRange> {
+  let (first, last) = value.split_once('-')?;
+  let first = first.trim().parse::<u64>().ok()?;
+  let last = last.trim().parse::<u64>().ok()?;
+  (last >= first).then(|| M3u8ByteRange {
+    length: last - first + 1,
+    offset: first,
+  })
+}
+
+// 替换 $RepresentationID$ $Number$ $Time$ $Bandwidth$ 支持 %05d 格式
+fn fill_template(
+  template: &str, id: &str, bandwidth: u64, number: u64, time: u64,
+) -> String {
+  let mut result = String::new();
+  for (index, part) in template.split('$').enumerate() {
+    if index % 2 == 0 {
+      result.push_str(part);
+      continue;
+    }
+    let (name, format) = part.split_once('%').unwrap_or((part, ""));
+    let value = match name {
+      "" => {
+        result.push('$');
+        continue;
+      }
+      "RepresentationID" => {
+        result.push_str(id);
+        continue;
+      }
+      "Number" => number,
+      "Time" => time,
+      "Bandwidth" => bandwidth,
+      _ => {
+        result.push('$');
+        result.push_str(part);
+        result.push('$');
+        continue;
+      }
+    };
+    let width = format
+      .strip_prefix('0')
+      .and_then(|format| format.strip_suffix('d'))
+      .and_then(|width| width.parse::<usize>().ok())
+      .unwrap_or(0);
+    result.push_str(&format!("{:0width$}", value, width = width));
+  }
+  result
+}
+
+fn parse_addressing(nodes: &[Node]) -> Option<DashAddressing> {
+  let find = |name: &str| -> Vec<Node> {
+    nodes.iter().filter_map(|node| child(*node, name)).collect()
+  };
+  let number = |nodes: &[Node], name: &str| {
+    nodes
+      .iter()
+      .find_map(|node| node.attribute(name))
+      .and_then(|value| value.parse::<u64>().ok())
+  };
+
+  let templates = find("SegmentTemplate");
+  if !templates.is_empty() {
+    let attribute = |name: &str| {
+      templates
+        .iter()
+        .find_map(|node| node.attribute(name))
+        .map(str::to_string)
+    };
+    let timeline = templates
+      .iter()
+      .find_map(|node| child(*node, "SegmentTimeline"))
+      .map(|timeline| {
+        children(timeline, "S")
+          .map(|entry| DashTimelineEntry {
+            time: entry.attribute("t").and_then(|t| t.parse().ok()),
+            duration: entry
+              .attribute("d")
+              .and_then(|d| d.parse().ok())
+              .unwrap_or(0),
+            repeat: entry
+              .attribute("r")
+              .and_then(|r| r.parse().ok())
+              .unwrap_or(0),
+          })
+          .collect()
+      });
+    return Some(DashAddressing::Template {
+      media: attribute("media")?,
+      initialization: attribute("initialization"),
+      start_number: number(&templates, "startNumber").unwrap_or(1),
+      timescale: number(&templates, "timescale").unwrap_or(1),
+      duration: number(&templates, "duration"),
+      timeline,
+    });
+  }
+
+  let lists = find("SegmentList");
+  if !lists.is_empty() {
+    let initialization = lists
+      .iter()
+      .find_map(|node| child(*node, "Initialization"))
+      .map(|init| {
+        (
+          init.attribute("sourceURL").map(str::to_string),
+          init.attribute("range").and_then(parse_range),
+        )
+      });
+    let segments = lists
+      .iter()
+      .map(|node| {
+        children(*node, "SegmentURL")
+          .map(|segment| {
+            (
+              segment.attribute("media").map(str::to_string),
+              segment.attribute("mediaRange").and_then(parse_range),
+            )
+          })
+          .collect::<Vec<_>>()
+      })
+      .find(|segments| !segments.is_empty())
+      .unwrap_or_default();
+    return Some(DashAddressing::List {
+      initialization,
+      segments,
+      timescale: number(&lists, "timescale").unwrap_or(1),
+      duration: number(&lists, "duration"),
+    });
+  }
+
+  let bases = find("SegmentBase");
+  Some(DashAddressing::Base {
+    initialization: bases
+      .iter()
+      .find_map(|node| child(*node, "Initialization"))
+      .and_then(|init| init.attribute("range"))
+      .and_then(parse_range),
+    index_range: bases
+      .iter()
+      .find_map(|node| node.attribute("indexRange"))
+      .and_then(parse_range),
+  })
+}
+
+// 只处理第一个 Period 中的音频和视频
+pub fn parse_mpd(
+  content: &str, mpd_url: &Url,
+) -> BzResult<Vec<MpdRepresentation>> {
+  let document = roxmltree::Document::parse(content)
+    .map_err(|err| parse_error(format!("invalid mpd {}: {}", mpd_url, err)))?;
+  let mpd = document.root_element();
+  if mpd.tag_name().name() != "MPD" {
+    return Err(parse_error(format!(
+      "invalid mpd {}: root is not MPD",
+      mpd_url
+    )));
+  }
+  if mpd.attribute("type") == Some("dynamic") {
+    return Err(parse_error(format!(
+      "live dash is not supported: {}",
+      mpd_url
+    )));
+  }
+  let total_duration = mpd
+    .attribute("mediaPresentationDuration")
+    .and_then(parse_duration);
+  let base_url = join_base(mpd_url, mpd)?;
+  let mut periods = children(mpd, "Period");
+  let period = periods
+    .next()
+    .ok_or_else(|| parse_error(format!("no period found in {}", mpd_url)))?;
+  if periods.next().is_some() {
+    log::warn!(
+      "multiple periods in {}, only the first one is downloaded",
+      mpd_url
+    );
+  }
+  let period_duration = period
+    .attribute("duration")
+    .and_then(parse_duration)
+    .or(total_duration);
+  let period_base = join_base(&base_url, period)?;
+
+  let mut representations = Vec::new();
+  for adaptation in children(period, "AdaptationSet") {
+    let adaptation_base = join_base(&period_base, adaptation)?;
+    for representation in children(adaptation, "Representation") {
+      let attribute = |name: &str| {
+        representation
+          .attribute(name)
+          .or(adaptation.attribute(name))
+      };
+      let mime_type = attribute("mimeType").unwrap_or_default();
+      let content_type =
+        adaptation.attribute("contentType").unwrap_or_default();
+      let kind = if content_type == "video" || mime_type.starts_with("video/") {
+        DashTrackKind::Video
+      } else if content_type == "audio" || mime_type.starts_with("audio/") {
+        DashTrackKind::Audio
+      } else {
+        continue;
+      };
+      if !mime_type.is_empty() && !mime_type.ends_with("mp4") {
+        log::warn!("ignore unsupported representation {}", mime_type);
+        continue;
+      }
+      let Some(addressing) =
+        parse_addressing(&[representation, adaptation, period])
+      else {
+        log::warn!("ignore representation without media template");
+        continue;
+      };
+      let number = |name: &str| {
+        attribute(name).and_then(|value| value.parse::<u64>().ok())
+      };
+      representations.push(MpdRepresentation {
+        info: DashRepresentation {
+          id: representation
+            .attribute("id")
+            .unwrap_or_default()
+            .to_string(),
+          kind,
+          bandwidth: number("bandwidth").unwrap_or(0),
+          width: number("width"),
+          height: number("height"),
+          codecs: attribute("codecs").map(str::to_string),
+        },
+        base_url: join_base(&adaptation_base, representation)?,
+        addressing,
+        period_duration,
+        protected: child(representation, "ContentProtection")
+          .or(child(adaptation, "ContentProtection"))
+          .is_some(),
+      });
+    }
+  }
+  Ok(representations)
+}
+
+impl MpdRepresentation {
+  fn period_length(&self, timescale: u64) -> BzResult<u64> {
+    self
+      .period_duration
+      .map(|duration| (duration * timescale as f64).ceil() as u64)
+      .ok_or_else(|| {
+        parse_error(format!("missing period duration for {}", self.info.id))
+      })
+  }
+
+  // 初始化分片和媒体分片 SegmentBase 需要先请求 sidx
+  fn expand(&self) -> BzResult<DashSegments> {
+    let info = &self.info;
+    let resource = |uri: Option<&str>, byte_range| -> BzResult<DashResource> {
+      Ok(DashResource {
+        url: match uri {
+          Some(uri) => http::join_url(&self.base_url, uri)?,
+          None => self.base_url.clone(),
+        },
+        byte_range,
+      })
+    };
+    match &self.addressing {
+      DashAddressing::Template {
+        media,
+        initialization,
+        start_number,
+        timescale,
+        duration,
+        timeline,
+      } => {
+        let init = initialization
+          .as_ref()
+          .map(|init| {
+            resource(
+              Some(&fill_template(init, &info.id, info.bandwidth, 0, 0)),
+              None,
+            )
+          })
+          .transpose()?;
+        // (number, time)
+        let mut items: Vec<(u64, u64)> = Vec::new();
+        match (timeline, duration) {
+          (Some(timeline), _) => {
+            let mut time = 0;
+            for (index, entry) in timeline.iter().enumerate() {
+              time = entry.time.unwrap_or(time);
+              let repeat = match entry.repeat {
+                // 重复到下一个 S 或者 Period 结束
+                repeat if repeat < 0 => {
+                  let end =
+                    match timeline.get(index + 1).and_then(|next| next.time) {
+                      Some(end) => end,
+                      None => self.period_length(*timescale)?,
+                    };
+                  end.saturating_sub(time).div_ceil(entry.duration.max(1))
+                    as i64
+                    - 1
+                }
+                repeat => repeat,
+              };
+              for _ in 0..=repeat {
+                items.push((start_number + items.len() as u64, time));
+                time += entry.duration;
+              }
+            }
+          }
+          (None, Some(duration)) => {
+            let count =
+              self.period_length(*timescale)?.div_ceil((*duration).max(1));
+            items.extend(
+              (0..count).map(|index| (start_number + index, index * duration)),
+            );
+          }
+          (None, None) => {
+            return Err(parse_error(format!(
+              "segment template without duration: {}",
+              info.id
+            )));
+          }
+        }
+        let segments = items
+          .into_iter()
+          .map(|(number, time)| {
+            let uri =
+              fill_template(media, &info.id, info.bandwidth, number, time);
+            Ok((resource(Some(&uri), None)?, time as f64 / *timescale as f64))
+          })
+          .collect::<BzResult<_>>()?;
+        Ok((init, segments))
+      }
+      DashAddressing::List {
+        initialization,
+        segments,
+        timescale,
+        duration,
+      } => {
+        let init = initialization
+          .as_ref()
+          .map(|(uri, byte_range)| resource(uri.as_deref(), byte_range.clone()))
+          .transpose()?;
+        let segments = segments
+          .iter()
+          .enumerate()
+          .map(|(index, (uri, byte_range))| {
+            let time =
+              (index as u64 * duration.unwrap_or(0)) as f64 / *timescale as f64;
+            Ok((resource(uri.as_deref(), byte_range.clone())?, time))
+          })
+          .collect::<BzResult<_>>()?;
+        Ok((init, segments))
+      }
+      DashAddressing::Base { .. } => Ok((None, Vec::new())),
+    }
+  }
+}
+
+// sidx 中每个引用对应一个分片 返回分片的范围和开始时间
+// first_offset 从 sidx 结束的位置开始计算 index 是从 index_offset 开始的内容
+fn parse_sidx(
+  index: &[u8], index_offset: u64,
+) -> BzResult<Vec<(M3u8ByteRange, f64)>> {
+  let invalid = || parse_error("invalid sidx box".to_string());
+  let read = |position: usize, length: usize| -> BzResult<u64> {
+    let bytes = index.get(position..position + length).ok_or_else(invalid)?;
+    Ok(
+      bytes
+        .iter()
+        .fold(0, |value, byte| value << 8 | *byte as u64),
+    )
+  };
+  let mut position = 0;
+  loop {
+    let size = read(position, 4)? as usize;
+    if index.get(position + 4..position + 8) == Some(b"sidx") {
+      break;
+    }
+    if size < 8 {
+      return Err(invalid());
+    }
+    position = position.checked_add(size).ok_or_else(invalid)?;
+  }
+  let sidx_size = read(position, 4)?;
+  let sidx_end = index_offset
+    .checked_add(position as u64)
+    .and_then(|end| end.checked_add(sidx_size))
+    .ok_or_else(invalid)?;
+  let version = read(position + 8, 1)?;
+  let timescale = read(position + 16, 4)?.max(1);
+  let field = if version == 0 { 4 } else { 8 };
+  let mut cursor = position + 20;
+  let earliest = read(cursor, field)?;
+  let first_offset = read(cursor + field, field)?;
+  cursor += 2 * field + 2;
+  let count = read(cursor, 2)?;
+  cursor += 2;
+
+  let mut offset = sidx_end.checked_add(first_offset).ok_or_else(invalid)?;
+  let mut time = earliest;
+  let mut segments = Vec::new();
+  for _ in 0..count {
+    let reference = read(cursor, 4)?;
+    if reference >> 31 == 1 {
+      return Err(parse_error(
+        "hierarchical sidx is not supported".to_string(),
+      ));
+    }
+    let length = reference & 0x7FFF_FFFF;
+    segments.push((
+      M3u8ByteRange { length, offset },
+      time as f64 / timescale as f64,
+    ));
+    offset = offset.checked_add(length).ok_or_else(invalid)?;
+    time = time.checked_add(read(cursor + 4, 4)?).ok_or_else(invalid)?;
+    cursor += 12;
+  }
+  Ok(segments)
+}
+
+// 指定了 id 时使用指定的 Representation 否则视频按照码流策略选择 音频选择码率最高的
+pub fn select_representations<'a>(
+  representations: &'a [MpdRepresentation], selection: &BzDashSelection,
+  policy: &BzVariantPolicy,
+) -> BzResult<Vec<&'a MpdRepresentation>> {
+  let select = |kind: DashTrackKind,
+                id: &Option<String>|
+   -> BzResult<Option<&'a MpdRepresentation>> {
+    let mut candidates = representations
+      .iter()
+      .filter(|representation| representation.info.kind == kind);
+    if let Some(id) = id {
+      return candidates
+        .find(|representation| &representation.info.id == id)
+        .map(Some)
+        .ok_or_else(|| {
+          parse_error(format!("representation not found: {}", id))
+        });
+    }
+    let candidates: Vec<_> = candidates.collect();
+    let highest = candidates
+      .iter()
+      .max_by_key(|representation| representation.info.bandwidth)
+      .copied();
+    let selected = match (kind, policy) {
+      (DashTrackKind::Audio, _) | (_, BzVariantPolicy::HighestBandwidth) => {
+        highest
+      }
+      (_, BzVariantPolicy::LowestBandwidth) => candidates
+        .iter()
+        .min_by_key(|representation| representation.info.bandwidth)
+        .copied(),
+      (_, BzVariantPolicy::Resolution { width, height }) => candidates
+        .iter()
+        .min_by_key(|representation| {
+          representation.info.width.unwrap_or(0).abs_diff(*width)
+            + representation.info.height.unwrap_or(0).abs_diff(*height)
+        })
+        .copied(),
+      (_, BzVariantPolicy::Codec(codec)) => candidates
+        .iter()
+        .filter(|representation| {
+          representation
+            .info
+            .codecs
+            .as_deref()
+            .is_some_and(|codecs| codecs.contains(codec))
+        })
+        .max_by_key(|representation| representation.info.bandwidth)
+        .copied()
+        .or(highest),
+    };
+    Ok(selected)
+  };
+  let selected: Vec<_> = [
+    select(DashTrackKind::Video, &selection.video)?,
+    select(DashTrackKind::Audio, &selection.audio)?,
+  ]
+  .into_iter()
+  .flatten()
+  .collect();
+  if selected.is_empty() {
+    return Err(parse_error(
+      "no audio or video representation found".to_string(),
+    ));
+  }
+  Ok(selected)
+}
+
+// 新建任务时列出可以选择的 Representation
+pub async fn list_representations(
+  url: Url, request: BzRequestConfig,
+) -> Result<Vec<DashRepresentation>, String> {
+  let client = http::build_client(&request).map_err(|err| err.to_string())?;
+  let content =
+    http::get_bytes(&client, url.clone(), &BzRateLimiter::default())
+      .await
+      .map_err(|err| format!("无法获取 MPD: {}", err))?;
+  let representations = parse_mpd(&String::from_utf8_lossy(&content), &url)
+    .map_err(|err| err.to_string())?;
+  Ok(
+    representations
+      .into_iter()
+      .map(|representation| representation.info)
+      .collect(),
+  )
+}
+
+pub struct DashTask {
+  task_info: BzTaskInfo,
+  porgress: M3u8TaskProgress,
+  client: reqwest::Client,
+  limiter: BzRateLimiter,
+  tracks: Vec<DashTrack>,
+  // 已经下载的分片的总大小
+  downloaded_bytes: u64,
+}
+
+impl DashTask {
+  pub fn new(task_info: BzTaskInfo) -> Self {
+    let limiter = BzRateLimiter::new(task_info.speed_limit);
+    Self {
+      porgress: M3u8TaskProgress::new(&task_info.cache),
+      task_info,
+      client: reqwest::Client::new(),
+      limiter,
+      tracks: Vec::new(),
+      downloaded_bytes: 0,
+    }
+  }
+
+  // 按照已经下载的分片的平均大小估计总字节数
+  fn estimate_total(&self) -> Option<u64> {
+    let downloaded = self.porgress.downloaded.len() as u64;
+    (downloaded > 0)
+      .then(|| self.downloaded_bytes * self.porgress.total as u64 / downloaded)
+  }
+
+  // MPD 缓存为 index.mpd 继续下载时分片的编号保持不变
+  async fn get_mpd(&self) -> BzResult<String> {
+    let cache_file = self.task_info.cache.join("index.mpd");
+    if cache_file.exists() {
+      return Ok(std::fs::read_to_string(cache_file)?);
+    }
+    let content = http::retry(&self.task_info.retry, || {
+      http::get_bytes(&self.client, self.task_info.src.clone(), &self.limiter)
+    })
+    .await?;
+    std::fs::write(&cache_file, &content)?;
+    Ok(String::from_utf8_lossy(&content).to_string())
+  }
+
+  async fn resolve_segment_base(
+    &self, representation: &MpdRepresentation,
+  ) -> BzResult<DashSegments> {
+    let DashAddressing::Base {
+      initialization,
+      index_range,
+    } = &representation.addressing
+    else {
+      return representation.expand();
+    };
+    let index_range = index_range.clone().ok_or_else(|| {
+      parse_error(format!(
+        "segment base without index range: {}",
+        representation.info.id
+      ))
+    })?;
+    let url = representation.base_url.clone();
+    let index = http::retry(&self.task_info.retry, || {
+      http::get_range(
+        &self.client,
+        url.clone(),
+        index_range.offset,
+        index_range.length,
+        &self.limiter,
+      )
+    })
+    .await?;
+    let segments = parse_sidx(&index, index_range.offset)?
+      .into_iter()
+      .map(|(byte_range, time)| {
+        (
+          DashResource {
+            url: url.clone(),
+            byte_range: Some(byte_range),
+          },
+          time,
+        )
+      })
+      .collect();
+    // 没有 Initialization 时 sidx 之前的内容就是初始化分片
+    let init = match initialization {
+      Some(init) => init.clone(),
+      None if index_range.offset > 0 => M3u8ByteRange {
+        length: index_range.offset,
+        offset: 0,
+      },
+      None => {
+        return Err(BzError::Parse {
+          reason: "SegmentBase without Initialization before indexRange"
+            .to_string(),
+        });
+      }
+    };
+    let init = DashResource {
+      url: url.clone(),
+      byte_range: Some(init),
+    };
+    Ok((Some(init), segments))
+  }
+
+  // 所有轨道的分片共用一个进度
+  async fn download_segments(
+    &mut self, controller: &mut BzTaskController,
+  ) -> BzResult<()> {
+    let segments = self
+      .tracks
+      .iter()
+      .flat_map(|track| {
+        track
+          .segments
+          .iter()
+          .map(|segment| (segment.sequence, segment.clone()))
+      })
+      .collect::<HashMap<u64, DashSegment>>();
+    let concurrency = self.task_info.concurrency.clamp(1, MAX_CONCURRENCY);
+    let mut downloading = JoinSet::new();
+    let mut stopping = false;
+    let mut failed: Option<BzError> = None;
+    let mut meter = BzSpeedMeter::new();
+    // 分片很大时也定时汇报速度
+    let mut ticker = tokio::time::interval(REPORT_INTERVAL);
+    let gate = controller.gate();
+    loop {
+      while !stopping
+        && !controller.is_paused()
+        && failed.is_none()
+        && downloading.len() < concurrency
+      {
+        let Some(sequence) = self.porgress.todos.pop_back() else {
+          break;
+        };
+        let segment = &segments[&sequence];
+        let file_path = self.task_info.cache.join(segment.file_name());
+        let resource = segment.resource.clone();
+        let client = self.client.clone();
+        let limiter = self.limiter.clone();
+        let retry_policy = self.task_info.retry.clone();
+        downloading.spawn(gate.clone().hold(async move {
+          download_segment(
+            client,
+            limiter,
+            resource.url,
+            resource.byte_range,
+            file_path,
+            None,
+            retry_policy,
+          )
+          .await
+          .map(|bytes| (sequence, bytes))
+        }));
+      }
+      if downloading.is_empty() {
+        if let Some(err) = failed {
+          return Err(err);
+        }
+        if stopping {
+          return Err(BzError::Cancelled);
+        }
+        if controller.is_paused() && !self.porgress.todos.is_empty() {
+          // 暂停中 等待继续或者停止
+          controller.wait_resumed().await;
+          stopping = controller.is_stopping();
+          continue;
+        }
+        return Ok(());
+      }
+
+      tokio::select! {
+        _ = controller.recv(), if !stopping => {
+          stopping = controller.is_stopping();
+          // 被打断的分片没有写入进度 下次重新下载
+          if stopping {
+            downloading.abort_all();
+          }
+        }
+        Some(res) = downloading.join_next() => match res {
+          Ok(Ok((sequence, bytes))) => {
+            self.porgress.update(M3u8TaskProgressMessage::Add(sequence));
+            self.downloaded_bytes += bytes;
+            meter.add(bytes);
+          }
+          Ok(Err(err)) => {
+            downloading.abort_all();
+            failed.get_or_insert(err);
+          }
+          Err(err) if err.is_cancelled() => {}
+          Err(err) => std::panic::resume_unwind(err.into_panic()),
+        },
+        _ = ticker.tick() => {
+          meter.add(0);
+          let feedback = meter.feedback(
+            controller.task_id(),
+            self.porgress.rate(),
+            self.downloaded_bytes,
+            self.estimate_total(),
+            controller.is_paused(),
+          );
+          controller.send_info(feedback).await;
+        }
+      }
+    }
+  }
+}
+
+impl Task for DashTask {
+  fn new_task(task_info: BzTaskInfo) -> Self {
+    Self::new(task_info)
+  }
+
+  fn take_notices(&mut self) -> Vec<String> {
+    std::mem::take(&mut self.porgress.notices)
+  }
+
+  async fn prepare(&mut self) -> BzResult<()> {
+    std::fs::create_dir_all(&self.task_info.cache)?;
+    self.client = http::build_client(&self.task_info.request_config())?;
+    let content = self.get_mpd().await?;
+    let representations = parse_mpd(&content, &self.task_info.src)?;
+    let selected = select_representations(
+      &representations,
+      &self.task_info.dash,
+      &self.task_info.variant,
+    )?;
+
+    let mut sequence = 0;
+    let mut tracks = Vec::new();
+    for representation in selected {
+      if representation.protected {
+        return Err(parse_error(format!(
+          "representation {} is drm protected",
+          representation.info.id
+        )));
+      }
+      log::info!(
+        "select {:?} representation: {}",
+        representation.info.kind,
+        representation.info
+      );
+      let (init, segments) = self.resolve_segment_base(representation).await?;
+      let init = init.ok_or_else(|| {
+        parse_error(format!(
+          "missing initialization: {}",
+          representation.info.id
+        ))
+      })?;
+      let segments = segments
+        .into_iter()
+        .map(|(resource, time)| {
+          sequence += 1;
+          DashSegment {
+            sequence,
+            resource,
+            time,
+          }
+        })
+        .collect();
+      let track = DashTrack {
+        kind: representation.info.kind,
+        init,
+        segments,
+      };
+      let init_file = self.task_info.cache.join(track.init_file_name());
+      if !init_file.exists() {
+        download_segment(
+          self.client.clone(),
+          self.limiter.clone(),
+          track.init.url.clone(),
+          track.init.byte_range.clone(),
+          init_file,
+          None,
+          self.task_info.retry.clone(),
+        )
+        .await?;
+      }
+      tracks.push(track);
+    }
+
+    let sequences = tracks
+      .iter()
+      .flat_map(|track| track.segments.iter().map(|segment| segment.sequence))
+      .collect::<Vec<u64>>();
+    self.porgress.load();
+    self.porgress.init_tasks(&sequences);
+    self.downloaded_bytes = tracks
+      .iter()
+      .flat_map(|track| track.segments.iter())
+      .filter(|segment| self.porgress.downloaded.contains(&segment.sequence))
+      .map(|segment| {
+        let file_path = self.task_info.cache.join(segment.file_name());
+        std::fs::metadata(file_path).map_or(0, |m| m.len())
+      })
+      .sum();
+    self.tracks = tracks;
+    Ok(())
+  }
+
+  async fn start(&mut self, controller: &mut BzTaskController) -> BzResult<()> {
+    let res = self.download_segments(controller).await;
+    self.porgress.dump();
+    res
+  }
+
+  // 各轨道都是 fMP4 合并为一个文件
+  async fn finish(&mut self) -> BzResult<()> {
+    let cache = &self.task_info.cache;
+    let tracks: Vec<FragmentedTrack> = self
+      .tracks
+      .iter()
+      .map(|track| FragmentedTrack {
+        init: cache.join(track.init_file_name()),
+        segments: track
+          .segments
+          .iter()
+          .map(|segment| FragmentedSegment {
+            time: segment.time,
+            path: cache.join(segment.file_name()),
+            offset: segment
+              .resource
+              .byte_range
+              .as_ref()
+              .map_or(0, |range| range.offset),
+          })
+          .collect(),
+      })
+      .collect();
+    let dest: PathBuf = self.task_info.dest.clone();
+    tokio::task::spawn_blocking(move || remux::mux_fragmented(&tracks, &dest))
+      .await
+      .map_err(std::io::Error::other)?
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT10S">
+  <BaseURL>media/</BaseURL>
+  <Period>
+    <AdaptationSet contentType="video" mimeType="video/mp4">
+      <SegmentTemplate timescale="1000" initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/$Number%03d$.m4s" duration="4000"/>
+      <Representation id="v720" bandwidth="3000000" width="1280" height="720" codecs="avc1.64001f"/>
+      <Representation id="v1080" bandwidth="6000000" width="1920" height="1080" codecs="avc1.640028"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4">
+      <Representation id="a128" bandwidth="128000" codecs="mp4a.40.2">
+        <SegmentTemplate timescale="48000" initialization="a/init.mp4" media="a/$Time$.m4s">
+          <SegmentTimeline>
+            <S t="0" d="192000" r="1"/>
+            <S d="96000"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet contentType="text" mimeType="application/ttml+xml"/>
+  </Period>
+</MPD>"#;
+
+  #[test]
+  fn test_parse_mpd() {
+    let url = Url::parse("https://example.com/dash/manifest.mpd").unwrap();
+    let representations = parse_mpd(MPD, &url).unwrap();
+    assert_eq!(representations.len(), 3);
+
+    let selection = BzDashSelection::default();
+    let selected = select_representations(
+      &representations,
+      &selection,
+      &BzVariantPolicy::Resolution {
+        width: 1280,
+        height: 720,
+      },
+    )
+    .unwrap();
+    assert_eq!(selected[0].info.id, "v720");
+    assert_eq!(selected[1].info.id, "a128");
+
+    let (init, segments) = selected[0].expand().unwrap();
+    assert_eq!(
+      init.unwrap().url.as_str(),
+      "https://example.com/dash/media/v720/init.mp4"
+    );
+    let urls: Vec<_> = segments
+      .iter()
+      .map(|(resource, _)| resource.url.path())
+      .collect();
+    assert_eq!(
+      urls,
+      [
+        "/dash/media/v720/001.m4s",
+        "/dash/media/v720/002.m4s",
+        "/dash/media/v720/003.m4s"
+      ]
+    );
+
+    let (_, segments) = selected[1].expand().unwrap();
+    let times: Vec<_> = segments
+      .iter()
+      .map(|(resource, time)| (resource.url.path(), *time))
+      .collect();
+    assert_eq!(
+      times,
+      [
+        ("/dash/media/a/0.m4s", 0.0),
+        ("/dash/media/a/192000.m4s", 4.0),
+        ("/dash/media/a/384000.m4s", 8.0)
+      ]
+    );
+  }
+
+  #[test]
+  fn test_parse_sidx() {
+    let mut sidx = vec![0, 0, 0, 44];
+    sidx.extend_from_slice(b"sidx");
+    sidx.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+    sidx.extend_from_slice(&1000u32.to_be_bytes());
+    sidx.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    for (size, duration) in [(500u32, 2000u32), (300, 1500)] {
+      sidx.extend_from_slice(&size.to_be_bytes());
+      sidx.extend_from_slice(&duration.to_be_bytes());
+      sidx.extend_from_slice(&[0x90, 0, 0, 0]);
+    }
+    let segments = parse_sidx(&sidx, 800).unwrap();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(
+      segments[0],
+      (
+        M3u8ByteRange {
+          length: 500,
+          offset: 844
+        },
+        0.0
+      )
+    );
+    assert_eq!(
+      segments[1],
+      (
+        M3u8ByteRange {
+          length: 300,
+          offset: 1344
+        },
+        2.0
+      )
+    );
+    // version 1 的 first_offset 溢出
+    let mut sidx = vec![0, 0, 0, 52];
+    sidx.extend_from_slice(b"sidx");
+    sidx.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 1]);
+    sidx.extend_from_slice(&1000u32.to_be_bytes());
+    sidx.extend_from_slice(&0u64.to_be_bytes());
+    sidx.extend_from_slice(&u64::MAX.to_be_bytes());
+    sidx.extend_from_slice(&[0, 0, 0, 1]);
+    sidx.extend_from_slice(&[0, 0, 1, 0, 0, 0, 0, 1, 0x90, 0, 0, 0]);
+    assert!(parse_sidx(&sidx, 800).is_err());
+    assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+  }
+}
@@ -1,6 +1,5 @@
 use crate::bz_task::{BzTaskControl, BzTaskId, BzTaskMessage, BzTaskStatus};
 
-
 #[derive(Debug, thiserror::Error)]
 pub enum BzError {
   #[error("Init Error: {reason}")]
@@ -10,10 +9,32 @@ pub enum BzError {
   #[error(" Runtime NotFound task_id: {0}")]
   RuntimeNotFound(BzTaskId),
   #[error("Send BzTaskControl Error: {0}")]
-  MpscBzTaskControlError(#[from] tokio::sync::mpsc::error::TrySendError<BzTaskControl>),
+  MpscBzTaskControlError(
+    #[from] tokio::sync::mpsc::error::TrySendError<BzTaskControl>,
+  ),
   #[error("Task Status Error! current_status: {0} current_action: {1}")]
   TaskStatusError(BzTaskStatus, BzTaskMessage),
+  #[error("Network Error: {0}")]
+  Network(#[from] reqwest::Error),
+  #[error("Http Status Error: {0}")]
+  HttpStatus(reqwest::StatusCode),
+  #[error("IO Error: {0}")]
+  Io(#[from] std::io::Error),
+  #[error("Decrypt Error: {reason}")]
+  Decrypt { reason: String },
+  #[error("Parse Error: {reason}")]
+  Parse { reason: String },
+  #[error("Remux Error: {reason}")]
+  Remux { reason: String },
+  // 下载的内容不完整 或者不是预期的格式
+  #[error("Corrupted: {reason}")]
+  Corrupted { reason: String },
+  // 继续下载时远程文件已经改变
+  #[error("Remote File Changed: {url}")]
+  RemoteChanged { url: String },
+  // 用户主动停止任务
+  #[error("Task Cancelled")]
+  Cancelled,
 }
 
-
-pub type BzResult<T> = std::result::Result<T, BzError>;
\ No newline at end of file
+pub type BzResult<T> = std::result::Result<T, BzError>;
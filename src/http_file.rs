@@ -0,0 +1,557 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use reqwest::Url;
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::bz_task::{
+  BzSpeedMeter, BzTaskController, BzTaskInfo, DUMP_INTERVAL, MAX_CONCURRENCY,
+  Task, TaskProgress,
+};
+use crate::error::{BzError, BzResult};
+use crate::http::{self, BzRetryPolicy};
+use crate::rate_limit::{self, BzRateLimiter};
+
+// 每个连接至少下载 1MB 小文件不再拆分
+const MIN_CHUNK_SIZE: u64 = 1 << 20;
+
+// 远程文件的信息 继续下载时用来判断文件是否已经改变
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HttpRemoteFile {
+  pub size: Option<u64>,
+  pub accept_ranges: bool,
+  pub etag: Option<String>,
+  pub last_modified: Option<String>,
+}
+
+impl HttpRemoteFile {
+  // If-Range 只能使用强 ETag 没有时使用 Last-Modified
+  fn validator(&self) -> Option<String> {
+    self
+      .etag
+      .clone()
+      .filter(|etag| !etag.starts_with("W/"))
+      .or(self.last_modified.clone())
+  }
+}
+
+// [start, end) 范围内的一段 end 为 None 表示文件大小未知 读到结束为止
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HttpChunk {
+  pub start: u64,
+  pub end: Option<u64>,
+  pub downloaded: u64,
+}
+
+impl HttpChunk {
+  fn is_done(&self) -> bool {
+    self
+      .end
+      .is_some_and(|end| self.start + self.downloaded >= end)
+  }
+}
+
+// 按照连接数拆分 服务器不支持 Range 时只能使用一个连接
+pub fn split_chunks(
+  size: Option<u64>, accept_ranges: bool, connections: usize,
+) -> Vec<HttpChunk> {
+  let Some(size) = size.filter(|_| accept_ranges) else {
+    return vec![HttpChunk {
+      start: 0,
+      end: size,
+      downloaded: 0,
+    }];
+  };
+  let count = size
+    .div_ceil(MIN_CHUNK_SIZE)
+    .clamp(1, connections.max(1) as u64);
+  let chunk_size = size.div_ceil(count);
+  (0..count)
+    .map(|index| HttpChunk {
+      start: index * chunk_size,
+      end: Some(((index + 1) * chunk_size).min(size)),
+      downloaded: 0,
+    })
+    .collect()
+}
+
+// "bytes 0-0/1234" 中的文件大小 未知时为 *
+fn parse_content_range(value: &str) -> Option<u64> {
+  value
+    .trim()
+    .strip_prefix("bytes ")?
+    .split_once('/')?
+    .1
+    .parse()
+    .ok()
+}
+
+// 请求第一个字节 根据返回的状态判断是否支持 Range
+async fn probe(client: &reqwest::Client, url: Url) -> BzResult<HttpRemoteFile> {
+  let response = client.get(url).header(RANGE, "bytes=0-0").send().await?;
+  let status = response.status();
+  if !status.is_success() {
+    return Err(BzError::HttpStatus(status));
+  }
+  let header = |name| {
+    response
+      .headers()
+      .get(name)
+      .and_then(|value| value.to_str().ok())
+      .map(str::to_string)
+  };
+  let accept_ranges = status == reqwest::StatusCode::PARTIAL_CONTENT;
+  let size = match accept_ranges {
+    true => header(CONTENT_RANGE)
+      .as_deref()
+      .and_then(parse_content_range),
+    false => response.content_length(),
+  };
+  Ok(HttpRemoteFile {
+    size,
+    accept_ranges,
+    etag: header(ETAG),
+    last_modified: header(LAST_MODIFIED),
+  })
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HttpProgressFile {
+  remote: Option<HttpRemoteFile>,
+  chunks: Vec<HttpChunk>,
+}
+
+pub struct HttpTaskProgress {
+  pub save_file: PathBuf,
+  pub remote: Option<HttpRemoteFile>,
+  pub chunks: Vec<HttpChunk>,
+  // 读取进度时需要提示用户的情况
+  pub notices: Vec<String>,
+}
+
+pub enum HttpTaskProgressMessage {
+  // 第 index 段已经下载的字节数
+  Downloaded(usize, u64),
+  // 大小未知的段读到了结尾
+  Finished(usize),
+}
+
+impl HttpTaskProgress {
+  pub fn new<P: AsRef<Path>>(temp_dir: P) -> Self {
+    Self {
+      save_file: temp_dir.as_ref().join("process.json"),
+      remote: None,
+      chunks: Vec::new(),
+      notices: Vec::new(),
+    }
+  }
+
+  fn downloaded(&self) -> u64 {
+    self.chunks.iter().map(|chunk| chunk.downloaded).sum()
+  }
+}
+
+impl TaskProgress for HttpTaskProgress {
+  type Message = HttpTaskProgressMessage;
+
+  fn load(&mut self) {
+    let loaded =
+      crate::persist::load_json_or_backup::<HttpProgressFile>(&self.save_file);
+    match loaded {
+      Ok(Some((progress, from_backup))) => {
+        if from_backup {
+          self.notices.push("进度文件损坏 已从备份恢复".to_string());
+        }
+        self.remote = progress.remote;
+        self.chunks = progress.chunks;
+      }
+      Ok(None) => log::warn!("no progress file found"),
+      Err(err) => {
+        log::error!("failed to load progress file: {}", err);
+        self
+          .notices
+          .push(format!("进度文件无法读取 重新下载: {}", err));
+      }
+    }
+  }
+
+  fn dump(&self) {
+    let progress = HttpProgressFile {
+      remote: self.remote.clone(),
+      chunks: self.chunks.clone(),
+    };
+    if let Err(err) = crate::persist::save_json(&self.save_file, &progress) {
+      log::error!("failed to save progress file: {}", err);
+    }
+  }
+
+  // 字节数变化频繁 由调用方定时 dump
+  fn _update(&mut self, message: Self::Message) {
+    match message {
+      HttpTaskProgressMessage::Downloaded(index, downloaded) => {
+        if let Some(chunk) = self.chunks.get_mut(index) {
+          chunk.downloaded = downloaded;
+        }
+      }
+      HttpTaskProgressMessage::Finished(index) => {
+        if let Some(chunk) = self.chunks.get_mut(index) {
+          chunk.end.get_or_insert(chunk.start + chunk.downloaded);
+        }
+      }
+    }
+  }
+
+  fn rate(&self) -> f32 {
+    match self.remote.as_ref().and_then(|remote| remote.size) {
+      Some(0) => 1.0,
+      Some(size) => self.downloaded() as f32 / size as f32,
+      None => 0.0,
+    }
+  }
+}
+
+// 读到一半断开的连接同样可以重试
+fn is_retryable(policy: &BzRetryPolicy, err: &BzError) -> bool {
+  match err {
+    BzError::Io(err) => err.kind() == std::io::ErrorKind::UnexpectedEof,
+    err => policy.is_retryable(err),
+  }
+}
+
+// 各个连接共用的下载参数
+#[derive(Clone)]
+struct ChunkDownloader {
+  client: reqwest::Client,
+  // 所有连接共用任务的限速
+  limiter: BzRateLimiter,
+  url: Url,
+  remote: HttpRemoteFile,
+  part: PathBuf,
+  retry_policy: BzRetryPolicy,
+}
+
+impl ChunkDownloader {
+  // 一次请求 从 chunk 已经下载的位置继续写入
+  // 返回时 chunk.downloaded 为实际写入的字节数
+  async fn fetch(
+    &self, file: &mut fs::File, index: usize, chunk: &mut HttpChunk,
+    sender: &mpsc::Sender<(usize, u64)>,
+  ) -> BzResult<()> {
+    let mut request = self.client.get(self.url.clone());
+    if self.remote.accept_ranges {
+      let first = chunk.start + chunk.downloaded;
+      let range = match chunk.end {
+        Some(end) => format!("bytes={}-{}", first, end - 1),
+        None => format!("bytes={}-", first),
+      };
+      request = request.header(RANGE, range);
+      if let Some(validator) = self.remote.validator() {
+        request = request.header(IF_RANGE, validator);
+      }
+    }
+    let mut response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+      return Err(BzError::HttpStatus(status));
+    }
+    // If-Range 不匹配时服务器返回整个文件
+    if self.remote.accept_ranges
+      && status != reqwest::StatusCode::PARTIAL_CONTENT
+    {
+      return Err(BzError::RemoteChanged {
+        url: self.url.to_string(),
+      });
+    }
+    file
+      .seek(SeekFrom::Start(chunk.start + chunk.downloaded))
+      .await?;
+    while let Some(bytes) = response.chunk().await? {
+      self.limiter.acquire(bytes.len()).await;
+      let remaining = chunk
+        .end
+        .map(|end| end - chunk.start - chunk.downloaded)
+        .unwrap_or(u64::MAX);
+      let bytes = &bytes[..bytes.len().min(remaining as usize)];
+      file.write_all(bytes).await?;
+      file.flush().await?;
+      chunk.downloaded += bytes.len() as u64;
+      let _ = sender.send((index, chunk.downloaded)).await;
+      if chunk.is_done() {
+        return Ok(());
+      }
+    }
+    match chunk.end {
+      Some(_) => Err(BzError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+      None => Ok(()),
+    }
+  }
+
+  // 下载一段 网络错误按照重试策略从断开的位置继续
+  // 不支持 Range 的服务器只能从头开始
+  async fn download(
+    self, index: usize, mut chunk: HttpChunk,
+    sender: mpsc::Sender<(usize, u64)>,
+  ) -> BzResult<usize> {
+    // 连接数达到全局上限时等待其他连接结束
+    let _permit = rate_limit::connections().acquire().await;
+    let mut file = fs::OpenOptions::new().write(true).open(&self.part).await?;
+    let retry_policy = &self.retry_policy;
+    let mut attempt = 1;
+    loop {
+      if !self.remote.accept_ranges && chunk.downloaded > 0 {
+        chunk.downloaded = 0;
+        let _ = sender.send((index, 0)).await;
+      }
+      let before = chunk.downloaded;
+      let res = self.fetch(&mut file, index, &mut chunk, &sender).await;
+      // 有进展时重新计算重试次数
+      if chunk.downloaded > before {
+        attempt = 1;
+      }
+      match res {
+        Ok(()) => return Ok(index),
+        Err(err)
+          if attempt < retry_policy.max_attempts
+            && is_retryable(retry_policy, &err) =>
+        {
+          let delay = retry_policy.delay(attempt);
+          log::warn!(
+            "chunk {} attempt {}/{} failed: {}, retry after {:?}",
+            index,
+            attempt,
+            retry_policy.max_attempts,
+            err,
+            delay
+          );
+          tokio::time::sleep(delay).await;
+          attempt += 1;
+        }
+        Err(err) => return Err(err),
+      }
+    }
+  }
+}
+
+// 普通文件 多个连接分段下载到同一个临时文件中
+pub struct HttpTask {
+  task_info: BzTaskInfo,
+  porgress: HttpTaskProgress,
+  client: reqwest::Client,
+}
+
+impl HttpTask {
+  pub fn new(task_info: BzTaskInfo) -> Self {
+    Self {
+      porgress: HttpTaskProgress::new(&task_info.cache),
+      task_info,
+      client: reqwest::Client::new(),
+    }
+  }
+
+  fn part_path(&self) -> PathBuf {
+    self.task_info.cache.join("download.part")
+  }
+
+  // 先把写入的内容落盘再保存进度 断电后进度不会包含还没写入的部分
+  async fn sync_dump(&self, part: &fs::File) {
+    match part.sync_data().await {
+      Ok(()) => self.porgress.dump(),
+      Err(err) => log::error!("failed to sync part file: {}", err),
+    }
+  }
+
+  // 把通道中剩余的进度都更新完
+  fn drain(&mut self, receiver: &mut mpsc::Receiver<(usize, u64)>) {
+    while let Ok((index, downloaded)) = receiver.try_recv() {
+      self
+        .porgress
+        ._update(HttpTaskProgressMessage::Downloaded(index, downloaded));
+    }
+  }
+}
+
+impl Task for HttpTask {
+  fn new_task(task_info: BzTaskInfo) -> Self {
+    Self::new(task_info)
+  }
+
+  fn take_notices(&mut self) -> Vec<String> {
+    std::mem::take(&mut self.porgress.notices)
+  }
+
+  async fn prepare(&mut self) -> BzResult<()> {
+    std::fs::create_dir_all(&self.task_info.cache)?;
+    self.client = http::build_client(&self.task_info.request_config())?;
+    let remote = http::retry(&self.task_info.retry, || {
+      probe(&self.client, self.task_info.src.clone())
+    })
+    .await?;
+    log::info!("remote file: {:?}", remote);
+
+    self.porgress.load();
+    let part = self.part_path();
+    // 远程文件改变后 之前下载的内容不能再使用
+    let resumable = self.porgress.remote.as_ref() == Some(&remote)
+      && !self.porgress.chunks.is_empty()
+      && part.exists();
+    if !resumable {
+      if self.porgress.remote.is_some() {
+        log::warn!("remote file changed, download from the beginning");
+      }
+      let concurrency = self.task_info.concurrency.clamp(1, MAX_CONCURRENCY);
+      self.porgress.chunks =
+        split_chunks(remote.size, remote.accept_ranges, concurrency);
+      let file = std::fs::File::create(&part)?;
+      if let Some(size) = remote.size {
+        file.set_len(size)?;
+      }
+    }
+    self.porgress.remote = Some(remote);
+    self.porgress.dump();
+    Ok(())
+  }
+
+  async fn start(&mut self, controller: &mut BzTaskController) -> BzResult<()> {
+    let remote = self.porgress.remote.clone().unwrap_or(HttpRemoteFile {
+      size: None,
+      accept_ranges: false,
+      etag: None,
+      last_modified: None,
+    });
+    let downloader = ChunkDownloader {
+      client: self.client.clone(),
+      limiter: BzRateLimiter::new(self.task_info.speed_limit),
+      url: self.task_info.src.clone(),
+      remote,
+      part: self.part_path(),
+      retry_policy: self.task_info.retry.clone(),
+    };
+    // 只用来 sync 写入由各个连接自己打开
+    let part = fs::OpenOptions::new()
+      .write(true)
+      .open(self.part_path())
+      .await?;
+    let (sender, mut receiver) = mpsc::channel(64);
+    let mut downloading = JoinSet::new();
+    // 暂停时连接保持打开 不再读取内容
+    let gate = controller.gate();
+    for (index, chunk) in self.porgress.chunks.iter().enumerate() {
+      if chunk.is_done() {
+        continue;
+      }
+      downloading.spawn(gate.clone().hold(downloader.clone().download(
+        index,
+        chunk.clone(),
+        sender.clone(),
+      )));
+    }
+    drop(sender);
+
+    let mut interval = tokio::time::interval(DUMP_INTERVAL);
+    let mut meter = BzSpeedMeter::new();
+    let mut last_downloaded = self.porgress.downloaded();
+    let res = loop {
+      if downloading.is_empty() {
+        break Ok(());
+      }
+      tokio::select! {
+        _ = controller.recv() => {
+          if controller.is_stopping() {
+            break Err(BzError::Cancelled);
+          }
+        }
+        Some((index, downloaded)) = receiver.recv() => {
+          self.porgress._update(HttpTaskProgressMessage::Downloaded(index, downloaded));
+        }
+        Some(res) = downloading.join_next() => match res {
+          Ok(Ok(index)) => {
+            self.drain(&mut receiver);
+            self.porgress._update(HttpTaskProgressMessage::Finished(index));
+          }
+          Ok(Err(err)) => break Err(err),
+          Err(err) if err.is_cancelled() => {}
+          Err(err) => std::panic::resume_unwind(err.into_panic()),
+        },
+        _ = interval.tick() => {
+          self.sync_dump(&part).await;
+          let downloaded = self.porgress.downloaded();
+          meter.add(downloaded.saturating_sub(last_downloaded));
+          last_downloaded = downloaded;
+          let total = self.porgress.remote.as_ref().and_then(|r| r.size);
+          let feedback = meter.feedback(
+            controller.task_id(),
+            self.porgress.rate(),
+            downloaded,
+            total,
+            controller.is_paused(),
+          );
+          controller.send_info(feedback).await;
+        }
+      }
+    };
+    // 已经写入的内容都记录下来 下次从这里继续
+    downloading.abort_all();
+    while downloading.join_next().await.is_some() {}
+    self.drain(&mut receiver);
+    self.sync_dump(&part).await;
+    res
+  }
+
+  async fn finish(&mut self) -> BzResult<()> {
+    if !self.porgress.chunks.iter().all(HttpChunk::is_done) {
+      return Err(BzError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    let part = self.part_path();
+    let size = self
+      .porgress
+      .chunks
+      .iter()
+      .filter_map(|chunk| chunk.end)
+      .max();
+    fs::OpenOptions::new()
+      .write(true)
+      .open(&part)
+      .await?
+      .set_len(size.unwrap_or(0))
+      .await?;
+    // 缓存目录和保存目录不在同一个分区时 rename 会失败
+    if fs::rename(&part, &self.task_info.dest).await.is_err() {
+      fs::copy(&part, &self.task_info.dest).await?;
+      fs::remove_file(&part).await?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_split_chunks() {
+    let chunks = split_chunks(Some(5 * MIN_CHUNK_SIZE + 1), true, 4);
+    assert_eq!(chunks.len(), 4);
+    assert_eq!(chunks[0].start, 0);
+    assert_eq!(chunks[3].end, Some(5 * MIN_CHUNK_SIZE + 1));
+    for pair in chunks.windows(2) {
+      assert_eq!(pair[0].end, Some(pair[1].start));
+    }
+    // 小文件和不支持 Range 时只使用一个连接
+    assert_eq!(split_chunks(Some(100), true, 4).len(), 1);
+    assert_eq!(
+      split_chunks(Some(5 * MIN_CHUNK_SIZE), false, 4),
+      vec![HttpChunk {
+        start: 0,
+        end: Some(5 * MIN_CHUNK_SIZE),
+        downloaded: 0
+      }]
+    );
+    assert_eq!(parse_content_range("bytes 0-0/1234"), Some(1234));
+    assert_eq!(parse_content_range("bytes 0-0/*"), None);
+  }
+}
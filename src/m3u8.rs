@@ -1,20 +1,45 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::{Path, PathBuf};
-use std::collections::HashSet;
+use std::time::{Duration, Instant};
 
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
+use tokio::task::JoinSet;
+
+use aes::cipher::{BlockDecryptMut, KeyIvInit, block_padding::Pkcs7};
+use m3u8_rs::{
+  AlternativeMediaType, KeyMethod, MasterPlaylist, MediaPlaylist, Playlist,
+  VariantStream,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 
 use crate::bz_task::{
-  BzTaskControl, BzTaskControlFeedBack, BzTaskControlFeedBackMessage,
-  BzTaskFeedBack, BzTaskId, BzTaskInfo, BzTaskInfoFeedBackMessage,
+  BzContainer, BzRenditionSelection, BzSpeedMeter, BzStopReason,
+  BzTaskControlFeedBack, BzTaskController, BzTaskInfo, BzVariantPolicy,
+  DUMP_INTERVAL, MAX_CONCURRENCY, REPORT_INTERVAL,
 };
 use crate::bz_task::{Task, TaskProgress};
+use crate::error::{BzError, BzResult};
+use crate::http::{self, BzRequestConfig, BzRetryPolicy};
+use crate::persist;
+use crate::rate_limit::{self, BzRateLimiter};
+use crate::remux::{
+  self, FragmentedSegment, FragmentedTrack, TS_PACKET_SIZE, TsInput,
+};
 
 pub struct M3u8TaskProgress {
   pub save_file: PathBuf,
-  pub downloaded: HashSet<String>,
-  pub todos: Vec<String>,
+  // 以 media sequence 作为分片的标识 多个分片可能是同一个文件的不同范围
+  pub downloaded: HashSet<u64>,
+  pub todos: VecDeque<u64>,
   pub total: usize,
+  // 读取进度时需要提示用户的情况
+  pub notices: Vec<String>,
+  last_dump: Instant,
+  // 旧版本以 uri 为标识的进度 解析播放列表之后再转换
+  legacy: HashSet<String>,
 }
 
 impl M3u8TaskProgress {
@@ -22,51 +47,126 @@ impl M3u8TaskProgress {
     Self {
       save_file: temp_dir.as_ref().join("process.json"),
       downloaded: HashSet::new(),
-      todos: Vec::new(),
+      todos: VecDeque::new(),
       total: 0,
+      notices: Vec::new(),
+      last_dump: Instant::now(),
+      legacy: HashSet::new(),
     }
   }
 
-  pub fn init_tasks(&mut self, uris: &Vec<String>) {
-    self.total = uris.len() as usize;
-    for uri in uris {
-      if !self.downloaded.contains(uri) {
-        self.todos.push(uri.clone());
+  // todos 从尾部取出 倒序保存使分片按照顺序下载
+  pub fn init_tasks(&mut self, sequences: &Vec<u64>) {
+    self.total = sequences.len() as usize;
+    for sequence in sequences.iter().rev() {
+      if !self.downloaded.contains(sequence) {
+        self.todos.push_back(*sequence);
       }
     }
   }
+
+  // 直播录制中新出现的分片 排在已有的分片之后下载
+  pub fn append_tasks(&mut self, sequences: &[u64]) {
+    self.total += sequences.len();
+    for sequence in sequences {
+      if !self.downloaded.contains(sequence) {
+        self.todos.push_front(*sequence);
+      }
+    }
+  }
+  // 旧版本的分片以 uri 为文件名保存在缓存目录中 改为新的文件名后记录为已下载
+  // 旧版本没有解密 加密的分片 同一个文件的不同范围 以及内容损坏的分片重新下载
+  pub fn migrate_legacy(&mut self, cache: &Path, segments: &[M3u8Segment]) {
+    if self.legacy.is_empty() {
+      return;
+    }
+    let mut migrated = 0;
+    for segment in segments {
+      if segment.key.is_some()
+        || segment.byte_range.is_some()
+        || !self.legacy.contains(&segment.uri)
+      {
+        continue;
+      }
+      // 只处理缓存目录中的相对路径
+      let relative = Path::new(&segment.uri)
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+      if !relative {
+        continue;
+      }
+      let old_path = cache.join(&segment.uri);
+      let valid = std::fs::read(&old_path)
+        .is_ok_and(|content| check_segment(&content).is_ok());
+      if valid
+        && std::fs::rename(&old_path, cache.join(segment.file_name())).is_ok()
+      {
+        self.downloaded.insert(segment.sequence);
+        migrated += 1;
+      }
+    }
+    log::info!(
+      "migrate legacy progress: {}/{} segments",
+      migrated,
+      self.legacy.len()
+    );
+    let notice = match self.legacy.len() - migrated {
+      0 => format!("已转换旧版本的进度 {} 个分片", migrated),
+      skipped => format!(
+        "已转换旧版本的进度 {} 个分片 {} 个分片需要重新下载",
+        migrated, skipped
+      ),
+    };
+    self.notices.push(notice);
+    self.legacy.clear();
+    self.dump();
+  }
 }
 
 pub enum M3u8TaskProgressMessage {
-  Add(String),
-  Remove(String),
+  Add(u64),
+  Remove(u64),
 }
 
 impl TaskProgress for M3u8TaskProgress {
   type Message = M3u8TaskProgressMessage;
   fn load(&mut self) {
-    let file = std::fs::File::open(&self.save_file);
-    match file {
-      Ok(f) => {
-        let reader = std::io::BufReader::new(f);
-        self.downloaded = serde_json::from_reader(reader).unwrap();
-      }
-      Err(_) => {
-        log::warn!("no progress file found");
+    // 进度文件损坏时重新下载所有分片
+    // 旧版本以 uri 为标识的进度文件先保存下来 由 migrate_legacy 转换
+    match crate::persist::load_json_or_backup(&self.save_file) {
+      Ok(Some((downloaded, from_backup))) => {
+        if from_backup {
+          self.notices.push("进度文件损坏 已从备份恢复".to_string());
+        }
+        self.downloaded = downloaded;
       }
+      Ok(None) => log::warn!("no progress file found"),
+      Err(err) => match crate::persist::load_json(&self.save_file) {
+        Ok(Some(uris)) => self.legacy = uris,
+        _ => {
+          log::error!("failed to load progress file: {}", err);
+          self
+            .notices
+            .push(format!("进度文件无法读取 重新下载: {}", err));
+        }
+      },
     }
   }
 
   fn dump(&self) {
-    let file = std::fs::File::create(&self.save_file);
-    match file {
-      Ok(f) => {
-        let writer = std::io::BufWriter::new(f);
-        serde_json::to_writer(writer, &self.downloaded).unwrap();
-      }
-      Err(_) => {
-        log::error!("failed to create progress file");
-      }
+    if let Err(err) =
+      crate::persist::save_json(&self.save_file, &self.downloaded)
+    {
+      log::error!("failed to save progress file: {}", err);
+    }
+  }
+
+  // 每个分片完成都会更新 按照 DUMP_INTERVAL 保存 下载结束时由调用方再保存一次
+  fn update(&mut self, message: Self::Message) {
+    self._update(message);
+    if self.last_dump.elapsed() >= DUMP_INTERVAL {
+      self.dump();
+      self.last_dump = Instant::now();
     }
   }
 
@@ -86,145 +186,1987 @@ impl TaskProgress for M3u8TaskProgress {
   }
 }
 
+// 按照策略从 master playlist 中选择一个码流
+// I-FRAME 码流不参与选择 没有符合条件的码流时返回 None
+pub fn select_variant<'a>(
+  variants: &'a [VariantStream], policy: &BzVariantPolicy,
+) -> Option<&'a VariantStream> {
+  let candidates = variants.iter().filter(|variant| !variant.is_i_frame);
+  match policy {
+    BzVariantPolicy::HighestBandwidth => {
+      candidates.max_by_key(|variant| variant.bandwidth)
+    }
+    BzVariantPolicy::LowestBandwidth => {
+      candidates.min_by_key(|variant| variant.bandwidth)
+    }
+    BzVariantPolicy::Resolution { width, height } => {
+      let target = width * height;
+      candidates
+        .filter(|variant| variant.resolution.is_some())
+        .min_by_key(|variant| {
+          let resolution = variant.resolution.unwrap();
+          let pixels = resolution.width * resolution.height;
+          // 面积相同的时候选择码率高的
+          (pixels.abs_diff(target), Reverse(variant.bandwidth))
+        })
+    }
+    BzVariantPolicy::Codec(codec) => candidates
+      .filter(|variant| {
+        variant
+          .codecs
+          .as_ref()
+          .is_some_and(|codecs| codecs.contains(codec.as_str()))
+      })
+      .max_by_key(|variant| variant.bandwidth),
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum M3u8RenditionKind {
+  Audio,
+  Subtitles,
+}
+
+impl M3u8RenditionKind {
+  fn name(&self) -> &'static str {
+    match self {
+      M3u8RenditionKind::Audio => "audio",
+      M3u8RenditionKind::Subtitles => "subtitles",
+    }
+  }
+}
+
+// EXT-X-MEDIA 中有单独 playlist 的音轨和字幕
+#[derive(Debug, Clone, PartialEq)]
+pub struct M3u8Rendition {
+  pub kind: M3u8RenditionKind,
+  pub group_id: String,
+  pub name: String,
+  pub language: Option<String>,
+  pub default: bool,
+  pub uri: String,
+}
+
+impl M3u8Rendition {
+  // 保存为单独文件时的后缀 例如 movie.en.vtt
+  fn label(&self) -> String {
+    self
+      .language
+      .as_deref()
+      .unwrap_or(&self.name)
+      .chars()
+      .map(|c| match c.is_alphanumeric() || c == '-' {
+        true => c,
+        false => '_',
+      })
+      .collect()
+  }
+
+  // dest 旁边的文件 1.mp4 -> 1.en.vtt
+  fn sidecar_path(&self, dest: &Path, extension: &str) -> PathBuf {
+    let stem = dest.file_stem().unwrap_or_default().to_string_lossy();
+    dest.with_file_name(format!("{}.{}.{}", stem, self.label(), extension))
+  }
+}
+
+impl std::fmt::Display for M3u8Rendition {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.language {
+      Some(language) => write!(f, "{} ({})", self.name, language),
+      None => write!(f, "{}", self.name),
+    }
+  }
+}
+
+// 没有 URI 的音轨包含在码流中 不需要单独下载
+pub fn parse_renditions(master: &MasterPlaylist) -> Vec<M3u8Rendition> {
+  master
+    .alternatives
+    .iter()
+    .filter_map(|media| {
+      let kind = match media.media_type {
+        AlternativeMediaType::Audio => M3u8RenditionKind::Audio,
+        AlternativeMediaType::Subtitles => M3u8RenditionKind::Subtitles,
+        _ => return None,
+      };
+      Some(M3u8Rendition {
+        kind,
+        group_id: media.group_id.clone(),
+        name: media.name.clone(),
+        language: media.language.clone(),
+        default: media.default,
+        uri: media.uri.clone()?,
+      })
+    })
+    .collect()
+}
+
+// 在码流引用的分组中按照 NAME 选择 没有指定时音轨使用分组中默认的
+pub fn select_renditions<'a>(
+  renditions: &'a [M3u8Rendition], variant: &VariantStream,
+  selection: &BzRenditionSelection,
+) -> Vec<&'a M3u8Rendition> {
+  let select = |kind: M3u8RenditionKind, name: &Option<String>| {
+    let group = match kind {
+      M3u8RenditionKind::Audio => variant.audio.as_ref(),
+      M3u8RenditionKind::Subtitles => variant.subtitles.as_ref(),
+    };
+    let mut candidates = renditions.iter().filter(move |rendition| {
+      rendition.kind == kind
+        && group.is_none_or(|group| &rendition.group_id == group)
+    });
+    match (name, group) {
+      (Some(name), _) => {
+        let selected = candidates.find(|rendition| &rendition.name == name);
+        if selected.is_none() {
+          log::warn!("no {} rendition named {}", kind.name(), name);
+        }
+        selected
+      }
+      (None, Some(_)) if kind == M3u8RenditionKind::Audio => {
+        let candidates: Vec<_> = candidates.collect();
+        candidates
+          .iter()
+          .find(|rendition| rendition.default)
+          .or(candidates.first())
+          .copied()
+      }
+      (None, _) => None,
+    }
+  };
+  [
+    select(M3u8RenditionKind::Audio, &selection.audio),
+    select(M3u8RenditionKind::Subtitles, &selection.subtitles),
+  ]
+  .into_iter()
+  .flatten()
+  .collect()
+}
+
+// 新建任务时列出 master playlist 中可以选择的音轨和字幕
+pub async fn list_renditions(
+  url: Url, request: BzRequestConfig,
+) -> Result<Vec<M3u8Rendition>, String> {
+  let client = http::build_client(&request).map_err(|err| err.to_string())?;
+  let content =
+    http::get_bytes(&client, url.clone(), &BzRateLimiter::default())
+      .await
+      .map_err(|err| format!("无法获取 m3u8: {}", err))?;
+  match m3u8_rs::parse_playlist_res(&content) {
+    Ok(Playlist::MasterPlaylist(master)) => {
+      let mut renditions = parse_renditions(&master);
+      // 不同分组中同名的只保留一个
+      let mut names = HashSet::new();
+      renditions.retain(|rendition| {
+        names.insert((rendition.kind.name(), rendition.name.clone()))
+      });
+      Ok(renditions)
+    }
+    Ok(Playlist::MediaPlaylist(_)) => Ok(Vec::new()),
+    Err(err) => Err(format!("无法解析 m3u8: {:?}", err)),
+  }
+}
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+// EXT-X-KEY:METHOD=AES-128
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct M3u8Key {
+  #[serde(
+    serialize_with = "http::serialize_url",
+    deserialize_with = "http::deserialize_url"
+  )]
+  pub uri: Url,
+  pub iv: Option<[u8; 16]>,
+}
+
+impl M3u8Key {
+  // 没有显式的IV时 使用 media sequence number 作为IV
+  pub fn iv(&self, sequence: u64) -> [u8; 16] {
+    self.iv.unwrap_or_else(|| (sequence as u128).to_be_bytes())
+  }
+}
+
+// BYTERANGE="<length>@<offset>"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct M3u8ByteRange {
+  pub length: u64,
+  pub offset: u64,
+}
+
+// EXT-X-MAP fMP4 的初始化分片
+// 加密时使用 EXT-X-MAP 之前最近的 EXT-X-KEY
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct M3u8Map {
+  #[serde(
+    serialize_with = "http::serialize_url",
+    deserialize_with = "http::deserialize_url"
+  )]
+  pub uri: Url,
+  pub byte_range: Option<M3u8ByteRange>,
+  pub key: Option<M3u8Key>,
+  // 第一个使用这个初始化分片的 media sequence 没有显式的IV时作为IV
+  pub sequence: u64,
+}
+
+impl M3u8Map {
+  // 缓存目录中的文件名 不同的 uri 和 range 对应不同的文件
+  pub fn file_name(&self) -> String {
+    let mut name =
+      format!("{}{}", self.uri.path(), self.uri.query().unwrap_or(""));
+    if let Some(range) = &self.byte_range {
+      name = format!("{}@{}-{}", name, range.offset, range.length);
+    }
+    let name = name
+      .chars()
+      .map(
+        |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+          true => c,
+          false => '_',
+        },
+      )
+      .collect::<String>();
+    format!("map{}", name)
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct M3u8Segment {
+  pub uri: String,
+  pub sequence: u64,
+  // EXTINF 中的时长 单位秒
+  pub duration: f32,
+  pub key: Option<M3u8Key>,
+  // 只有 fMP4 分片才有初始化分片
+  #[serde(default)]
+  pub map: Option<M3u8Map>,
+  // EXT-X-BYTERANGE 分片是 uri 对应文件中的一段
+  #[serde(default)]
+  pub byte_range: Option<M3u8ByteRange>,
+  // EXT-X-DISCONTINUITY 转换为 mp4 时需要重新计算时间戳
+  #[serde(default)]
+  pub discontinuity: bool,
+}
+
+impl M3u8Segment {
+  // 缓存目录中的文件名 uri 可能重复 使用 media sequence
+  pub fn file_name(&self) -> String {
+    format!("{}.seg", self.sequence)
+  }
+}
+
+// IV 格式为 0x 开头的16进制字符串
+fn parse_iv(iv: &str) -> Option<[u8; 16]> {
+  let hex = iv.strip_prefix("0x").or(iv.strip_prefix("0X"))?;
+  let value = u128::from_str_radix(hex, 16).ok()?;
+  Some(value.to_be_bytes())
+}
+
+// 解析 media playlist 中的分片
+// EXT-X-KEY 只出现在开始使用它的分片上 对后续的分片一直有效 直到下一个 EXT-X-KEY
+// EXT-X-MAP 同理 直到下一个 EXT-X-MAP
+pub fn parse_segments(
+  playlist: &MediaPlaylist, base_url: &Url,
+) -> BzResult<Vec<M3u8Segment>> {
+  let mut key: Option<M3u8Key> = None;
+  let mut map: Option<M3u8Map> = None;
+  // 上一个分片的 uri 和结束位置 BYTERANGE 没有 offset 时紧接着上一个分片
+  let mut range_end: Option<(&str, u64)> = None;
+  playlist
+    .segments
+    .iter()
+    .enumerate()
+    .map(|(index, segment)| {
+      // m3u8-rs 会把不带IV的 METHOD=NONE 当成未知标签
+      let key_none = segment.unknown_tags.iter().any(|tag| {
+        tag.tag == "X-KEY"
+          && tag
+            .rest
+            .as_deref()
+            .is_some_and(|rest| rest.contains("METHOD=NONE"))
+      });
+      if key_none {
+        key = None;
+      }
+      if let Some(segment_key) = &segment.key {
+        key = match (&segment_key.method, &segment_key.uri) {
+          (KeyMethod::AES128, Some(uri)) => Some(M3u8Key {
+            uri: http::join_url(base_url, uri)?,
+            iv: segment_key.iv.as_deref().and_then(parse_iv),
+          }),
+          (KeyMethod::None, _) => None,
+          (method, _) => {
+            log::warn!("unsupported key method: {:?}", method);
+            None
+          }
+        };
+      }
+      let sequence = playlist.media_sequence + index as u64;
+      if let Some(segment_map) = &segment.map {
+        map = Some(M3u8Map {
+          uri: http::join_url(base_url, &segment_map.uri)?,
+          byte_range: segment_map.byte_range.as_ref().map(|range| {
+            M3u8ByteRange {
+              length: range.length,
+              offset: range.offset.unwrap_or(0),
+            }
+          }),
+          key: key.clone(),
+          sequence,
+        });
+      }
+      let byte_range = match &segment.byte_range {
+        Some(range) => {
+          let offset = match (range.offset, range_end) {
+            (Some(offset), _) => offset,
+            (None, Some((uri, end))) if uri == segment.uri => end,
+            (None, _) => {
+              return Err(BzError::Parse {
+                reason: format!("missing byte range offset: {}", segment.uri),
+              });
+            }
+          };
+          range_end = Some((&segment.uri, offset + range.length));
+          Some(M3u8ByteRange {
+            length: range.length,
+            offset,
+          })
+        }
+        None => {
+          range_end = None;
+          None
+        }
+      };
+      Ok(M3u8Segment {
+        uri: segment.uri.clone(),
+        sequence,
+        duration: segment.duration,
+        key: key.clone(),
+        map: map.clone(),
+        byte_range,
+        discontinuity: segment.discontinuity,
+      })
+    })
+    .collect()
+}
+
+// 1.mp4 -> 1.part2.mp4
+fn part_path(dest: &Path, part: usize) -> PathBuf {
+  let stem = dest.file_stem().unwrap_or_default().to_string_lossy();
+  let file_name = match dest.extension() {
+    Some(ext) => format!("{}.part{}.{}", stem, part, ext.to_string_lossy()),
+    None => format!("{}.part{}", stem, part),
+  };
+  dest.with_file_name(file_name)
+}
+
+// 长度或者填充不正确说明下载的内容不完整 按照损坏的分片重新下载
+pub fn decrypt_segment(
+  content: &[u8], key: &[u8; 16], iv: &[u8; 16],
+) -> BzResult<Vec<u8>> {
+  Aes128CbcDec::new(key.into(), iv.into())
+    .decrypt_padded_vec_mut::<Pkcs7>(content)
+    .map_err(|err| BzError::Corrupted {
+      reason: format!("decrypt failed: {}", err),
+    })
+}
+
+// 错误页面被当成分片保存时 内容是 HTML
+fn is_html(content: &[u8]) -> bool {
+  let start = content
+    .iter()
+    .position(|byte| !byte.is_ascii_whitespace())
+    .unwrap_or(content.len());
+  let head = &content[start..content.len().min(start + 64)];
+  let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+  head.starts_with("<!doctype html") || head.starts_with("<html")
+}
+
+// 每个 box 以 4 字节的大小和 4 字节的类型开始 所有 box 正好占满整个分片
+fn check_boxes(content: &[u8]) -> Result<(), String> {
+  let mut offset = 0;
+  while offset < content.len() {
+    let header = content
+      .get(offset..offset + 8)
+      .ok_or_else(|| format!("truncated box header at {}", offset))?;
+    let kind = &header[4..8];
+    if !kind
+      .iter()
+      .all(|byte| byte.is_ascii_graphic() || *byte == b' ')
+    {
+      return Err(format!("invalid box type at {}", offset));
+    }
+    // largesize 的 box 头部有 16 字节
+    let (size, header_size) =
+      match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+        // 大小为 0 表示一直到文件结束
+        0 => (content.len() - offset, 8),
+        1 => {
+          let large = content
+            .get(offset + 8..offset + 16)
+            .ok_or_else(|| format!("truncated box header at {}", offset))?;
+          let large = u64::from_be_bytes(large.try_into().unwrap());
+          (usize::try_from(large).unwrap_or(usize::MAX), 16)
+        }
+        size => (size as usize, 8),
+      };
+    let end = offset
+      .checked_add(size)
+      .filter(|end| size >= header_size && *end <= content.len())
+      .ok_or_else(|| {
+        format!(
+          "box {} at {} with size {} out of {} bytes",
+          String::from_utf8_lossy(kind),
+          offset,
+          size,
+          content.len()
+        )
+      })?;
+    offset = end;
+  }
+  Ok(())
+}
+
+// fMP4 分片和初始化分片开头的 box
+const FMP4_LEADING_BOXES: [&[u8; 4]; 7] = [
+  b"ftyp", b"styp", b"moov", b"moof", b"sidx", b"emsg", b"prft",
+];
+
+// 解密之前就可以检查的内容 服务器返回的错误页面不会加密
+fn check_body(content: &[u8]) -> Result<(), String> {
+  if content.is_empty() {
+    return Err("empty segment".to_string());
+  }
+  if is_html(content) {
+    return Err("got HTML page instead of media".to_string());
+  }
+  Ok(())
+}
+
+// 下载之后检查分片 返回损坏的原因
+// 以 0x47 开始的按照 TS 检查 每 188 字节一个同步字节
+// 以 fMP4 box 开始的检查 box 结构
+// 音频和字幕等其他格式只检查是否为空和 HTML
+pub fn check_segment(content: &[u8]) -> Result<(), String> {
+  check_body(content)?;
+  if content.get(4..8).is_some_and(|kind| {
+    FMP4_LEADING_BOXES.iter().any(|box_type| kind == *box_type)
+  }) {
+    return check_boxes(content);
+  }
+  if content[0] == 0x47 {
+    if !content.len().is_multiple_of(TS_PACKET_SIZE) {
+      return Err(format!(
+        "TS size {} is not a multiple of {}",
+        content.len(),
+        TS_PACKET_SIZE
+      ));
+    }
+    if let Some(index) = content
+      .chunks(TS_PACKET_SIZE)
+      .position(|packet| packet[0] != 0x47)
+    {
+      return Err(format!("missing TS sync byte in packet {}", index));
+    }
+  }
+  Ok(())
+}
+
+// 有 BYTERANGE 时只请求对应的范围
+async fn get_content(
+  client: &reqwest::Client, limiter: &BzRateLimiter, url: Url,
+  byte_range: Option<&M3u8ByteRange>,
+) -> BzResult<Vec<u8>> {
+  match byte_range {
+    Some(range) => {
+      http::get_range(client, url, range.offset, range.length, limiter).await
+    }
+    None => http::get_bytes(client, url, limiter).await,
+  }
+}
+
+// 下载单个分片 如果分片加密则解密后再写入文件 返回写入的字节数
+// 网络错误按照重试策略重试 内容损坏时返回 Corrupted 由调用者决定是否重新下载
+pub async fn download_segment(
+  client: reqwest::Client, limiter: BzRateLimiter, url: Url,
+  byte_range: Option<M3u8ByteRange>, file_path: PathBuf,
+  key: Option<([u8; 16], [u8; 16])>, retry_policy: BzRetryPolicy,
+) -> BzResult<u64> {
+  // 分片数达到全局上限时等待其他任务的分片下载完成
+  let _permit = rate_limit::connections().acquire().await;
+  let mut content = http::retry(&retry_policy, || {
+    get_content(&client, &limiter, url.clone(), byte_range.as_ref())
+  })
+  .await?;
+  if let Some((key, iv)) = key {
+    check_body(&content).map_err(|reason| BzError::Corrupted { reason })?;
+    content = decrypt_segment(&content, &key, &iv)?;
+  }
+  check_segment(&content).map_err(|reason| BzError::Corrupted { reason })?;
+  // 先写临时文件 停止时被打断的分片不会留下写了一半的文件
+  let temp_path = file_path.with_extension("tmp");
+  let mut file = fs::File::create(&temp_path).await?;
+  file.write_all(&content).await?;
+  file.flush().await?;
+  drop(file);
+  fs::rename(&temp_path, &file_path).await?;
+  Ok(content.len() as u64)
+}
+
+// 下载进度的统计 有音轨和字幕时把各个轨道的数据相加
+#[derive(Debug, Clone, Copy, Default)]
+struct M3u8Stats {
+  downloaded: usize,
+  total: usize,
+  bytes: u64,
+  // 已经下载和总共的时长 用于估计总字节数
+  downloaded_duration: f64,
+  total_duration: f64,
+}
+
+impl std::ops::Add for M3u8Stats {
+  type Output = M3u8Stats;
+
+  fn add(self, other: M3u8Stats) -> M3u8Stats {
+    M3u8Stats {
+      downloaded: self.downloaded + other.downloaded,
+      total: self.total + other.total,
+      bytes: self.bytes + other.bytes,
+      downloaded_duration: self.downloaded_duration + other.downloaded_duration,
+      total_duration: self.total_duration + other.total_duration,
+    }
+  }
+}
+
+impl M3u8Stats {
+  fn rate(&self) -> f32 {
+    self.downloaded as f32 / self.total.max(1) as f32
+  }
+
+  // 按照已经下载的分片的码率估计总字节数
+  fn estimate_total(&self) -> Option<u64> {
+    (self.downloaded_duration > 0.0).then(|| {
+      (self.bytes as f64 * self.total_duration / self.downloaded_duration)
+        as u64
+    })
+  }
+}
+
+// 直播录制的状态
+struct M3u8Recording {
+  // 重新获取 media playlist 的间隔 即 EXT-X-TARGETDURATION
+  interval: Duration,
+  // 下一个需要加入下载列表的 media sequence
+  next_sequence: u64,
+  // 已经录制的时长和大小 用于判断是否达到上限
+  duration: f64,
+  bytes: u64,
+}
+
 pub struct M3u8Task {
   task_info: BzTaskInfo,
   porgress: M3u8TaskProgress,
-  uris: Vec<String>,
+  segments: Vec<M3u8Segment>,
+  // 已经下载过的密钥 同一个密钥在多个分片之间共用
+  keys: HashMap<Url, [u8; 16]>,
+  client: reqwest::Client,
+  // 子任务和主任务共用同一个限速
+  limiter: BzRateLimiter,
+  // media playlist 的地址 ts文件的相对路径基于这个地址
+  base_url: Url,
+  // 没有 EXT-X-ENDLIST 时进入直播录制
+  recording: Option<M3u8Recording>,
+  // 选择的音轨和字幕 作为子任务使用同样的方式下载
+  renditions: Vec<(M3u8Rendition, M3u8Task)>,
+  // 已经下载的分片的总大小
+  downloaded_bytes: u64,
 }
 
 impl M3u8Task {
   pub fn new(task_info: BzTaskInfo) -> Self {
+    let limiter = BzRateLimiter::new(task_info.speed_limit);
     Self {
       porgress: M3u8TaskProgress::new(&task_info.cache),
+      base_url: task_info.src.clone(),
       task_info: task_info,
-      uris: Vec::new(),
+      segments: Vec::new(),
+      keys: HashMap::new(),
+      client: reqwest::Client::new(),
+      limiter,
+      recording: None,
+      renditions: Vec::new(),
+      downloaded_bytes: 0,
+    }
+  }
+
+  // 直播录制时已经加入下载列表的分片
+  fn record_file(&self) -> PathBuf {
+    self.task_info.cache.join("record.json")
+  }
+
+  // 如果本地有缓存文件则返回缓存文件
+  // 否则下载并且缓存
+  async fn get_cached(&self, url: &Url, file_name: &str) -> BzResult<Vec<u8>> {
+    let cache_file = PathBuf::from(&self.task_info.cache).join(file_name);
+    if cache_file.exists() {
+      let content = std::fs::read(cache_file)?;
+      return Ok(content);
+    } else {
+      let content = http::retry(&self.task_info.retry, || {
+        http::get_bytes(&self.client, url.clone(), &self.limiter)
+      })
+      .await?;
+      std::fs::write(&cache_file, &content)?;
+      return Ok(content);
     }
   }
 
   // 获取索引文件
-  // 如果本地有索引文件则返回本地索引文件
-  // 否则下载并且返回
-  async fn get_m3u8_index(&self) -> Vec<u8> {
+  // src 是 master playlist 的时候缓存为 master.m3u8
+  // 按照策略选择码流后 把对应的 media playlist 缓存为 index.m3u8
+  async fn get_m3u8_index(&mut self) -> BzResult<Vec<u8>> {
+    let master_file = PathBuf::from(&self.task_info.cache).join("master.m3u8");
     let index_file = PathBuf::from(&self.task_info.cache).join("index.m3u8");
-    if index_file.exists() {
-      let content = std::fs::read(index_file).unwrap();
-      return content;
-    } else {
-      let content = reqwest::get(self.task_info.src.clone())
-        .await
-        .unwrap()
-        .bytes()
-        .await
-        .unwrap();
-      std::fs::write(&index_file, &content).unwrap();
-      return content.into();
+    if index_file.exists() && !master_file.exists() {
+      // src 本身就是 media playlist
+      return Ok(std::fs::read(index_file)?);
+    }
+    let src = self.task_info.src.clone();
+    let content = self.get_cached(&src, "master.m3u8").await?;
+    let playlist =
+      m3u8_rs::parse_playlist_res(&content).map_err(|err| BzError::Parse {
+        reason: format!("invalid m3u8 {}: {:?}", src, err),
+      })?;
+    match playlist {
+      Playlist::MasterPlaylist(master) => {
+        let variant = select_variant(&master.variants, &self.task_info.variant)
+          .or_else(|| {
+            log::warn!(
+              "no variant match {:?}, fallback to highest bandwidth",
+              self.task_info.variant
+            );
+            select_variant(&master.variants, &BzVariantPolicy::HighestBandwidth)
+          })
+          .ok_or_else(|| BzError::Parse {
+            reason: format!("no variant found in {}", src),
+          })?;
+        log::info!(
+          "select variant: {} bandwidth: {} resolution: {:?}",
+          variant.uri,
+          variant.bandwidth,
+          variant.resolution
+        );
+        let renditions = parse_renditions(&master);
+        self.renditions =
+          select_renditions(&renditions, variant, &self.task_info.renditions)
+            .into_iter()
+            .map(|rendition| self.rendition_task(&src, rendition))
+            .collect::<BzResult<_>>()?;
+        self.base_url = http::join_url(&src, &variant.uri)?;
+        let base_url = self.base_url.clone();
+        self.get_cached(&base_url, "index.m3u8").await
+      }
+      Playlist::MediaPlaylist(_) => {
+        std::fs::rename(&master_file, &index_file)?;
+        Ok(content)
+      }
     }
   }
 
+  // 音轨和字幕的子任务 使用单独的缓存目录
+  fn rendition_task(
+    &self, master_url: &Url, rendition: &M3u8Rendition,
+  ) -> BzResult<(M3u8Rendition, M3u8Task)> {
+    let mut task_info = self.task_info.clone();
+    task_info.src = http::join_url(master_url, &rendition.uri)?;
+    task_info.cache = self.task_info.cache.join(format!(
+      "{}-{}",
+      rendition.kind.name(),
+      rendition.label()
+    ));
+    task_info.renditions = BzRenditionSelection::default();
+    let mut task = M3u8Task::new(task_info);
+    task.limiter = self.limiter.clone();
+    Ok((rendition.clone(), task))
+  }
+
+  fn parse_media_playlist(&self, content: &[u8]) -> BzResult<MediaPlaylist> {
+    m3u8_rs::parse_media_playlist_res(content).map_err(|err| BzError::Parse {
+      reason: format!("invalid media playlist {}: {:?}", self.base_url, err),
+    })
+  }
+
   // 解析索引文件 获取ts文件列表
-  pub async fn get_ts_file_list(&self) -> Vec<String> {
-    let index_content = self.get_m3u8_index().await;
-    let m3u8 = m3u8_rs::parse_media_playlist(&index_content).unwrap().1;
-    let uris = m3u8
-      .segments
+  pub async fn get_ts_file_list(&mut self) -> BzResult<Vec<M3u8Segment>> {
+    let index_content = self.get_m3u8_index().await?;
+    let m3u8 = self.parse_media_playlist(&index_content)?;
+    let mut segments = parse_segments(&m3u8, &self.base_url)?;
+    if !m3u8.end_list {
+      // 继续之前的录制 之前加入下载列表的分片可能已经不在当前的 playlist 中
+      if let Some(recorded) = persist::load_json(&self.record_file())? {
+        segments = recorded;
+      }
+      log::info!("live playlist, start recording: {}", self.base_url);
+      self.recording = Some(M3u8Recording {
+        interval: Duration::from_secs(m3u8.target_duration.max(1)),
+        next_sequence: segments
+          .last()
+          .map_or(m3u8.media_sequence, |segment| segment.sequence + 1),
+        duration: 0.0,
+        bytes: 0,
+      });
+      persist::save_json(&self.record_file(), &segments)?;
+    }
+    Ok(segments)
+  }
+
+  // 重新获取直播的 media playlist 把新出现的分片加入下载列表
+  // 返回是否已经出现 EXT-X-ENDLIST
+  async fn poll_playlist(&mut self) -> BzResult<bool> {
+    let content = http::retry(&self.task_info.retry, || {
+      http::get_bytes(&self.client, self.base_url.clone(), &self.limiter)
+    })
+    .await?;
+    let m3u8 = self.parse_media_playlist(&content)?;
+    self.append_playlist(&m3u8)
+  }
+
+  // 只加入上次之后出现的分片 已经过期的分片无法再下载
+  fn append_playlist(&mut self, m3u8: &MediaPlaylist) -> BzResult<bool> {
+    let Some(recording) = self.recording.as_mut() else {
+      return Ok(true);
+    };
+    if m3u8.media_sequence > recording.next_sequence {
+      log::warn!(
+        "segments {}..{} expired before recording",
+        recording.next_sequence,
+        m3u8.media_sequence
+      );
+    }
+    let segments = parse_segments(m3u8, &self.base_url)?
       .into_iter()
-      .map(|segment| segment.uri.clone())
-      .collect::<Vec<String>>();
-    uris
+      .filter(|segment| segment.sequence >= recording.next_sequence)
+      .collect::<Vec<_>>();
+    if let Some(last) = segments.last() {
+      recording.next_sequence = last.sequence + 1;
+      let sequences = segments
+        .iter()
+        .map(|segment| segment.sequence)
+        .collect::<Vec<_>>();
+      self.porgress.append_tasks(&sequences);
+      self.segments.extend(segments);
+      persist::save_json(&self.record_file(), &self.segments)?;
+    }
+    Ok(m3u8.end_list)
   }
-}
 
-impl Task for M3u8Task {
-  fn new_task(task_info: BzTaskInfo) -> Self {
-    Self::new(task_info)
+  // 录制的时长或者大小达到上限
+  fn record_limit_reached(&self) -> bool {
+    let Some(recording) = &self.recording else {
+      return false;
+    };
+    let limit = &self.task_info.record_limit;
+    limit
+      .max_duration_secs
+      .is_some_and(|max| recording.duration >= max as f64)
+      || limit.max_bytes.is_some_and(|max| recording.bytes >= max)
+  }
+
+  async fn get_key(&mut self, key: &M3u8Key) -> BzResult<[u8; 16]> {
+    if let Some(key) = self.keys.get(&key.uri) {
+      return Ok(*key);
+    }
+    log::debug!("fetch key: {}", key.uri);
+    let content = http::retry(&self.task_info.retry, || {
+      http::get_bytes(&self.client, key.uri.clone(), &self.limiter)
+    })
+    .await?;
+    let key_bytes: [u8; 16] =
+      content
+        .as_slice()
+        .try_into()
+        .map_err(|_| BzError::Decrypt {
+          reason: format!("invalid key length: {}", content.len()),
+        })?;
+    self.keys.insert(key.uri.clone(), key_bytes);
+    Ok(key_bytes)
   }
 
-  async fn prepare(&mut self) {
+  // 下载初始化分片到缓存目录 已经下载过的直接跳过
+  async fn get_map(&mut self, map: &M3u8Map) -> BzResult<()> {
+    let file_path = self.task_info.cache.join(map.file_name());
+    if file_path.exists() {
+      return Ok(());
+    }
+    log::debug!("fetch map: {} {:?}", map.uri, map.byte_range);
+    let mut content = http::retry(&self.task_info.retry, || {
+      get_content(
+        &self.client,
+        &self.limiter,
+        map.uri.clone(),
+        map.byte_range.as_ref(),
+      )
+    })
+    .await?;
+    let corrupted = |reason| BzError::Corrupted {
+      reason: format!("init segment {}: {}", map.uri, reason),
+    };
+    if let Some(key) = &map.key {
+      check_body(&content).map_err(corrupted)?;
+      let key_bytes = self.get_key(key).await?;
+      content = decrypt_segment(&content, &key_bytes, &key.iv(map.sequence))?;
+    }
+    check_segment(&content).map_err(corrupted)?;
+    // 先写临时文件 避免下载一半的文件被当成已经下载
+    let temp_path = file_path.with_extension("tmp");
+    std::fs::write(&temp_path, &content)?;
+    std::fs::rename(&temp_path, &file_path)?;
+    Ok(())
+  }
+
+  // 下载分片前需要准备好初始化分片和密钥
+  async fn prepare_segment(
+    &mut self, segment: &M3u8Segment,
+  ) -> BzResult<(Option<([u8; 16], [u8; 16])>, Url)> {
+    if let Some(map) = &segment.map {
+      self.get_map(map).await?;
+    }
+    let key = match &segment.key {
+      Some(key) => Some((self.get_key(key).await?, key.iv(segment.sequence))),
+      None => None,
+    };
+    Ok((key, http::join_url(&self.base_url, &segment.uri)?))
+  }
+
+  // 检查缓存中已经下载的分片 损坏的分片从进度中移除并删除文件
+  // 内容在下载时已经检查过 这里只检查文件是否存在以及大小
+  // 返回损坏的分片和原因
+  async fn verify_segments(&mut self) -> BzResult<Vec<String>> {
+    let mut corrupted = Vec::new();
+    for segment in &self.segments {
+      if self.skipped(segment) {
+        continue;
+      }
+      let path = self.task_info.cache.join(segment.file_name());
+      let res = match fs::metadata(&path).await {
+        Ok(metadata) if metadata.len() == 0 => Err("empty segment".to_string()),
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+          Err("file not found".to_string())
+        }
+        Err(err) => return Err(err.into()),
+      };
+      if let Err(reason) = res {
+        log::warn!("segment {} corrupted: {}", segment.sequence, reason);
+        corrupted.push(format!("#{} {}", segment.sequence, reason));
+        self
+          .porgress
+          ._update(M3u8TaskProgressMessage::Remove(segment.sequence));
+        let _ = fs::remove_file(&path).await;
+      }
+    }
+    if !corrupted.is_empty() {
+      self.porgress.dump();
+    }
+    Ok(corrupted)
+  }
+
+  // 直播录制停止时 还没有下载的分片直接跳过
+  fn skipped(&self, segment: &M3u8Segment) -> bool {
+    self.recording.is_some()
+      && !self.porgress.downloaded.contains(&segment.sequence)
+  }
+
+  // TS 分片转换为 mp4 跳过的分片上的不连续标记留给下一个分片
+  async fn remux(&self) -> BzResult<()> {
+    let mut inputs = Vec::new();
+    let mut discontinuity = false;
+    for segment in &self.segments {
+      discontinuity |= segment.discontinuity;
+      if self.skipped(segment) {
+        continue;
+      }
+      inputs.push(TsInput {
+        path: self.task_info.cache.join(segment.file_name()),
+        discontinuity: std::mem::take(&mut discontinuity),
+      });
+    }
+    let dest = self.task_info.dest.clone();
+    tokio::task::spawn_blocking(move || remux::remux_ts_to_mp4(&inputs, &dest))
+      .await
+      .map_err(std::io::Error::other)?
+  }
+
+  // fMP4 的初始化分片写在使用它的分片之前
+  // 不连续的地方初始化分片发生变化时 后面的分片写入新的文件 例如 1.part2.mp4
+  // 拆分之后提示用户 播放器只能看到第一个文件的内容
+  async fn concat(&mut self) -> BzResult<()> {
+    let mut target_file = fs::File::create(&self.task_info.dest).await?;
+    let mut map: Option<&M3u8Map> = None;
+    let mut init: Option<Vec<u8>> = None;
+    let mut part = 1;
+    let mut parts = Vec::new();
+    for segment in &self.segments {
+      if self.skipped(segment) {
+        continue;
+      }
+      if let Some(segment_map) = &segment.map
+        && map != Some(segment_map)
+      {
+        map = Some(segment_map);
+        let map_file_path = self.task_info.cache.join(segment_map.file_name());
+        let content = fs::read(map_file_path).await?;
+        if init.as_ref() != Some(&content) {
+          if init.is_some() {
+            part += 1;
+            let part_path = part_path(&self.task_info.dest, part);
+            log::warn!(
+              "init segment changed, write to {}",
+              part_path.display()
+            );
+            target_file.flush().await?;
+            target_file = fs::File::create(&part_path).await?;
+            parts.push(format!(
+              "#{} 之后的内容保存在 {}",
+              segment.sequence,
+              part_path.display()
+            ));
+          }
+          target_file.write_all(&content).await?;
+          init = Some(content);
+        }
+      }
+      let segment_file_path = self.task_info.cache.join(segment.file_name());
+      let content = fs::read(segment_file_path).await?;
+      target_file.write_all(&content).await?;
+    }
+    target_file.flush().await?;
+    if !parts.is_empty() {
+      self.porgress.notices.push(format!(
+        "初始化分片中途改变 输出拆分为 {} 个文件: {}",
+        part,
+        parts.join(", ")
+      ));
+    }
+    Ok(())
+  }
+
+  // 获取分片列表和本地的下载进度
+  async fn prepare_playlist(&mut self) -> BzResult<()> {
     // 下载 m3u8 url
     // 解析 m3u8 获取需要下载哪些ts文件
     // 检查本地已经下载了那些文件
     // 设置后续需要下载的文件
-    let ts_files = self.get_ts_file_list().await;
+    std::fs::create_dir_all(&self.task_info.cache)?;
+    self.client = http::build_client(&self.task_info.request_config())?;
+    let segments = self.get_ts_file_list().await?;
+    let sequences = segments
+      .iter()
+      .map(|segment| segment.sequence)
+      .collect::<Vec<u64>>();
     self.porgress.load();
-    self.porgress.init_tasks(&ts_files);
-    self.uris = ts_files;
+    self
+      .porgress
+      .migrate_legacy(&self.task_info.cache, &segments);
+    self.porgress.init_tasks(&sequences);
+    // 统计之前已经下载的大小 继续录制时同样统计已经录制的时长
+    for segment in &segments {
+      if self.porgress.downloaded.contains(&segment.sequence) {
+        let file_path = self.task_info.cache.join(segment.file_name());
+        let bytes = std::fs::metadata(file_path).map_or(0, |m| m.len());
+        self.downloaded_bytes += bytes;
+        if let Some(recording) = self.recording.as_mut() {
+          recording.duration += segment.duration as f64;
+          recording.bytes += bytes;
+        }
+      }
+    }
+    self.segments = segments;
+    Ok(())
+  }
+
+  // 下载这个 playlist 的分片
+  // others 为其他轨道的统计 用于计算整体的进度
+  async fn download(
+    &mut self, controller: &mut BzTaskController, meter: &mut BzSpeedMeter,
+    others: M3u8Stats,
+  ) -> BzResult<()> {
+    let res = self.download_segments(controller, meter, others).await;
+    self.porgress.dump();
+    res
   }
 
-  async fn start(
-    &mut self, task_id: BzTaskId,
-    mut control_receiver: tokio::sync::mpsc::Receiver<BzTaskControl>,
-    feedback_sender: tokio::sync::mpsc::Sender<BzTaskFeedBack>,
-  ) -> bool {
+  async fn download_segments(
+    &mut self, controller: &mut BzTaskController, meter: &mut BzSpeedMeter,
+    others: M3u8Stats,
+  ) -> BzResult<()> {
     // 下载ts文件
     // 更新下载进度
-    let client = reqwest::Client::new();
-    loop {
-      if self.porgress.todos.is_empty() {
-        return true;
-      }
-      if let Ok(control_message) = control_receiver.try_recv() {
-        match control_message {
-          BzTaskControl::Stop => {
-            let _ = feedback_sender
-              .send(BzTaskFeedBack::TaskConrol(BzTaskControlFeedBackMessage {
-                task_id,
-                control: BzTaskControlFeedBack::Stoped,
-              }))
+    let mut segments = self
+      .segments
+      .iter()
+      .map(|segment| (segment.sequence, segment.clone()))
+      .collect::<HashMap<u64, M3u8Segment>>();
+    let concurrency = self.task_info.concurrency.clamp(1, MAX_CONCURRENCY);
+    let mut downloading = JoinSet::new();
+    // 收到停止消息后不再下载新的分片 并且放弃正在下载的分片
+    // 直播录制停止后合并已经下载的分片 取消和程序退出时不合并
+    // 达到录制上限时等待正在下载的分片完成
+    let mut stopping = false;
+    // 重试用尽后的错误 出现错误后放弃正在下载的分片
+    let mut failed: Option<BzError> = None;
+    // 每个分片内容损坏的次数
+    let mut corrupted: HashMap<u64, u32> = HashMap::new();
+    // 直播录制中 定时获取新的分片
+    let mut polling = self.recording.is_some();
+    let mut next_poll = tokio::time::Instant::now();
+    // 分片很大时也定时汇报速度
+    let mut ticker = tokio::time::interval(REPORT_INTERVAL);
+    let gate = controller.gate();
+    'download: loop {
+      // 控制消息可能在下面任意一个等待的地方收到
+      if controller.is_stopping() && !stopping {
+        stopping = true;
+        polling = false;
+        // 被打断的分片没有写入进度 下次重新下载
+        downloading.abort_all();
+      }
+      // 暂停时不再开始新的分片 正在下载的分片由 gate 挂起
+      while !stopping
+        && !controller.is_paused()
+        && failed.is_none()
+        && downloading.len() < concurrency
+      {
+        let Some(sequence) = self.porgress.todos.pop_back() else {
+          break;
+        };
+        let segment = &segments[&sequence];
+        // 获取密钥和初始化分片时同样响应控制消息
+        let prepared = tokio::select! {
+          prepared = self.prepare_segment(segment) => prepared,
+          _ = controller.recv() => {
+            self.porgress.todos.push_back(sequence);
+            continue 'download;
+          }
+        };
+        let (key, url) = match prepared {
+          Ok(prepared) => prepared,
+          Err(err) => {
+            downloading.abort_all();
+            failed = Some(err);
+            break;
+          }
+        };
+        let file_path = self.task_info.cache.join(segment.file_name());
+        let byte_range = segment.byte_range.clone();
+        let client = self.client.clone();
+        let limiter = self.limiter.clone();
+        let retry_policy = self.task_info.retry.clone();
+        downloading.spawn(gate.clone().hold(async move {
+          let res = download_segment(
+            client,
+            limiter,
+            url,
+            byte_range,
+            file_path,
+            key,
+            retry_policy,
+          )
+          .await;
+          (sequence, res)
+        }));
+      }
+      if downloading.is_empty() && (!polling || failed.is_some()) {
+        if let Some(err) = failed {
+          return Err(err);
+        }
+        // 停止和达到录制上限时合并 程序退出时不合并 避免合并到一半被中止
+        let finalize = !matches!(
+          controller.stop_reason(),
+          Some(BzStopReason::Cancel | BzStopReason::Shutdown)
+        );
+        if stopping && (self.recording.is_none() || !finalize) {
+          return Err(BzError::Cancelled);
+        }
+        // 暂停中 还有分片没有开始下载
+        if stopping || !controller.is_paused() || self.porgress.todos.is_empty()
+        {
+          return Ok(());
+        }
+      }
+
+      tokio::select! {
+        _ = controller.recv(), if !stopping => {}
+        _ = tokio::time::sleep_until(next_poll), if polling => {
+          let known = self.segments.len();
+          let polled = tokio::select! {
+            polled = self.poll_playlist() => polled,
+            _ = controller.recv() => continue 'download,
+          };
+          match polled {
+            Ok(end_list) => {
+              polling = !end_list;
+              segments.extend(
+                self.segments[known..]
+                  .iter()
+                  .map(|segment| (segment.sequence, segment.clone())),
+              );
+            }
+            Err(err) => {
+              downloading.abort_all();
+              polling = false;
+              failed.get_or_insert(err);
+            }
+          }
+          if let Some(recording) = &self.recording {
+            next_poll = tokio::time::Instant::now() + recording.interval;
+          }
+        }
+        Some(res) = downloading.join_next() => match res {
+          Ok((sequence, Ok(bytes))) => {
+            if let Some(recording) = self.recording.as_mut() {
+              recording.duration += segments[&sequence].duration as f64;
+              recording.bytes += bytes;
+            }
+            if self.record_limit_reached() {
+              log::info!(
+                "record limit reached: {:?}",
+                self.task_info.record_limit
+              );
+              stopping = true;
+              polling = false;
+            }
+            self.porgress.update(M3u8TaskProgressMessage::Add(sequence));
+            self.downloaded_bytes += bytes;
+            meter.add(bytes);
+          }
+          // 损坏的分片放回队列重新下载 次数用尽后失败
+          Ok((sequence, Err(BzError::Corrupted { reason }))) => {
+            log::warn!("segment {} corrupted: {}", sequence, reason);
+            let reason = format!("#{} {}", sequence, reason);
+            controller
+              .send_control(BzTaskControlFeedBack::SegmentCorrupted(
+                reason.clone(),
+              ))
               .await;
-            return false;
+            let attempts = corrupted.entry(sequence).or_insert(0);
+            *attempts += 1;
+            if *attempts < self.task_info.retry.max_attempts {
+              self.porgress.todos.push_back(sequence);
+            } else {
+              downloading.abort_all();
+              polling = false;
+              failed.get_or_insert(BzError::Corrupted { reason });
+            }
           }
-          _ => {
-            log::warn!("Unsupported control message: {:?}", control_message);
+          Ok((_, Err(err))) => {
+            downloading.abort_all();
+            polling = false;
+            failed.get_or_insert(err);
           }
+          // abort_all 取消的分片
+          Err(err) if err.is_cancelled() => {}
+          Err(err) => std::panic::resume_unwind(err.into_panic()),
+        },
+        _ = ticker.tick() => {
+          meter.add(0);
+          let stats = self.stats() + others;
+          // 直播录制没有总大小
+          let total = match self.recording {
+            Some(_) => None,
+            None => stats.estimate_total(),
+          };
+          let feedback = meter.feedback(
+            controller.task_id(),
+            stats.rate(),
+            stats.bytes,
+            total,
+            controller.is_paused(),
+          );
+          controller.send_info(feedback).await;
         }
       }
+    }
+  }
 
-      let uri = self.porgress.todos.pop().unwrap();
-      let file_path = self.task_info.cache.clone().join(&uri);
-      let url = self.task_info.src.clone().join(&uri).unwrap();
-      let content =
-        client.get(url).send().await.unwrap().bytes().await.unwrap();
-      // 这里可能有问题  创建了完文件就gg了  文件内容没有写入
-      let mut file = fs::File::create(file_path).await.unwrap();
-      file.write(&content).await.unwrap();
-      self.porgress.update(M3u8TaskProgressMessage::Add(uri));
-      let _ = feedback_sender
-        .send(BzTaskFeedBack::TaskInfo(BzTaskInfoFeedBackMessage {
-          task_id,
-          progress: self.porgress.rate(),
-        }))
-        .await;
+  // fMP4 分片本身就是 mp4 直接拼接
+  async fn save(&mut self) -> BzResult<()> {
+    let container = self.task_info.container.resolve(&self.task_info.dest);
+    let is_ts = self.segments.iter().all(|segment| segment.map.is_none());
+    match (container, is_ts) {
+      (BzContainer::Mp4, true) => self.remux().await,
+      _ => self.concat().await,
     }
   }
 
-  async fn finish(&mut self) {
-    let mut target_file = fs::File::create(&self.task_info.dest).await.unwrap();
-    for uri in &self.uris {
-      let uri_file_path = self.task_info.cache.join(uri);
-      let content = fs::read(uri_file_path).await.unwrap();
-      target_file.write_all(&content).await.unwrap();
+  fn stats(&self) -> M3u8Stats {
+    let mut stats = M3u8Stats {
+      downloaded: self.porgress.downloaded.len(),
+      total: self.porgress.total,
+      bytes: self.downloaded_bytes,
+      ..Default::default()
+    };
+    for segment in &self.segments {
+      stats.total_duration += segment.duration as f64;
+      if self.porgress.downloaded.contains(&segment.sequence) {
+        stats.downloaded_duration += segment.duration as f64;
+      }
+    }
+    stats
+  }
+
+  // 除了正在下载的轨道以外的统计
+  // running 为 None 表示码流本身 Some 表示对应的音轨或者字幕
+  fn others(&self, running: Option<usize>) -> M3u8Stats {
+    let mut stats = match running {
+      Some(_) => self.stats(),
+      None => M3u8Stats::default(),
+    };
+    for (index, (_, task)) in self.renditions.iter().enumerate() {
+      if running != Some(index) {
+        stats = stats + task.stats();
+      }
+    }
+    stats
+  }
+
+  // 所有分片都使用同一个初始化分片
+  fn single_map(&self) -> Option<&M3u8Map> {
+    let map = self.segments.first()?.map.as_ref()?;
+    self
+      .segments
+      .iter()
+      .all(|segment| segment.map.as_ref() == Some(map))
+      .then_some(map)
+  }
+
+  // 分片的开始时间按照 EXTINF 累加
+  fn fragmented_track(&self, map: &M3u8Map) -> FragmentedTrack {
+    let mut time = 0.0;
+    FragmentedTrack {
+      init: self.task_info.cache.join(map.file_name()),
+      segments: self
+        .segments
+        .iter()
+        .map(|segment| {
+          let start = time;
+          time += segment.duration as f64;
+          FragmentedSegment {
+            time: start,
+            path: self.task_info.cache.join(segment.file_name()),
+            offset: segment.byte_range.as_ref().map_or(0, |range| range.offset),
+          }
+        })
+        .collect(),
+    }
+  }
+
+  // 码流和音轨都是 fMP4 时合并为一个 mp4
+  async fn mux_audio(&self, audio: &M3u8Task) -> BzResult<bool> {
+    let (Some(video_map), Some(audio_map)) =
+      (self.single_map(), audio.single_map())
+    else {
+      return Ok(false);
+    };
+    let tracks = vec![
+      self.fragmented_track(video_map),
+      audio.fragmented_track(audio_map),
+    ];
+    let dest = self.task_info.dest.clone();
+    tokio::task::spawn_blocking(move || remux::mux_fragmented(&tracks, &dest))
+      .await
+      .map_err(std::io::Error::other)??;
+    Ok(true)
+  }
+
+  // 没有合并的音轨和字幕保存在 dest 旁边 例如 1.en.m4a 1.en.vtt
+  async fn save_rendition(
+    &mut self, rendition: &M3u8Rendition, dest: &Path,
+  ) -> BzResult<()> {
+    let Some(first) = self.segments.first() else {
+      return Ok(());
+    };
+    let first_path = self.task_info.cache.join(first.file_name());
+    if rendition.kind == M3u8RenditionKind::Subtitles {
+      self.task_info.dest = rendition.sidecar_path(dest, "vtt");
+      let mut contents = Vec::new();
+      for segment in &self.segments {
+        let path = self.task_info.cache.join(segment.file_name());
+        contents
+          .push(String::from_utf8_lossy(&fs::read(path).await?).to_string());
+      }
+      fs::write(&self.task_info.dest, remux::merge_webvtt(&contents)).await?;
+      return Ok(());
+    }
+    // TS 以 0x47 开始 转换为 m4a 其他格式的音频直接拼接
+    let is_ts = fs::read(&first_path).await?.first() == Some(&0x47);
+    let extension = match (is_ts, &first.map) {
+      (true, _) | (false, Some(_)) => "m4a".to_string(),
+      (false, None) => {
+        Path::new(&first.uri.split('?').next().unwrap_or_default())
+          .extension()
+          .map(|ext| ext.to_string_lossy().to_string())
+          .unwrap_or("aac".to_string())
+      }
+    };
+    self.task_info.dest = rendition.sidecar_path(dest, &extension);
+    match is_ts {
+      true => self.remux().await,
+      false => self.concat().await,
     }
   }
 }
 
+impl Task for M3u8Task {
+  fn new_task(task_info: BzTaskInfo) -> Self {
+    Self::new(task_info)
+  }
+
+  fn take_notices(&mut self) -> Vec<String> {
+    let mut notices = std::mem::take(&mut self.porgress.notices);
+    for (_, task) in &mut self.renditions {
+      notices.extend(task.take_notices());
+    }
+    notices
+  }
+
+  async fn prepare(&mut self) -> BzResult<()> {
+    self.prepare_playlist().await?;
+    if self.recording.is_some() && !self.renditions.is_empty() {
+      log::warn!("alternate renditions are not recorded for live playlist");
+      self.renditions.clear();
+    }
+    for (rendition, task) in &mut self.renditions {
+      log::info!("select {} rendition: {}", rendition.kind.name(), rendition);
+      task.prepare_playlist().await?;
+    }
+    Ok(())
+  }
+
+  // 先下载码流的分片 再依次下载音轨和字幕
+  async fn start(&mut self, controller: &mut BzTaskController) -> BzResult<()> {
+    let mut meter = BzSpeedMeter::new();
+    let others = self.others(None);
+    self.download(controller, &mut meter, others).await?;
+    for index in 0..self.renditions.len() {
+      let others = self.others(Some(index));
+      self.renditions[index]
+        .1
+        .download(controller, &mut meter, others)
+        .await?;
+    }
+    Ok(())
+  }
+
+  async fn finish(&mut self) -> BzResult<()> {
+    // 合并前检查所有分片是否还在 缺失的分片下次启动时重新下载
+    let mut corrupted = self.verify_segments().await?;
+    for (_, task) in &mut self.renditions {
+      corrupted.extend(task.verify_segments().await?);
+    }
+    if !corrupted.is_empty() {
+      return Err(BzError::Corrupted {
+        reason: format!(
+          "{} segments corrupted, restart to download again: {}",
+          corrupted.len(),
+          corrupted.join(", ")
+        ),
+      });
+    }
+    let container = self.task_info.container.resolve(&self.task_info.dest);
+    let audio = self
+      .renditions
+      .iter()
+      .position(|(rendition, _)| rendition.kind == M3u8RenditionKind::Audio);
+    let muxed = match (container, audio) {
+      (BzContainer::Mp4, Some(index)) => self
+        .mux_audio(&self.renditions[index].1)
+        .await?
+        .then_some(index),
+      _ => None,
+    };
+    if muxed.is_none() {
+      self.save().await?;
+    }
+    for (index, (rendition, task)) in self.renditions.iter_mut().enumerate() {
+      if muxed != Some(index) {
+        task.save_rendition(rendition, &self.task_info.dest).await?;
+      }
+    }
+    Ok(())
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
-  use crate::bz_task::{BzTaskStatus, BzTaskType};
+  use crate::bz_task::{
+    BzContainer, BzDashSelection, BzRecordLimit, BzRenditionSelection,
+    BzTaskStatus, BzTaskType,
+  };
+  use crate::http::BzTaskProxy;
+  use crate::rate_limit::BzSpeedLimit;
   use tokio;
 
+  fn test_task_info(src: &str, dest: PathBuf, cache: PathBuf) -> BzTaskInfo {
+    BzTaskInfo {
+      src: reqwest::Url::parse(src).unwrap(),
+      dest,
+      cache,
+      kind: BzTaskType::M3u8,
+      status: BzTaskStatus::Queued,
+      variant: BzVariantPolicy::default(),
+      concurrency: 4,
+      retry: BzRetryPolicy::default(),
+      headers: Vec::new(),
+      user_agent: None,
+      cookies: Vec::new(),
+      proxy: BzTaskProxy::default(),
+      speed_limit: BzSpeedLimit::default(),
+      start_at: None,
+      record_limit: BzRecordLimit::default(),
+      container: BzContainer::default(),
+      dash: BzDashSelection::default(),
+      renditions: BzRenditionSelection::default(),
+    }
+  }
+
   #[tokio::test]
   async fn test_m3u8_task() {
     env_logger::init();
-    let task_info = BzTaskInfo {
-      src: reqwest::Url::parse("https://svipsvip.ffzy-online5.com/20250118/37333_517b17a8/2000k/hls/mixed.m3u8").unwrap(),
-      dest: PathBuf::from("./tmp"),
-      cache: PathBuf::from("./tmp"),
-      kind: BzTaskType::M3u8,
-      status: BzTaskStatus::Queued,
-    };
+    let task_info = test_task_info(
+      "https://svipsvip.ffzy-online5.com/20250118/37333_517b17a8/2000k/hls/mixed.m3u8",
+      PathBuf::from("./tmp"),
+      PathBuf::from("./tmp"),
+    );
     // let mut task = M3u8Task::new(task_info);
     let task_url = task_info.src.join("adc.ts").unwrap();
     println!("task_url: {:?}", task_url);
   }
+
+  const MASTER: &str = r#"#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,CODECS="avc1.4d401e,mp4a.40.2"
+360p/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2000000,RESOLUTION=1280x720,CODECS="avc1.4d401f,mp4a.40.2"
+720p/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1500000,RESOLUTION=1280x720,CODECS="hvc1.1.6.L93.B0,mp4a.40.2"
+720p_hevc/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=5000000,RESOLUTION=1920x1080,CODECS="avc1.640028,mp4a.40.2"
+1080p/index.m3u8
+#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH=100000,URI="iframe.m3u8"
+"#;
+
+  fn master_variants() -> Vec<VariantStream> {
+    match m3u8_rs::parse_playlist_res(MASTER.as_bytes()).unwrap() {
+      Playlist::MasterPlaylist(master) => master.variants,
+      Playlist::MediaPlaylist(_) => panic!("expect master playlist"),
+    }
+  }
+
+  const ENCRYPTED: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXT-X-MEDIA-SEQUENCE:7
+#EXTINF:10.0,
+clear.ts
+#EXT-X-KEY:METHOD=AES-128,URI="key1.key"
+#EXTINF:10.0,
+a.ts
+#EXTINF:10.0,
+b.ts
+#EXT-X-KEY:METHOD=AES-128,URI="https://keys.example.com/key2.key",IV=0x000102030405060708090a0b0c0d0e0f
+#EXTINF:10.0,
+c.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:10.0,
+d.ts
+#EXT-X-ENDLIST
+"#;
+
+  #[test]
+  fn test_parse_segments_key_rotation() {
+    let playlist = m3u8_rs::parse_media_playlist(ENCRYPTED.as_bytes())
+      .unwrap()
+      .1;
+    let base_url = Url::parse("https://example.com/hls/index.m3u8").unwrap();
+    let segments = parse_segments(&playlist, &base_url).unwrap();
+    let keys = segments
+      .iter()
+      .map(|segment| segment.key.as_ref().map(|key| key.uri.to_string()))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      keys,
+      vec![
+        None,
+        Some("https://example.com/hls/key1.key".to_string()),
+        Some("https://example.com/hls/key1.key".to_string()),
+        Some("https://keys.example.com/key2.key".to_string()),
+        None,
+      ]
+    );
+    assert_eq!(segments[2].sequence, 9);
+    // 没有显式IV 使用 sequence number
+    let mut iv = [0u8; 16];
+    iv[15] = 9;
+    assert_eq!(segments[2].key.as_ref().unwrap().iv(9), iv);
+    let explicit: [u8; 16] = core::array::from_fn(|i| i as u8);
+    assert_eq!(segments[3].key.as_ref().unwrap().iv(10), explicit);
+  }
+
+  const FMP4: &str = r#"#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-TARGETDURATION:6
+#EXT-X-MAP:URI="main.mp4",BYTERANGE="720@0"
+#EXTINF:6.0,
+#EXT-X-BYTERANGE:1000@720
+main.mp4
+#EXTINF:6.0,
+seg1.m4s
+#EXT-X-DISCONTINUITY
+#EXT-X-MAP:URI="ad/init.mp4"
+#EXTINF:4.0,
+ad/seg0.m4s
+#EXT-X-ENDLIST
+"#;
+
+  #[test]
+  fn test_parse_segments_map() {
+    let playlist = m3u8_rs::parse_media_playlist(FMP4.as_bytes()).unwrap().1;
+    let base_url = Url::parse("https://example.com/hls/index.m3u8").unwrap();
+    let segments = parse_segments(&playlist, &base_url).unwrap();
+    let maps = segments
+      .iter()
+      .map(|segment| segment.map.clone().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(maps[0], maps[1]);
+    assert_eq!(
+      maps[0].byte_range,
+      Some(M3u8ByteRange {
+        length: 720,
+        offset: 0
+      })
+    );
+    assert_eq!(maps[2].uri.as_str(), "https://example.com/hls/ad/init.mp4");
+    assert_eq!(maps[2].sequence, 2);
+    assert_ne!(maps[0].file_name(), maps[2].file_name());
+    assert_eq!(
+      part_path(Path::new("./tmp/1.mp4"), 2),
+      PathBuf::from("./tmp/1.part2.mp4")
+    );
+  }
+
+  #[tokio::test]
+  async fn test_concat_init_changed() {
+    let dir = std::env::temp_dir()
+      .join(format!("bz_downloader_concat_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let task_info = test_task_info(
+      "https://example.com/hls/index.m3u8",
+      dir.join("1.mp4"),
+      dir.clone(),
+    );
+    let mut task = M3u8Task::new(task_info);
+    let playlist = m3u8_rs::parse_media_playlist(FMP4.as_bytes()).unwrap().1;
+    task.segments = parse_segments(&playlist, &task.task_info.src).unwrap();
+    for segment in &task.segments {
+      let map = segment.map.as_ref().unwrap();
+      std::fs::write(dir.join(map.file_name()), map.uri.path()).unwrap();
+      std::fs::write(dir.join(segment.file_name()), b"seg").unwrap();
+    }
+    task.concat().await.unwrap();
+
+    let first = std::fs::read(dir.join("1.mp4")).unwrap();
+    assert_eq!(first, b"/hls/main.mp4segseg");
+    let second = std::fs::read(dir.join("1.part2.mp4")).unwrap();
+    assert_eq!(second, b"/hls/ad/init.mp4seg");
+    let notices = task.take_notices();
+    assert_eq!(notices.len(), 1);
+    assert!(notices[0].contains("1.part2.mp4"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  const BYTERANGE: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXT-X-MEDIA-SEQUENCE:3
+#EXTINF:10.0,
+#EXT-X-BYTERANGE:1000@0
+all.ts
+#EXTINF:10.0,
+#EXT-X-BYTERANGE:2000
+all.ts
+#EXTINF:10.0,
+#EXT-X-BYTERANGE:500
+all.ts
+#EXT-X-ENDLIST
+"#;
+
+  #[test]
+  fn test_parse_segments_byte_range() {
+    let playlist = m3u8_rs::parse_media_playlist(BYTERANGE.as_bytes())
+      .unwrap()
+      .1;
+    let base_url = Url::parse("https://example.com/hls/index.m3u8").unwrap();
+    let segments = parse_segments(&playlist, &base_url).unwrap();
+    let ranges = segments
+      .iter()
+      .map(|segment| {
+        let range = segment.byte_range.as_ref().unwrap();
+        (range.offset, range.length)
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(ranges, vec![(0, 1000), (1000, 2000), (3000, 500)]);
+    // 同一个 uri 的分片缓存为不同的文件
+    let file_names = segments
+      .iter()
+      .map(|segment| segment.file_name())
+      .collect::<HashSet<_>>();
+    assert_eq!(file_names.len(), 3);
+  }
+
+  const LIVE: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-MEDIA-SEQUENCE:10
+#EXTINF:4.0,
+10.ts
+#EXTINF:4.0,
+11.ts
+"#;
+
+  const LIVE_NEXT: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-MEDIA-SEQUENCE:11
+#EXTINF:4.0,
+11.ts
+#EXTINF:4.0,
+12.ts
+#EXTINF:4.0,
+13.ts
+#EXT-X-ENDLIST
+"#;
+
+  #[test]
+  fn test_append_playlist() {
+    let dir = std::env::temp_dir()
+      .join(format!("bz_downloader_live_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let task_info = test_task_info(
+      "https://example.com/live/index.m3u8",
+      dir.join("live.ts"),
+      dir.clone(),
+    );
+    let mut task = M3u8Task::new(task_info);
+    let playlist = m3u8_rs::parse_media_playlist(LIVE.as_bytes()).unwrap().1;
+    task.segments = parse_segments(&playlist, &task.base_url).unwrap();
+    task.porgress.init_tasks(&vec![10, 11]);
+    task.recording = Some(M3u8Recording {
+      interval: Duration::from_secs(4),
+      next_sequence: 12,
+      duration: 0.0,
+      bytes: 0,
+    });
+
+    let playlist = m3u8_rs::parse_media_playlist(LIVE_NEXT.as_bytes())
+      .unwrap()
+      .1;
+    assert!(task.append_playlist(&playlist).unwrap());
+    let sequences = task
+      .segments
+      .iter()
+      .map(|segment| segment.sequence)
+      .collect::<Vec<_>>();
+    assert_eq!(sequences, vec![10, 11, 12, 13]);
+    assert_eq!(task.recording.as_ref().unwrap().next_sequence, 14);
+    let order =
+      std::iter::from_fn(|| task.porgress.todos.pop_back()).collect::<Vec<_>>();
+    assert_eq!(order, vec![10, 11, 12, 13]);
+    // 重新启动时从保存的列表继续录制
+    let recorded: Vec<M3u8Segment> =
+      persist::load_json(&task.record_file()).unwrap().unwrap();
+    assert_eq!(recorded.len(), 4);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  // 测试用的 HTTP 服务 按照路径返回固定的内容
+  async fn serve(routes: HashMap<String, Vec<u8>>) -> Url {
+    use tokio::io::AsyncReadExt;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let routes = std::sync::Arc::new(routes);
+    tokio::spawn(async move {
+      while let Ok((mut stream, _)) = listener.accept().await {
+        let routes = routes.clone();
+        tokio::spawn(async move {
+          let mut buf = vec![0u8; 4096];
+          let n = stream.read(&mut buf).await.unwrap_or(0);
+          let request = String::from_utf8_lossy(&buf[..n]).to_string();
+          let path = request.split_whitespace().nth(1).unwrap_or("/");
+          let (status, body) = match routes.get(path) {
+            Some(body) => ("200 OK", body.clone()),
+            None => ("404 Not Found", Vec::new()),
+          };
+          let head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+          );
+          let _ = stream.write_all(head.as_bytes()).await;
+          let _ = stream.write_all(&body).await;
+        });
+      }
+    });
+    Url::parse(&format!("http://{}/", addr)).unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_record_until_limit() {
+    let dir = std::env::temp_dir()
+      .join(format!("bz_downloader_record_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut routes = HashMap::from([(
+      "/live/index.m3u8".to_string(),
+      LIVE_FOUR.as_bytes().to_vec(),
+    )]);
+    for index in 0..4 {
+      routes.insert(format!("/live/{}.ts", index), vec![0x47; 188]);
+    }
+    let base_url = serve(routes).await;
+    let mut task_info = test_task_info(
+      base_url.join("live/index.m3u8").unwrap().as_str(),
+      dir.join("live.ts"),
+      dir.join("cache"),
+    );
+    task_info.proxy = BzTaskProxy::Direct;
+    task_info.record_limit.max_bytes = Some(188 * 2);
+    let mut task = M3u8Task::new(task_info);
+    task.prepare().await.unwrap();
+    assert!(task.recording.is_some());
+
+    let (_control_sender, control_receiver) = tokio::sync::mpsc::channel(10);
+    let (feedback_sender, _feedback_receiver) = tokio::sync::mpsc::channel(100);
+    let mut controller = BzTaskController::new(
+      crate::bz_task::BzTaskId::unique(),
+      control_receiver,
+      feedback_sender,
+    );
+    // 达到上限后停止录制 并且合并已经下载的分片
+    task.start(&mut controller).await.unwrap();
+    task.finish().await.unwrap();
+    let output = std::fs::read(dir.join("live.ts")).unwrap();
+    assert!(output.len() >= 188 * 2 && output.len().is_multiple_of(188));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  const LIVE_FOUR: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:1
+#EXT-X-MEDIA-SEQUENCE:0
+#EXTINF:1.0,
+0.ts
+#EXTINF:1.0,
+1.ts
+#EXTINF:1.0,
+2.ts
+#EXTINF:1.0,
+3.ts
+"#;
+
+  #[test]
+  fn test_record_limit_reached() {
+    let mut task_info = test_task_info(
+      "https://example.com/live/index.m3u8",
+      PathBuf::from("./tmp/live.ts"),
+      PathBuf::from("./tmp"),
+    );
+    task_info.record_limit = BzRecordLimit {
+      max_duration_secs: Some(60),
+      max_bytes: Some(1000),
+    };
+    let mut task = M3u8Task::new(task_info);
+    assert!(!task.record_limit_reached());
+    task.recording = Some(M3u8Recording {
+      interval: Duration::from_secs(4),
+      next_sequence: 0,
+      duration: 59.5,
+      bytes: 999,
+    });
+    assert!(!task.record_limit_reached());
+    task.recording.as_mut().unwrap().duration = 60.0;
+    assert!(task.record_limit_reached());
+    let recording = task.recording.as_mut().unwrap();
+    recording.duration = 0.0;
+    recording.bytes = 1000;
+    assert!(task.record_limit_reached());
+  }
+
+  #[test]
+  fn test_migrate_legacy_progress() {
+    let dir = std::env::temp_dir()
+      .join(format!("bz_downloader_legacy_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let playlist = m3u8_rs::parse_media_playlist(ENCRYPTED.as_bytes())
+      .unwrap()
+      .1;
+    let base_url = Url::parse("https://example.com/hls/index.m3u8").unwrap();
+    let segments = parse_segments(&playlist, &base_url).unwrap();
+    // 旧版本的进度和以 uri 命名的分片
+    std::fs::write(dir.join("process.json"), r#"["clear.ts", "a.ts", "d.ts"]"#)
+      .unwrap();
+    std::fs::write(dir.join("clear.ts"), [0x47; 188]).unwrap();
+    std::fs::write(dir.join("a.ts"), [0x47; 188]).unwrap();
+    std::fs::write(dir.join("d.ts"), b"<html></html>").unwrap();
+
+    let mut progress = M3u8TaskProgress::new(&dir);
+    progress.load();
+    progress.migrate_legacy(&dir, &segments);
+    // 加密的分片和损坏的分片重新下载
+    assert_eq!(progress.downloaded, HashSet::from([7]));
+    assert!(dir.join("7.seg").exists());
+    assert_eq!(progress.notices.len(), 1);
+    assert!(progress.notices[0].contains("2 个分片需要重新下载"));
+
+    let mut progress = M3u8TaskProgress::new(&dir);
+    progress.load();
+    assert_eq!(progress.downloaded, HashSet::from([7]));
+    assert!(progress.notices.is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_progress_order() {
+    let mut progress = M3u8TaskProgress::new("./tmp");
+    progress.downloaded.insert(0);
+    progress.init_tasks(&vec![0, 1]);
+    progress.append_tasks(&[2, 3]);
+    assert_eq!(progress.total, 4);
+    let order =
+      std::iter::from_fn(|| progress.todos.pop_back()).collect::<Vec<_>>();
+    assert_eq!(order, vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn test_decrypt_segment() {
+    use aes::cipher::BlockEncryptMut;
+    let key = [7u8; 16];
+    let iv = (42u128).to_be_bytes();
+    let plain = vec![0x47u8; 188 * 3];
+    let encrypted = cbc::Encryptor::<aes::Aes128>::new(&key.into(), &iv.into())
+      .encrypt_padded_vec_mut::<Pkcs7>(&plain);
+    assert_ne!(encrypted, plain);
+    assert_eq!(decrypt_segment(&encrypted, &key, &iv).unwrap(), plain);
+    // 不完整的密文
+    assert!(matches!(
+      decrypt_segment(&encrypted[..encrypted.len() - 5], &key, &iv),
+      Err(BzError::Corrupted { .. })
+    ));
+  }
+
+  #[test]
+  fn test_check_segment() {
+    let ts = vec![0x47u8; 188 * 2];
+    assert!(check_segment(&ts).is_ok());
+    assert!(check_segment(&ts[..300]).is_err());
+    let mut bad_sync = ts.clone();
+    bad_sync[188] = 0;
+    assert!(check_segment(&bad_sync).is_err());
+    assert!(check_segment(&[]).is_err());
+    let html = b"\n  <!DOCTYPE html><html><body>404</body></html>";
+    assert!(check_segment(html).is_err());
+
+    let mut fmp4 = Vec::new();
+    fmp4.extend(16u32.to_be_bytes());
+    fmp4.extend(b"ftypiso6");
+    fmp4.extend(0u32.to_be_bytes());
+    fmp4.extend(12u32.to_be_bytes());
+    fmp4.extend(b"free");
+    fmp4.extend([0u8; 4]);
+    assert!(check_segment(&fmp4).is_ok());
+    assert!(check_segment(&fmp4[..20]).is_err());
+    // largesize 太小或者加上偏移之后溢出
+    for large in [8u64, u64::MAX - 15, u64::MAX] {
+      let mut boxes = fmp4.clone();
+      boxes.extend(1u32.to_be_bytes());
+      boxes.extend(b"mdat");
+      boxes.extend(large.to_be_bytes());
+      assert!(check_segment(&boxes).is_err());
+    }
+    // 不认识的格式不检查结构
+    assert!(check_segment(b"WEBVTT\n\n").is_ok());
+  }
+
+  #[test]
+  fn test_select_variant() {
+    let variants = master_variants();
+    let select = |policy: BzVariantPolicy| {
+      select_variant(&variants, &policy).map(|variant| variant.uri.clone())
+    };
+    assert_eq!(
+      select(BzVariantPolicy::HighestBandwidth).as_deref(),
+      Some("1080p/index.m3u8")
+    );
+    assert_eq!(
+      select(BzVariantPolicy::LowestBandwidth).as_deref(),
+      Some("360p/index.m3u8")
+    );
+    assert_eq!(
+      select(BzVariantPolicy::Resolution {
+        width: 1280,
+        height: 720
+      })
+      .as_deref(),
+      Some("720p/index.m3u8")
+    );
+    assert_eq!(
+      select(BzVariantPolicy::Codec("hvc1".into())).as_deref(),
+      Some("720p_hevc/index.m3u8")
+    );
+    assert_eq!(select(BzVariantPolicy::Codec("av01".into())), None);
+  }
+
+  const MASTER_RENDITIONS: &str = r#"#EXTM3U
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aac",NAME="English",LANGUAGE="en",DEFAULT=YES,AUTOSELECT=YES,URI="audio/en.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aac",NAME="Deutsch",LANGUAGE="de",URI="audio/de.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="muxed",NAME="Main",DEFAULT=YES
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="中文",LANGUAGE="zh",URI="subs/zh.m3u8"
+#EXT-X-STREAM-INF:BANDWIDTH=2000000,AUDIO="aac",SUBTITLES="subs"
+720p/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1000000,AUDIO="muxed"
+480p/index.m3u8
+"#;
+
+  #[test]
+  fn test_select_renditions() {
+    let Ok(Playlist::MasterPlaylist(master)) =
+      m3u8_rs::parse_playlist_res(MASTER_RENDITIONS.as_bytes())
+    else {
+      panic!("invalid master playlist");
+    };
+    let renditions = parse_renditions(&master);
+    assert_eq!(renditions.len(), 3);
+    let select =
+      |variant: usize, audio: Option<&str>, subtitles: Option<&str>| {
+        let selection = BzRenditionSelection {
+          audio: audio.map(str::to_string),
+          subtitles: subtitles.map(str::to_string),
+        };
+        select_renditions(&renditions, &master.variants[variant], &selection)
+          .iter()
+          .map(|rendition| rendition.uri.clone())
+          .collect::<Vec<_>>()
+      };
+    assert_eq!(select(0, None, None), ["audio/en.m3u8"]);
+    assert_eq!(
+      select(0, Some("Deutsch"), Some("中文")),
+      ["audio/de.m3u8", "subs/zh.m3u8"]
+    );
+    // 音轨包含在码流中
+    assert!(select(1, None, None).is_empty());
+
+    let dest = Path::new("/tmp/movie.mp4");
+    assert_eq!(
+      renditions[2].sidecar_path(dest, "vtt"),
+      Path::new("/tmp/movie.zh.vtt")
+    );
+  }
 }
@@ -1,8 +1,17 @@
+mod add_task;
 mod app_state;
 mod bz_downloader;
 mod bz_task;
+mod cookies;
+mod dash;
 mod error;
+mod http;
+mod http_file;
 mod m3u8;
+mod persist;
+mod rate_limit;
+mod remux;
+mod time_window;
 mod tray;
 mod view;
 mod zfs;
@@ -0,0 +1,113 @@
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::error::{BzError, BzResult};
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+  let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+  file_name.push(suffix);
+  path.with_file_name(file_name)
+}
+
+// 上一次成功写入的内容
+pub fn backup_path(path: &Path) -> PathBuf {
+  with_suffix(path, ".bak")
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> BzResult<T> {
+  let reader = BufReader::new(std::fs::File::open(path)?);
+  serde_json::from_reader(reader).map_err(|err| BzError::Parse {
+    reason: format!("{}: {}", path.display(), err),
+  })
+}
+
+// 先写入临时文件并且 fsync 再通过 rename 替换原文件
+// 原文件保留为 .bak 即使在两次 rename 之间崩溃也能从备份中恢复
+pub fn save_json<T: Serialize>(path: &Path, value: &T) -> BzResult<()> {
+  let temp_path = with_suffix(path, ".tmp");
+  let file = std::fs::File::create(&temp_path)?;
+  let mut writer = BufWriter::new(file);
+  serde_json::to_writer_pretty(&mut writer, value).map_err(|err| {
+    BzError::Parse {
+      reason: format!("{}: {}", path.display(), err),
+    }
+  })?;
+  let file = writer.into_inner().map_err(|err| err.into_error())?;
+  file.sync_all()?;
+  drop(file);
+  if path.exists() {
+    std::fs::rename(path, backup_path(path))?;
+  }
+  std::fs::rename(&temp_path, path)?;
+  Ok(())
+}
+
+// 文件和备份都不存在时返回 None
+// 文件损坏时使用备份 都无法解析时返回原文件的错误
+// 第二个值表示内容来自备份 调用方需要提示用户
+pub fn load_json_or_backup<T: DeserializeOwned>(
+  path: &Path,
+) -> BzResult<Option<(T, bool)>> {
+  let backup = backup_path(path);
+  if !path.exists() && !backup.exists() {
+    return Ok(None);
+  }
+  match read_json(path) {
+    Ok(value) => Ok(Some((value, false))),
+    Err(err) => {
+      log::error!("failed to load {}: {}, try backup", path.display(), err);
+      read_json(&backup)
+        .map(|value| Some((value, true)))
+        .map_err(|_| err)
+    }
+  }
+}
+
+pub fn load_json<T: DeserializeOwned>(path: &Path) -> BzResult<Option<T>> {
+  Ok(load_json_or_backup(path)?.map(|(value, _)| value))
+}
+
+// 把无法解析的文件改名保存 避免之后的写入把它覆盖掉
+pub fn keep_corrupted(path: &Path) -> PathBuf {
+  let corrupted = with_suffix(path, ".corrupted");
+  if let Err(err) = std::fs::rename(path, &corrupted) {
+    log::error!("failed to rename {}: {}", path.display(), err);
+  }
+  corrupted
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_load_json_fallback() {
+    let dir = std::env::temp_dir()
+      .join(format!("bz_downloader_persist_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("task_list.json");
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(backup_path(&path));
+
+    assert!(load_json::<Vec<u32>>(&path).unwrap().is_none());
+
+    save_json(&path, &vec![1u32]).unwrap();
+    save_json(&path, &vec![1u32, 2]).unwrap();
+    assert_eq!(load_json::<Vec<u32>>(&path).unwrap(), Some(vec![1, 2]));
+
+    // 写了一半的文件
+    std::fs::write(&path, "[1, 2").unwrap();
+    assert_eq!(
+      load_json_or_backup::<Vec<u32>>(&path).unwrap(),
+      Some((vec![1], true))
+    );
+
+    std::fs::write(backup_path(&path), "").unwrap();
+    assert!(load_json::<Vec<u32>>(&path).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
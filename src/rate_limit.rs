@@ -0,0 +1,264 @@
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+
+// 全局同时下载的连接数量 启动时按照设置修改
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+// 所有运行中的任务共用
+static GLOBAL_BUCKET: LazyLock<BzTokenBucket> =
+  LazyLock::new(|| BzTokenBucket::new(BzSpeedLimit::Unlimited));
+static GLOBAL_CONNECTIONS: LazyLock<BzConnectionLimit> =
+  LazyLock::new(|| BzConnectionLimit::new(DEFAULT_MAX_CONNECTIONS));
+
+// 下载速度上限
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum BzSpeedLimit {
+  #[default]
+  Unlimited,
+  // 字节每秒
+  Limited(u64),
+}
+
+impl BzSpeedLimit {
+  pub const PRESETS: [BzSpeedLimit; 6] = [
+    BzSpeedLimit::Unlimited,
+    BzSpeedLimit::Limited(512 * 1024),
+    BzSpeedLimit::Limited(1024 * 1024),
+    BzSpeedLimit::Limited(2 * 1024 * 1024),
+    BzSpeedLimit::Limited(5 * 1024 * 1024),
+    BzSpeedLimit::Limited(10 * 1024 * 1024),
+  ];
+
+  fn rate(&self) -> Option<f64> {
+    match self {
+      BzSpeedLimit::Unlimited => None,
+      BzSpeedLimit::Limited(rate) => Some((*rate).max(1) as f64),
+    }
+  }
+}
+
+impl std::fmt::Display for BzSpeedLimit {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BzSpeedLimit::Unlimited => write!(f, "不限速"),
+      BzSpeedLimit::Limited(rate) if rate % (1024 * 1024) == 0 => {
+        write!(f, "{} MB/s", rate / 1024 / 1024)
+      }
+      BzSpeedLimit::Limited(rate) => write!(f, "{} KB/s", rate / 1024),
+    }
+  }
+}
+
+#[derive(Debug)]
+struct BzBucketState {
+  limit: BzSpeedLimit,
+  // 可以为负数 表示预支的字节数
+  tokens: f64,
+  last: Instant,
+}
+
+// 令牌桶 最多积攒一秒的流量
+#[derive(Debug)]
+pub struct BzTokenBucket {
+  state: Mutex<BzBucketState>,
+}
+
+impl BzTokenBucket {
+  pub fn new(limit: BzSpeedLimit) -> Self {
+    Self {
+      state: Mutex::new(BzBucketState {
+        limit,
+        tokens: limit.rate().unwrap_or_default(),
+        last: Instant::now(),
+      }),
+    }
+  }
+
+  // 下载过程中修改 之后读取的数据按照新的速度
+  pub fn set_limit(&self, limit: BzSpeedLimit) {
+    let mut state = self.state.lock().unwrap();
+    state.limit = limit;
+    state.tokens = state.tokens.min(limit.rate().unwrap_or_default());
+    state.last = Instant::now();
+  }
+
+  // 先扣除 不够的部分按照速度计算需要等待的时间
+  // 读到的数据大小不固定 预支之后让后面的读取等待
+  fn reserve(&self, bytes: usize) -> Duration {
+    let mut state = self.state.lock().unwrap();
+    let Some(rate) = state.limit.rate() else {
+      return Duration::ZERO;
+    };
+    let now = Instant::now();
+    let elapsed = now.duration_since(state.last).as_secs_f64();
+    state.tokens = (state.tokens + elapsed * rate).min(rate) - bytes as f64;
+    state.last = now;
+    match state.tokens < 0.0 {
+      true => Duration::from_secs_f64(-state.tokens / rate),
+      false => Duration::ZERO,
+    }
+  }
+
+  pub async fn acquire(&self, bytes: usize) {
+    let delay = self.reserve(bytes);
+    if !delay.is_zero() {
+      tokio::time::sleep(delay).await;
+    }
+  }
+}
+
+pub fn global() -> &'static BzTokenBucket {
+  &GLOBAL_BUCKET
+}
+
+#[derive(Debug)]
+struct BzConnectionState {
+  limit: usize,
+  // 减少上限时还在使用中的连接 释放时不再归还
+  debt: usize,
+}
+
+// 所有任务同时下载的分片和连接数量
+#[derive(Debug)]
+pub struct BzConnectionLimit {
+  semaphore: Semaphore,
+  state: Mutex<BzConnectionState>,
+}
+
+// 连接结束时释放
+pub struct BzConnectionPermit {
+  limit: &'static BzConnectionLimit,
+  permit: Option<SemaphorePermit<'static>>,
+}
+
+impl Drop for BzConnectionPermit {
+  fn drop(&mut self) {
+    let mut state = self.limit.state.lock().unwrap();
+    if state.debt > 0
+      && let Some(permit) = self.permit.take()
+    {
+      state.debt -= 1;
+      permit.forget();
+    }
+  }
+}
+
+impl BzConnectionLimit {
+  pub fn new(limit: usize) -> Self {
+    let limit = limit.max(1);
+    Self {
+      semaphore: Semaphore::new(limit),
+      state: Mutex::new(BzConnectionState { limit, debt: 0 }),
+    }
+  }
+
+  // 增加时立即生效 减少时空闲的位置立即收回 使用中的等连接结束后收回
+  pub fn set_limit(&self, limit: usize) {
+    let limit = limit.max(1);
+    let mut state = self.state.lock().unwrap();
+    if limit > state.limit {
+      let more = limit - state.limit;
+      let repaid = more.min(state.debt);
+      state.debt -= repaid;
+      self.semaphore.add_permits(more - repaid);
+    } else {
+      let fewer = state.limit - limit;
+      state.debt += fewer - self.semaphore.forget_permits(fewer);
+    }
+    state.limit = limit;
+  }
+
+  pub async fn acquire(&'static self) -> BzConnectionPermit {
+    // 信号量不会被关闭
+    let permit = self.semaphore.acquire().await.ok();
+    BzConnectionPermit {
+      limit: self,
+      permit,
+    }
+  }
+}
+
+pub fn connections() -> &'static BzConnectionLimit {
+  &GLOBAL_CONNECTIONS
+}
+
+// 读取响应内容前先经过全局和任务自己的限速
+#[derive(Debug, Clone, Default)]
+pub struct BzRateLimiter {
+  task: Option<Arc<BzTokenBucket>>,
+}
+
+impl BzRateLimiter {
+  pub fn new(task_limit: BzSpeedLimit) -> Self {
+    Self {
+      task: (task_limit != BzSpeedLimit::Unlimited)
+        .then(|| Arc::new(BzTokenBucket::new(task_limit))),
+    }
+  }
+
+  pub async fn acquire(&self, bytes: usize) {
+    if let Some(task) = &self.task {
+      task.acquire(bytes).await;
+    }
+    global().acquire(bytes).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_token_bucket() {
+    let bucket = BzTokenBucket::new(BzSpeedLimit::Limited(10_000));
+    let start = Instant::now();
+    // 初始可以使用一秒的流量
+    bucket.acquire(10_000).await;
+    assert!(start.elapsed() < Duration::from_millis(100));
+    bucket.acquire(2_000).await;
+    let elapsed = start.elapsed();
+    assert!(
+      elapsed >= Duration::from_millis(150)
+        && elapsed < Duration::from_millis(1000),
+      "{elapsed:?}"
+    );
+
+    bucket.set_limit(BzSpeedLimit::Unlimited);
+    let start = Instant::now();
+    bucket.acquire(1 << 30).await;
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    assert_eq!(BzSpeedLimit::PRESETS[1].to_string(), "512 KB/s");
+    assert_eq!(BzSpeedLimit::PRESETS[3].to_string(), "2 MB/s");
+  }
+  #[tokio::test]
+  async fn test_connection_limit() {
+    let limit: &'static BzConnectionLimit =
+      Box::leak(Box::new(BzConnectionLimit::new(2)));
+    let first = limit.acquire().await;
+    let second = limit.acquire().await;
+    assert_eq!(limit.semaphore.available_permits(), 0);
+
+    // 使用中的连接结束后才收回
+    limit.set_limit(1);
+    drop(first);
+    assert_eq!(limit.semaphore.available_permits(), 0);
+    drop(second);
+    assert_eq!(limit.semaphore.available_permits(), 1);
+
+    limit.set_limit(3);
+    assert_eq!(limit.semaphore.available_permits(), 3);
+    let held = limit.acquire().await;
+    limit.set_limit(1);
+    assert_eq!(limit.semaphore.available_permits(), 0);
+    limit.set_limit(2);
+    drop(held);
+    assert_eq!(limit.semaphore.available_permits(), 2);
+  }
+}
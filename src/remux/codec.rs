@@ -0,0 +1,552 @@
+use crate::error::{BzError, BzResult};
+
+fn remux_error(reason: &str) -> BzError {
+  BzError::Remux {
+    reason: reason.to_string(),
+  }
+}
+
+// 按位读取 用于解析 SPS
+pub struct BitReader<'a> {
+  data: &'a [u8],
+  position: usize,
+}
+
+impl<'a> BitReader<'a> {
+  pub fn new(data: &'a [u8]) -> Self {
+    Self { data, position: 0 }
+  }
+
+  pub fn read_bit(&mut self) -> BzResult<u32> {
+    let byte = self
+      .data
+      .get(self.position / 8)
+      .ok_or_else(|| remux_error("unexpected end of parameter set"))?;
+    let bit = (byte >> (7 - self.position % 8)) & 1;
+    self.position += 1;
+    Ok(bit as u32)
+  }
+
+  pub fn read_bits(&mut self, count: u32) -> BzResult<u32> {
+    let mut value = 0;
+    for _ in 0..count {
+      value = (value << 1) | self.read_bit()?;
+    }
+    Ok(value)
+  }
+
+  pub fn skip_bits(&mut self, count: usize) {
+    self.position += count;
+  }
+
+  // Exp-Golomb
+  pub fn read_ue(&mut self) -> BzResult<u32> {
+    let mut zeros = 0;
+    while self.read_bit()? == 0 {
+      zeros += 1;
+      if zeros > 31 {
+        return Err(remux_error("invalid exp-golomb code"));
+      }
+    }
+    Ok((1u32 << zeros) - 1 + self.read_bits(zeros)?)
+  }
+
+  pub fn read_se(&mut self) -> BzResult<i32> {
+    let value = self.read_ue()?;
+    Ok(match value % 2 {
+      1 => ((value + 1) / 2) as i32,
+      _ => -((value / 2) as i32),
+    })
+  }
+}
+
+// 裁剪的数值来自码流 溢出时视为无效的 SPS
+fn crop(size: u32, start: u32, end: u32, unit: u32) -> BzResult<u32> {
+  start
+    .checked_add(end)
+    .and_then(|value| value.checked_mul(unit))
+    .and_then(|value| size.checked_sub(value))
+    .ok_or_else(|| remux_error("invalid sps cropping"))
+}
+
+// 去掉 NAL 中的防竞争字节 00 00 03 -> 00 00
+pub fn unescape_rbsp(nal: &[u8]) -> Vec<u8> {
+  let mut rbsp = Vec::with_capacity(nal.len());
+  let mut zeros = 0;
+  for &byte in nal {
+    if zeros >= 2 && byte == 3 {
+      zeros = 0;
+      continue;
+    }
+    zeros = if byte == 0 { zeros + 1 } else { 0 };
+    rbsp.push(byte);
+  }
+  rbsp
+}
+
+// Annex B 格式的码流按照起始码 00 00 01 拆分为 NAL
+pub fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+  let mut starts = Vec::new();
+  let mut i = 0;
+  while i + 3 <= data.len() {
+    if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+      starts.push(i + 3);
+      i += 3;
+    } else {
+      i += 1;
+    }
+  }
+  starts
+    .iter()
+    .enumerate()
+    .map(|(index, &start)| {
+      let end = starts.get(index + 1).map_or(data.len(), |next| next - 3);
+      let mut nal = &data[start..end.max(start)];
+      // 4字节起始码的第一个 00 属于前一个 NAL 的末尾
+      while let [rest @ .., 0] = nal {
+        nal = rest;
+      }
+      nal
+    })
+    .filter(|nal| !nal.is_empty())
+    .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoSize {
+  pub width: u32,
+  pub height: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct AvcSps {
+  pub profile_idc: u8,
+  pub constraint_flags: u8,
+  pub level_idc: u8,
+  pub chroma_format_idc: u32,
+  pub bit_depth_luma_minus8: u32,
+  pub bit_depth_chroma_minus8: u32,
+  pub size: VideoSize,
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> BzResult<()> {
+  let mut last = 8;
+  let mut next = 8;
+  for _ in 0..size {
+    if next != 0 {
+      let delta = reader.read_se()?;
+      next = (last + delta + 256) % 256;
+    }
+    if next != 0 {
+      last = next;
+    }
+  }
+  Ok(())
+}
+
+// 只解析到 frame cropping 为止 用于获取分辨率和 avcC 需要的字段
+pub fn parse_avc_sps(nal: &[u8]) -> BzResult<AvcSps> {
+  let rbsp = unescape_rbsp(nal);
+  if rbsp.len() < 4 {
+    return Err(remux_error("sps too short"));
+  }
+  let profile_idc = rbsp[1];
+  let mut reader = BitReader::new(&rbsp[4..]);
+  reader.read_ue()?; // seq_parameter_set_id
+  let mut chroma_format_idc = 1;
+  let mut bit_depth_luma_minus8 = 0;
+  let mut bit_depth_chroma_minus8 = 0;
+  let mut separate_colour_plane = false;
+  if matches!(
+    profile_idc,
+    100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+  ) {
+    chroma_format_idc = reader.read_ue()?;
+    if chroma_format_idc == 3 {
+      separate_colour_plane = reader.read_bit()? == 1;
+    }
+    bit_depth_luma_minus8 = reader.read_ue()?;
+    bit_depth_chroma_minus8 = reader.read_ue()?;
+    reader.read_bit()?; // qpprime_y_zero_transform_bypass_flag
+    if reader.read_bit()? == 1 {
+      let count = if chroma_format_idc == 3 { 12 } else { 8 };
+      for index in 0..count {
+        if reader.read_bit()? == 1 {
+          skip_scaling_list(&mut reader, if index < 6 { 16 } else { 64 })?;
+        }
+      }
+    }
+  }
+  reader.read_ue()?; // log2_max_frame_num_minus4
+  match reader.read_ue()? {
+    0 => {
+      reader.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+    }
+    1 => {
+      reader.read_bit()?;
+      reader.read_se()?;
+      reader.read_se()?;
+      for _ in 0..reader.read_ue()? {
+        reader.read_se()?;
+      }
+    }
+    _ => {}
+  }
+  reader.read_ue()?; // max_num_ref_frames
+  reader.read_bit()?; // gaps_in_frame_num_value_allowed_flag
+  let width_in_mbs = reader.read_ue()? + 1;
+  let height_in_map_units = reader.read_ue()? + 1;
+  let frame_mbs_only = reader.read_bit()?;
+  if frame_mbs_only == 0 {
+    reader.read_bit()?; // mb_adaptive_frame_field_flag
+  }
+  reader.read_bit()?; // direct_8x8_inference_flag
+  let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) =
+    (0, 0, 0, 0);
+  if reader.read_bit()? == 1 {
+    crop_left = reader.read_ue()?;
+    crop_right = reader.read_ue()?;
+    crop_top = reader.read_ue()?;
+    crop_bottom = reader.read_ue()?;
+  }
+  let (sub_width, sub_height) = match (chroma_format_idc, separate_colour_plane)
+  {
+    (0, _) | (3, true) => (1, 1),
+    (1, _) => (2, 2),
+    (2, _) => (2, 1),
+    _ => (1, 1),
+  };
+  let crop_unit_x = sub_width;
+  let crop_unit_y = sub_height * (2 - frame_mbs_only);
+  let width = width_in_mbs
+    .checked_mul(16)
+    .ok_or_else(|| remux_error("invalid sps width"))?;
+  let height = height_in_map_units
+    .checked_mul(16 * (2 - frame_mbs_only))
+    .ok_or_else(|| remux_error("invalid sps height"))?;
+  let width = crop(width, crop_left, crop_right, crop_unit_x)?;
+  let height = crop(height, crop_top, crop_bottom, crop_unit_y)?;
+  Ok(AvcSps {
+    profile_idc,
+    constraint_flags: rbsp[2],
+    level_idc: rbsp[3],
+    chroma_format_idc,
+    bit_depth_luma_minus8,
+    bit_depth_chroma_minus8,
+    size: VideoSize { width, height },
+  })
+}
+
+// AVCDecoderConfigurationRecord
+pub fn avc_decoder_config(
+  sps: &[u8], pps: &[u8],
+) -> BzResult<(Vec<u8>, VideoSize)> {
+  let info = parse_avc_sps(sps)?;
+  let mut config = vec![
+    1,
+    info.profile_idc,
+    info.constraint_flags,
+    info.level_idc,
+    0xFF, // NAL 长度使用4个字节
+    0xE1, // 1个 SPS
+  ];
+  config.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+  config.extend_from_slice(sps);
+  config.push(1);
+  config.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+  config.extend_from_slice(pps);
+  if matches!(info.profile_idc, 100 | 110 | 122 | 144) {
+    config.push(0xFC | info.chroma_format_idc as u8);
+    config.push(0xF8 | info.bit_depth_luma_minus8 as u8);
+    config.push(0xF8 | info.bit_depth_chroma_minus8 as u8);
+    config.push(0);
+  }
+  Ok((config, info.size))
+}
+
+#[derive(Debug, Clone)]
+pub struct HevcSps {
+  pub max_sub_layers: u8,
+  pub temporal_id_nesting: bool,
+  // general_profile_space general_tier_flag general_profile_idc
+  pub profile: u8,
+  pub profile_compatibility: u32,
+  pub constraint_flags: u64,
+  pub level_idc: u8,
+  pub chroma_format_idc: u32,
+  pub bit_depth_luma_minus8: u32,
+  pub bit_depth_chroma_minus8: u32,
+  pub size: VideoSize,
+}
+
+pub fn parse_hevc_sps(nal: &[u8]) -> BzResult<HevcSps> {
+  let rbsp = unescape_rbsp(nal);
+  if rbsp.len() < 3 {
+    return Err(remux_error("sps too short"));
+  }
+  // 跳过2字节的 NAL 头
+  let mut reader = BitReader::new(&rbsp[2..]);
+  reader.read_bits(4)?; // sps_video_parameter_set_id
+  let max_sub_layers_minus1 = reader.read_bits(3)?;
+  let temporal_id_nesting = reader.read_bit()? == 1;
+  // profile_tier_level
+  let profile = reader.read_bits(8)? as u8;
+  let profile_compatibility = reader.read_bits(32)?;
+  let constraint_flags =
+    ((reader.read_bits(32)? as u64) << 16) | reader.read_bits(16)? as u64;
+  let level_idc = reader.read_bits(8)? as u8;
+  let mut sub_layers = Vec::new();
+  for _ in 0..max_sub_layers_minus1 {
+    sub_layers.push((reader.read_bit()?, reader.read_bit()?));
+  }
+  if max_sub_layers_minus1 > 0 {
+    reader.skip_bits(2 * (8 - max_sub_layers_minus1 as usize));
+  }
+  for (profile_present, level_present) in sub_layers {
+    if profile_present == 1 {
+      reader.skip_bits(88);
+    }
+    if level_present == 1 {
+      reader.skip_bits(8);
+    }
+  }
+  reader.read_ue()?; // sps_seq_parameter_set_id
+  let chroma_format_idc = reader.read_ue()?;
+  let mut separate_colour_plane = false;
+  if chroma_format_idc == 3 {
+    separate_colour_plane = reader.read_bit()? == 1;
+  }
+  let mut width = reader.read_ue()?;
+  let mut height = reader.read_ue()?;
+  if reader.read_bit()? == 1 {
+    let (sub_width, sub_height) =
+      match (chroma_format_idc, separate_colour_plane) {
+        (1, _) => (2, 2),
+        (2, _) => (2, 1),
+        _ => (1, 1),
+      };
+    let left = reader.read_ue()?;
+    let right = reader.read_ue()?;
+    let top = reader.read_ue()?;
+    let bottom = reader.read_ue()?;
+    width = crop(width, left, right, sub_width)?;
+    height = crop(height, top, bottom, sub_height)?;
+  }
+  let bit_depth_luma_minus8 = reader.read_ue()?;
+  let bit_depth_chroma_minus8 = reader.read_ue()?;
+  Ok(HevcSps {
+    max_sub_layers: max_sub_layers_minus1 as u8 + 1,
+    temporal_id_nesting,
+    profile,
+    profile_compatibility,
+    constraint_flags,
+    level_idc,
+    chroma_format_idc,
+    bit_depth_luma_minus8,
+    bit_depth_chroma_minus8,
+    size: VideoSize { width, height },
+  })
+}
+
+// HEVCDecoderConfigurationRecord
+pub fn hevc_decoder_config(
+  vps: &[u8], sps: &[u8], pps: &[u8],
+) -> BzResult<(Vec<u8>, VideoSize)> {
+  let info = parse_hevc_sps(sps)?;
+  let mut config = vec![1, info.profile];
+  config.extend_from_slice(&info.profile_compatibility.to_be_bytes());
+  config.extend_from_slice(&info.constraint_flags.to_be_bytes()[2..]);
+  config.push(info.level_idc);
+  config.extend_from_slice(&[0xF0, 0x00]); // min_spatial_segmentation_idc
+  config.push(0xFC); // parallelismType
+  config.push(0xFC | info.chroma_format_idc as u8);
+  config.push(0xF8 | info.bit_depth_luma_minus8 as u8);
+  config.push(0xF8 | info.bit_depth_chroma_minus8 as u8);
+  config.extend_from_slice(&[0, 0]); // avgFrameRate
+  config.push(
+    (info.max_sub_layers << 3) | ((info.temporal_id_nesting as u8) << 2) | 3,
+  );
+  config.push(3);
+  for (nal_type, nal) in [(32u8, vps), (33, sps), (34, pps)] {
+    config.push(0x80 | nal_type);
+    config.extend_from_slice(&1u16.to_be_bytes());
+    config.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+    config.extend_from_slice(nal);
+  }
+  Ok((config, info.size))
+}
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+  96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025,
+  8000, 7350,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdtsHeader {
+  // audio object type = profile + 1
+  pub object_type: u8,
+  pub sample_rate_index: u8,
+  pub channels: u8,
+  pub header_length: usize,
+  pub frame_length: usize,
+}
+
+impl AdtsHeader {
+  pub fn sample_rate(&self) -> u32 {
+    AAC_SAMPLE_RATES
+      .get(self.sample_rate_index as usize)
+      .copied()
+      .unwrap_or(44100)
+  }
+
+  // AudioSpecificConfig
+  pub fn audio_specific_config(&self) -> [u8; 2] {
+    let config = ((self.object_type as u16) << 11)
+      | ((self.sample_rate_index as u16) << 7)
+      | ((self.channels as u16) << 3);
+    config.to_be_bytes()
+  }
+}
+
+pub fn parse_adts_header(data: &[u8]) -> BzResult<AdtsHeader> {
+  if data.len() < 7 || data[0] != 0xFF || data[1] & 0xF0 != 0xF0 {
+    return Err(remux_error("invalid adts header"));
+  }
+  let protection_absent = data[1] & 1 == 1;
+  let header = AdtsHeader {
+    object_type: (data[2] >> 6) + 1,
+    sample_rate_index: (data[2] >> 2) & 0x0F,
+    channels: ((data[2] & 1) << 2) | (data[3] >> 6),
+    header_length: if protection_absent { 7 } else { 9 },
+    frame_length: (((data[3] & 3) as usize) << 11)
+      | ((data[4] as usize) << 3)
+      | ((data[5] as usize) >> 5),
+  };
+  if header.frame_length < header.header_length {
+    return Err(remux_error("invalid adts frame length"));
+  }
+  Ok(header)
+}
+
+// 一个 PES 中可能有多个 ADTS 帧 返回帧头和去掉帧头的数据
+pub fn split_adts(data: &[u8]) -> Vec<(AdtsHeader, &[u8])> {
+  let mut frames = Vec::new();
+  let mut rest = data;
+  while let Ok(header) = parse_adts_header(rest) {
+    let Some(frame) = rest.get(..header.frame_length) else {
+      log::warn!("truncated adts frame");
+      break;
+    };
+    let payload = &frame[header.header_length..];
+    rest = &rest[header.frame_length..];
+    frames.push((header, payload));
+  }
+  frames
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+
+  // 测试用的位写入 生成 SPS
+  #[derive(Default)]
+  pub struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+  }
+
+  impl BitWriter {
+    pub fn bit(&mut self, bit: u32) {
+      if self.bits.is_multiple_of(8) {
+        self.bytes.push(0);
+      }
+      if bit != 0 {
+        *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+      }
+      self.bits += 1;
+    }
+
+    pub fn bits(&mut self, value: u32, count: u32) {
+      for i in (0..count).rev() {
+        self.bit((value >> i) & 1);
+      }
+    }
+
+    pub fn ue(&mut self, value: u32) {
+      let value = value + 1;
+      let length = 32 - value.leading_zeros();
+      self.bits(0, length - 1);
+      self.bits(value, length);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+      self.bit(1); // rbsp_stop_one_bit
+      self.bytes
+    }
+  }
+
+  // baseline 320x240
+  pub fn avc_sps() -> Vec<u8> {
+    avc_sps_cropped(None)
+  }
+
+  // 裁剪的单位为 2 个像素
+  fn avc_sps_cropped(crop: Option<[u32; 4]>) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    writer.ue(0); // seq_parameter_set_id
+    writer.ue(0); // log2_max_frame_num_minus4
+    writer.ue(2); // pic_order_cnt_type
+    writer.ue(1); // max_num_ref_frames
+    writer.bit(0);
+    writer.ue(19);
+    writer.ue(14);
+    writer.bit(1); // frame_mbs_only_flag
+    writer.bit(1);
+    writer.bit(crop.is_some() as u32); // frame_cropping_flag
+    for value in crop.into_iter().flatten() {
+      writer.ue(value);
+    }
+    writer.bit(0); // vui_parameters_present_flag
+    let mut sps = vec![0x67, 66, 0, 30];
+    sps.extend(writer.finish());
+    sps
+  }
+
+  #[test]
+  fn test_parse_avc_sps() {
+    let sps = parse_avc_sps(&avc_sps()).unwrap();
+    assert_eq!(
+      sps.size,
+      VideoSize {
+        width: 320,
+        height: 240
+      }
+    );
+    assert_eq!(
+      split_annex_b(&[0, 0, 0, 1, 0x09, 0xF0, 0, 0, 1, 0x65, 0, 0, 3, 1]),
+      vec![&[0x09, 0xF0][..], &[0x65, 0, 0, 3, 1][..]]
+    );
+    assert_eq!(unescape_rbsp(&[0x65, 0, 0, 3, 1]), vec![0x65, 0, 0, 1]);
+
+    let sps = parse_avc_sps(&avc_sps_cropped(Some([0, 0, 0, 4]))).unwrap();
+    assert_eq!(sps.size.height, 232);
+    // 裁剪超过画面大小
+    assert!(parse_avc_sps(&avc_sps_cropped(Some([0, 200, 0, 0]))).is_err());
+    assert!(
+      parse_avc_sps(&avc_sps_cropped(Some([0, u32::MAX - 1, 0, 0]))).is_err()
+    );
+  }
+
+  #[test]
+  fn test_split_adts() {
+    // AAC LC 44100Hz 双声道 帧长 7 + 2
+    let frame = [0xFF, 0xF1, 0x50, 0x80, 0x01, 0x3F, 0xFC, 0xAA, 0xBB];
+    let data = [frame, frame].concat();
+    let frames = split_adts(&data);
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].1, &[0xAA, 0xBB]);
+    assert_eq!(frames[0].0.sample_rate(), 44100);
+    assert_eq!(frames[0].0.channels, 2);
+    assert_eq!(frames[0].0.audio_specific_config(), [0x12, 0x10]);
+  }
+}
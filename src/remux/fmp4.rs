@@ -0,0 +1,454 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::error::{BzError, BzResult};
+
+// tfhd 和 trun 的 flags
+const BASE_DATA_OFFSET_PRESENT: u32 = 0x000001;
+const DEFAULT_BASE_IS_MOOF: u32 = 0x020000;
+const DATA_OFFSET_PRESENT: u32 = 0x000001;
+
+// 一条 fMP4 轨道 初始化分片和按时间排列的媒体分片
+#[derive(Debug, Clone)]
+pub struct FragmentedTrack {
+  pub init: PathBuf,
+  pub segments: Vec<FragmentedSegment>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FragmentedSegment {
+  // 开始时间 单位秒 用于和其他轨道交错排列
+  pub time: f64,
+  pub path: PathBuf,
+  // 分片在远程文件中的位置 base_data_offset 相对于远程文件
+  pub offset: u64,
+}
+
+fn remux_error(reason: String) -> BzError {
+  BzError::Remux { reason }
+}
+
+// 盒子的名称和在 data 中的范围 content 不包含盒子头
+struct Mp4Box {
+  name: [u8; 4],
+  range: Range<usize>,
+  content: Range<usize>,
+}
+
+fn boxes(data: &[u8], range: Range<usize>) -> Vec<Mp4Box> {
+  let mut result = Vec::new();
+  let mut position = range.start;
+  while position + 8 <= range.end {
+    let size =
+      u32::from_be_bytes(data[position..position + 4].try_into().unwrap());
+    let name: [u8; 4] = data[position + 4..position + 8].try_into().unwrap();
+    let (header, size) = match size {
+      0 => (8, range.end - position),
+      1 if position + 16 <= range.end => {
+        let size = u64::from_be_bytes(
+          data[position + 8..position + 16].try_into().unwrap(),
+        );
+        (16, usize::try_from(size).unwrap_or(usize::MAX))
+      }
+      size => (8, size as usize),
+    };
+    let Some(end) = position
+      .checked_add(size)
+      .filter(|end| size >= header && *end <= range.end)
+    else {
+      log::warn!("truncated mp4 box: {}", String::from_utf8_lossy(&name));
+      break;
+    };
+    result.push(Mp4Box {
+      name,
+      range: position..end,
+      content: position + header..end,
+    });
+    position = end;
+  }
+  result
+}
+
+fn find_box<'a>(boxes: &'a [Mp4Box], name: &[u8; 4]) -> Option<&'a Mp4Box> {
+  boxes.iter().find(|item| &item.name == name)
+}
+
+fn write_box(output: &mut Vec<u8>, name: &[u8; 4], content: &[u8]) {
+  output.extend_from_slice(&(content.len() as u32 + 8).to_be_bytes());
+  output.extend_from_slice(name);
+  output.extend_from_slice(content);
+}
+
+fn set_u32(data: &mut [u8], offset: usize, value: u32) -> BzResult<()> {
+  data
+    .get_mut(offset..offset + 4)
+    .ok_or_else(|| remux_error(format!("truncated box at {}", offset)))?
+    .copy_from_slice(&value.to_be_bytes());
+  Ok(())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> BzResult<u32> {
+  data
+    .get(offset..offset + 4)
+    .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    .ok_or_else(|| remux_error("truncated moof".to_string()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> BzResult<u64> {
+  data
+    .get(offset..offset + 8)
+    .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+    .ok_or_else(|| remux_error("truncated moof".to_string()))
+}
+
+// 初始化分片中的第一个 trak 和对应的 trex 修改 track_ID
+fn init_track(init: &[u8], track_id: u32) -> BzResult<(Vec<u8>, Vec<u8>)> {
+  let top = boxes(init, 0..init.len());
+  let moov = find_box(&top, b"moov")
+    .ok_or_else(|| remux_error("moov not found in init segment".to_string()))?;
+  let children = boxes(init, moov.content.clone());
+  let trak = find_box(&children, b"trak")
+    .ok_or_else(|| remux_error("trak not found in init segment".to_string()))?;
+  let mut trak_data = init[trak.range.clone()].to_vec();
+  let base = trak.range.start;
+  let tkhd = find_box(&boxes(init, trak.content.clone()), b"tkhd")
+    .map(|tkhd| tkhd.content.start - base)
+    .ok_or_else(|| remux_error("tkhd not found in init segment".to_string()))?;
+  // track_ID 在 version/flags 和创建修改时间之后
+  let version = trak_data
+    .get(tkhd)
+    .ok_or_else(|| remux_error("truncated tkhd in init segment".to_string()))?;
+  let time_fields = if *version == 1 { 16 } else { 8 };
+  set_u32(&mut trak_data, tkhd + 4 + time_fields, track_id)?;
+
+  let trex = find_box(&children, b"mvex")
+    .and_then(|mvex| {
+      find_box(&boxes(init, mvex.content.clone()), b"trex")
+        .map(|trex| trex.range.clone())
+    })
+    .ok_or_else(|| remux_error("trex not found in init segment".to_string()))?;
+  let mut trex_data = init[trex].to_vec();
+  set_u32(&mut trex_data, 12, track_id)?;
+  Ok((trak_data, trex_data))
+}
+
+// 把多个轨道的初始化分片合并为一个 moov
+fn merge_init(inits: &[Vec<u8>]) -> BzResult<(Vec<u8>, Vec<u8>)> {
+  let first = &inits[0];
+  let top = boxes(first, 0..first.len());
+  let ftyp = find_box(&top, b"ftyp")
+    .map(|ftyp| first[ftyp.range.clone()].to_vec())
+    .unwrap_or_default();
+  let moov = find_box(&top, b"moov")
+    .ok_or_else(|| remux_error("moov not found in init segment".to_string()))?;
+  let mut mvhd = find_box(&boxes(first, moov.content.clone()), b"mvhd")
+    .map(|mvhd| first[mvhd.range.clone()].to_vec())
+    .ok_or_else(|| remux_error("mvhd not found in init segment".to_string()))?;
+  // next_track_ID 在 mvhd 的最后
+  let length = mvhd.len();
+  set_u32(&mut mvhd, length - 4, inits.len() as u32 + 1)?;
+
+  let mut content = mvhd;
+  let mut mvex = Vec::new();
+  for (index, init) in inits.iter().enumerate() {
+    let (trak, trex) = init_track(init, index as u32 + 1)?;
+    content.extend(trak);
+    mvex.extend(trex);
+  }
+  write_box(&mut content, b"mvex", &mvex);
+  let mut moov = Vec::new();
+  write_box(&mut moov, b"moov", &content);
+  Ok((ftyp, moov))
+}
+
+// 重新生成 moof 修改 tfhd 中的 track_ID
+// base_data_offset 相对于远程文件 去掉之后改为相对于 moof
+// trun 的 data_offset 按照新的 moof 大小调整 mdat 紧跟在 moof 之后
+// moof_offset 为 moof 在远程文件中的位置
+fn rewrite_moof(
+  data: &[u8], moof: &Mp4Box, moof_offset: u64, track_id: u32,
+) -> BzResult<Vec<u8>> {
+  let mut content = Vec::new();
+  // data_offset 在新的 content 中的位置 和相对于原来 moof 开始的偏移
+  let mut data_offsets: Vec<(usize, i64)> = Vec::new();
+  for child in boxes(data, moof.content.clone()) {
+    if &child.name != b"traf" {
+      content.extend_from_slice(&data[child.range.clone()]);
+      continue;
+    }
+    let mut traf = Vec::new();
+    let mut traf_offsets = Vec::new();
+    // tfhd 中的 base_data_offset 相对于原来 moof 的位置
+    let mut base: Option<i64> = None;
+    let mut first_run = true;
+    for item in boxes(data, child.content.clone()) {
+      let body = &data[item.content.clone()];
+      match &item.name {
+        b"tfhd" => {
+          // version/flags 和 track_ID
+          read_u32(body, 4)?;
+          let mut flags = read_u32(body, 0)?;
+          let mut tfhd = body.to_vec();
+          set_u32(&mut tfhd, 4, track_id)?;
+          if flags & BASE_DATA_OFFSET_PRESENT != 0 {
+            let value = read_u64(body, 8)?;
+            let relative = value.checked_sub(moof_offset).ok_or_else(|| {
+              remux_error(format!(
+                "base_data_offset {} before moof at {}",
+                value, moof_offset
+              ))
+            })?;
+            base = Some(relative as i64);
+            tfhd.drain(8..16);
+            flags = (flags & !BASE_DATA_OFFSET_PRESENT) | DEFAULT_BASE_IS_MOOF;
+            set_u32(&mut tfhd, 0, flags)?;
+          }
+          write_box(&mut traf, b"tfhd", &tfhd);
+        }
+        b"trun" => {
+          // version/flags 和 sample_count
+          read_u32(body, 4)?;
+          let flags = read_u32(body, 0)?;
+          let mut trun = body.to_vec();
+          let offset = match flags & DATA_OFFSET_PRESENT {
+            0 => None,
+            _ => Some(read_u32(body, 8)? as i32 as i64),
+          };
+          // 没有 data_offset 的第一个 trun 从 base_data_offset 开始
+          // 之后的 trun 接在前一个之后 不需要修改
+          let relative = match (offset, base) {
+            (Some(offset), base) => Some(base.unwrap_or_default() + offset),
+            (None, Some(base)) if first_run => {
+              trun.splice(8..8, [0; 4]);
+              set_u32(&mut trun, 0, flags | DATA_OFFSET_PRESENT)?;
+              Some(base)
+            }
+            (None, _) => None,
+          };
+          if let Some(relative) = relative {
+            // 盒子头 8 字节 version/flags 和 sample_count 8 字节
+            traf_offsets.push((traf.len() + 16, relative));
+          }
+          first_run = false;
+          write_box(&mut traf, b"trun", &trun);
+        }
+        _ => traf.extend_from_slice(&data[item.range.clone()]),
+      }
+    }
+    data_offsets.extend(
+      traf_offsets
+        .into_iter()
+        .map(|(position, relative)| (content.len() + 8 + position, relative)),
+    );
+    write_box(&mut content, b"traf", &traf);
+  }
+  let mut output = Vec::new();
+  write_box(&mut output, b"moof", &content);
+  let delta = output.len() as i64 - moof.range.len() as i64;
+  for (position, relative) in data_offsets {
+    let value = u32::try_from(relative + delta)
+      .map_err(|_| remux_error(format!("invalid data_offset {}", relative)))?;
+    set_u32(&mut output, position + 8, value)?;
+  }
+  Ok(output)
+}
+
+// 只保留 moof 和 mdat
+// segment_offset 为分片在远程文件中的位置
+fn write_fragments(
+  output: &mut impl Write, data: &[u8], segment_offset: u64, track_id: u32,
+) -> BzResult<()> {
+  for item in boxes(data, 0..data.len()) {
+    match &item.name {
+      b"moof" => {
+        let moof_offset = segment_offset + item.range.start as u64;
+        let moof = rewrite_moof(data, &item, moof_offset, track_id)?;
+        output.write_all(&moof)?;
+      }
+      b"mdat" => {
+        output.write_all(&data[item.range.clone()])?;
+      }
+      _ => {}
+    }
+  }
+  Ok(())
+}
+
+// 把多条 fMP4 轨道合并为一个文件 分片按照开始时间交错排列
+pub fn mux_fragmented(tracks: &[FragmentedTrack], dest: &Path) -> BzResult<()> {
+  if tracks.is_empty() {
+    return Err(remux_error("no track to mux".to_string()));
+  }
+  let inits = tracks
+    .iter()
+    .map(|track| std::fs::read(&track.init))
+    .collect::<Result<Vec<_>, _>>()?;
+  let (ftyp, moov) = merge_init(&inits)?;
+
+  let mut segments: Vec<(u32, &FragmentedSegment)> = tracks
+    .iter()
+    .enumerate()
+    .flat_map(|(index, track)| {
+      track
+        .segments
+        .iter()
+        .map(move |segment| (index as u32 + 1, segment))
+    })
+    .collect();
+  segments.sort_by(|a, b| a.1.time.total_cmp(&b.1.time).then(a.0.cmp(&b.0)));
+
+  let mut output = BufWriter::new(File::create(dest)?);
+  output.write_all(&ftyp)?;
+  output.write_all(&moov)?;
+  for (track_id, segment) in segments {
+    let data = std::fs::read(&segment.path)?;
+    write_fragments(&mut output, &data, segment.offset, track_id)?;
+  }
+  output.flush()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn init_segment(track_id: u32) -> Vec<u8> {
+    let mut tkhd = vec![0; 84];
+    set_u32(&mut tkhd, 12, track_id).unwrap();
+    let mut trak = Vec::new();
+    write_box(&mut trak, b"tkhd", &tkhd);
+    let mut trex = vec![0; 24];
+    set_u32(&mut trex, 4, track_id).unwrap();
+    let mut mvex = Vec::new();
+    write_box(&mut mvex, b"trex", &trex);
+    let mut moov = Vec::new();
+    write_box(&mut moov, b"mvhd", &[0; 100]);
+    write_box(&mut moov, b"trak", &trak);
+    write_box(&mut moov, b"mvex", &mvex);
+    let mut init = Vec::new();
+    write_box(&mut init, b"ftyp", b"iso6");
+    write_box(&mut init, b"moov", &moov);
+    init
+  }
+
+  // 一个样本的分片 base 为 Some 时使用 base_data_offset
+  fn media_segment(
+    track_id: u32, base: Option<u64>, payload: &[u8],
+  ) -> Vec<u8> {
+    let build = |data_offset: u32| {
+      let mut tfhd = match base {
+        Some(base) => {
+          let mut tfhd = vec![0, 0, 0, 1, 0, 0, 0, 0];
+          tfhd.extend_from_slice(&base.to_be_bytes());
+          tfhd
+        }
+        None => vec![0, 2, 0, 0, 0, 0, 0, 0],
+      };
+      set_u32(&mut tfhd, 4, track_id).unwrap();
+      let mut trun = vec![0, 0, 0, 1, 0, 0, 0, 1];
+      trun.extend_from_slice(&data_offset.to_be_bytes());
+      let mut traf = Vec::new();
+      write_box(&mut traf, b"tfhd", &tfhd);
+      write_box(&mut traf, b"trun", &trun);
+      let mut moof = Vec::new();
+      write_box(&mut moof, b"mfhd", &[0; 8]);
+      write_box(&mut moof, b"traf", &traf);
+      let mut segment = Vec::new();
+      write_box(&mut segment, b"moof", &moof);
+      segment
+    };
+    // 数据在 mdat 的开头
+    let moof_len = build(0).len() as u32;
+    let mut segment = build(moof_len + 8);
+    write_box(&mut segment, b"mdat", payload);
+    segment
+  }
+
+  #[test]
+  fn test_mux_fragmented() {
+    let dir =
+      std::env::temp_dir().join(format!("bz_fmp4_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, data: Vec<u8>| {
+      let path = dir.join(name);
+      std::fs::write(&path, data).unwrap();
+      path
+    };
+    // 视频使用相对于远程文件的 base_data_offset 音频相对于 moof
+    let remote_offset = 5000;
+    let tracks = [
+      FragmentedTrack {
+        init: write("video.init", init_segment(7)),
+        segments: vec![FragmentedSegment {
+          time: 0.0,
+          path: write(
+            "video.seg",
+            media_segment(7, Some(remote_offset), b"video"),
+          ),
+          offset: remote_offset,
+        }],
+      },
+      FragmentedTrack {
+        init: write("audio.init", init_segment(3)),
+        segments: vec![FragmentedSegment {
+          time: 0.0,
+          path: write("audio.seg", media_segment(3, None, b"audio")),
+          offset: 0,
+        }],
+      },
+    ];
+    let dest = dir.join("out.mp4");
+    mux_fragmented(&tracks, &dest).unwrap();
+
+    let data = std::fs::read(&dest).unwrap();
+    let top = boxes(&data, 0..data.len());
+    let names: Vec<_> = top.iter().map(|item| &item.name).collect();
+    assert_eq!(
+      names,
+      [b"ftyp", b"moov", b"moof", b"mdat", b"moof", b"mdat"]
+    );
+    let moov = boxes(&data, top[1].content.clone());
+    assert_eq!(moov.iter().filter(|item| &item.name == b"trak").count(), 2);
+    for (moof, (track_id, payload)) in [&top[2], &top[4]]
+      .into_iter()
+      .zip([(1, b"video"), (2, b"audio")])
+    {
+      let traf = find_box(&boxes(&data, moof.content.clone()), b"traf")
+        .unwrap()
+        .content
+        .clone();
+      let children = boxes(&data, traf);
+      let tfhd = find_box(&children, b"tfhd").unwrap().content.clone();
+      let flags = read_u32(&data, tfhd.start).unwrap();
+      assert_eq!(flags & BASE_DATA_OFFSET_PRESENT, 0);
+      assert_ne!(flags & DEFAULT_BASE_IS_MOOF, 0);
+      assert_eq!(read_u32(&data, tfhd.start + 4).unwrap(), track_id);
+      let trun = find_box(&children, b"trun").unwrap().content.clone();
+      let offset = read_u32(&data, trun.start + 8).unwrap() as usize;
+      let start = moof.range.start + offset;
+      assert_eq!(&data[start..start + 5], payload);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_malformed_boxes() {
+    // largesize 溢出或小于盒子头时停止解析
+    for large in [8, u64::MAX - 15, u64::MAX] {
+      let mut data = vec![0, 0, 0, 1];
+      data.extend_from_slice(b"mdat");
+      data.extend_from_slice(&large.to_be_bytes());
+      assert!(boxes(&data, 0..data.len()).is_empty());
+    }
+    // tkhd 太短时返回错误而不是 panic
+    let mut trak = Vec::new();
+    write_box(&mut trak, b"tkhd", &[0; 8]);
+    let mut moov = Vec::new();
+    write_box(&mut moov, b"trak", &trak);
+    let mut init = Vec::new();
+    write_box(&mut init, b"moov", &moov);
+    assert!(init_track(&init, 1).is_err());
+  }
+}
@@ -0,0 +1,505 @@
+mod codec;
+mod fmp4;
+mod mp4;
+mod ts;
+mod webvtt;
+
+pub use fmp4::{FragmentedSegment, FragmentedTrack, mux_fragmented};
+pub use ts::TS_PACKET_SIZE;
+pub use webvtt::merge_webvtt;
+
+use std::path::{Path, PathBuf};
+
+use crate::error::{BzError, BzResult};
+use codec::{
+  avc_decoder_config, hevc_decoder_config, split_adts, split_annex_b,
+};
+use mp4::{Mp4Track, Mp4Writer, TS_TIMESCALE, TrackConfig};
+use ts::{Pes, StreamKind, TsDemuxer};
+
+// 转换的输入 按播放顺序排列
+#[derive(Debug, Clone)]
+pub struct TsInput {
+  pub path: PathBuf,
+  // 对应 EXT-X-DISCONTINUITY 时间戳需要重新计算
+  pub discontinuity: bool,
+}
+
+const WRAP: i64 = 1 << 33;
+// 相邻分片的时间戳相差超过10秒 即使没有标记也视为不连续
+const MAX_GAP: i64 = 10 * TS_TIMESCALE as i64;
+const VIDEO_TRACK_ID: u32 = 1;
+const AUDIO_TRACK_ID: u32 = 2;
+
+struct Remuxer {
+  writer: Mp4Writer,
+  demuxer: TsDemuxer,
+  video_kind: Option<StreamKind>,
+  video: Option<Mp4Track>,
+  audio: Option<Mp4Track>,
+  // 最近的一组参数集 变化后在下一个关键帧增加新的 sample entry
+  vps: Option<Vec<u8>>,
+  sps: Option<Vec<u8>>,
+  pps: Option<Vec<u8>>,
+  parameters_changed: bool,
+  // 输入时间戳到输出时间轴的偏移 单位 90kHz
+  offset: Option<i64>,
+  // 处理33位时间戳回绕
+  wrap: i64,
+  last_timestamp: Option<i64>,
+  // 已经写入的结束时间
+  video_end: i64,
+  audio_end: i64,
+  last_video_dts: Option<i64>,
+  video_duration: i64,
+}
+
+impl Remuxer {
+  fn new(dest: &Path) -> BzResult<Self> {
+    Ok(Self {
+      writer: Mp4Writer::create(dest)?,
+      demuxer: TsDemuxer::default(),
+      video_kind: None,
+      video: None,
+      audio: None,
+      vps: None,
+      sps: None,
+      pps: None,
+      parameters_changed: false,
+      offset: None,
+      wrap: 0,
+      last_timestamp: None,
+      video_end: 0,
+      audio_end: 0,
+      last_video_dts: None,
+      video_duration: TS_TIMESCALE as i64 / 25,
+    })
+  }
+
+  fn unwrap_timestamp(&mut self, timestamp: u64) -> i64 {
+    let mut value = timestamp as i64 + self.wrap;
+    if let Some(last) = self.last_timestamp {
+      if value < last - WRAP / 2 {
+        self.wrap += WRAP;
+        value += WRAP;
+      } else if value > last + WRAP / 2 {
+        // 回绕之后才到达的旧时间戳
+        value -= WRAP;
+      }
+    }
+    self.last_timestamp = Some(value);
+    value
+  }
+
+  fn push_segment(&mut self, input: &TsInput) -> BzResult<()> {
+    let data = std::fs::read(&input.path)?;
+    let mut packets = Vec::new();
+    self.demuxer.push(&data, &mut packets);
+    self.demuxer.flush(&mut packets);
+    if let Some((stream_type, name)) = self.demuxer.unsupported_media {
+      return Err(BzError::Remux {
+        reason: format!(
+          "unsupported stream {} ({:#04x}), choose TS container to keep it",
+          name, stream_type
+        ),
+      });
+    }
+
+    if input.discontinuity {
+      self.wrap = 0;
+      self.last_timestamp = None;
+    }
+    let packets: Vec<(Pes, Option<i64>, Option<i64>)> = packets
+      .into_iter()
+      .map(|pes| {
+        let pts = pes.pts.map(|pts| self.unwrap_timestamp(pts));
+        let dts = pes.dts.map(|dts| self.unwrap_timestamp(dts));
+        (pes, pts, dts)
+      })
+      .collect();
+    let Some(first) = packets
+      .iter()
+      .filter_map(|(_, pts, dts)| dts.or(*pts))
+      .min()
+    else {
+      return Ok(());
+    };
+
+    // 分片的第一个时间戳接在已经写入的内容之后
+    let expected = self.video_end.max(self.audio_end);
+    self.offset = match self.offset {
+      None => Some(-first),
+      Some(offset)
+        if input.discontinuity
+          || (first + offset - expected).abs() > MAX_GAP =>
+      {
+        log::info!("timestamp discontinuity at {}", input.path.display());
+        Some(expected - first)
+      }
+      offset => offset,
+    };
+    let offset = self.offset.unwrap_or_default();
+
+    for (pes, pts, dts) in packets {
+      let pts = pts.map(|pts| pts + offset);
+      let dts = dts.map(|dts| dts + offset);
+      match pes.kind {
+        StreamKind::Aac => self.push_audio(pts, &pes.data)?,
+        kind => self.push_video(kind, pts, dts, &pes.data)?,
+      }
+    }
+    Ok(())
+  }
+
+  fn push_video(
+    &mut self, kind: StreamKind, pts: Option<i64>, dts: Option<i64>,
+    data: &[u8],
+  ) -> BzResult<()> {
+    if *self.video_kind.get_or_insert(kind) != kind {
+      return Ok(());
+    }
+    let mut sample = Vec::with_capacity(data.len());
+    let mut keyframe = false;
+    for nal in split_annex_b(data) {
+      let nal_type = match kind {
+        StreamKind::H265 => (nal[0] >> 1) & 0x3F,
+        _ => nal[0] & 0x1F,
+      };
+      let parameter_set = match (kind, nal_type) {
+        (StreamKind::H265, 32) => Some(&mut self.vps),
+        (StreamKind::H264, 7) | (StreamKind::H265, 33) => Some(&mut self.sps),
+        (StreamKind::H264, 8) | (StreamKind::H265, 34) => Some(&mut self.pps),
+        _ => None,
+      };
+      if let Some(parameter_set) = parameter_set {
+        if parameter_set.as_deref() != Some(nal) {
+          *parameter_set = Some(nal.to_vec());
+          self.parameters_changed = true;
+        }
+        continue;
+      }
+      match (kind, nal_type) {
+        // AUD
+        (StreamKind::H264, 9) | (StreamKind::H265, 35) => continue,
+        (StreamKind::H264, 5) | (StreamKind::H265, 16..=21) => keyframe = true,
+        _ => {}
+      }
+      sample.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+      sample.extend_from_slice(nal);
+    }
+    if sample.is_empty() {
+      return Ok(());
+    }
+
+    // 从第一个关键帧开始 之前的帧无法解码
+    if self.video.is_none() && !keyframe {
+      return Ok(());
+    }
+    // 不连续或者切换码流之后分辨率等参数可能变化
+    if keyframe && (self.video.is_none() || self.parameters_changed) {
+      let config = match (kind, &self.vps, &self.sps, &self.pps) {
+        (StreamKind::H264, _, Some(sps), Some(pps)) => {
+          let (config, size) = avc_decoder_config(sps, pps)?;
+          TrackConfig::Avc {
+            width: size.width,
+            height: size.height,
+            config,
+          }
+        }
+        (StreamKind::H265, Some(vps), Some(sps), Some(pps)) => {
+          let (config, size) = hevc_decoder_config(vps, sps, pps)?;
+          TrackConfig::Hevc {
+            width: size.width,
+            height: size.height,
+            config,
+          }
+        }
+        _ => return Ok(()),
+      };
+      self.parameters_changed = false;
+      self
+        .video
+        .get_or_insert_with(|| Mp4Track::new(VIDEO_TRACK_ID, TS_TIMESCALE))
+        .set_config(config);
+    }
+
+    let dts = dts.or(pts).unwrap_or_else(|| {
+      self.last_video_dts.unwrap_or_default() + self.video_duration
+    });
+    let pts = pts.unwrap_or(dts);
+    if let Some(last) = self.last_video_dts.filter(|last| dts > *last) {
+      self.video_duration = dts - last;
+    }
+    self.last_video_dts = Some(dts);
+    self.video_end = dts + self.video_duration;
+
+    let track = self.video.as_mut().unwrap();
+    if track.is_empty() {
+      track.start = pts.max(0) as u64;
+    }
+    self
+      .writer
+      .write_sample(track, &sample, dts, pts - dts, keyframe)
+  }
+
+  fn push_audio(&mut self, pts: Option<i64>, data: &[u8]) -> BzResult<()> {
+    let mut pts = pts.unwrap_or(self.audio_end);
+    for (header, payload) in split_adts(data) {
+      let sample_rate = header.sample_rate();
+      let track = self.audio.get_or_insert_with(|| {
+        let mut track = Mp4Track::new(AUDIO_TRACK_ID, sample_rate);
+        track.set_config(TrackConfig::Aac {
+          sample_rate,
+          channels: header.channels as u16,
+          config: header.audio_specific_config(),
+        });
+        track.start = pts.max(0) as u64;
+        track
+      });
+      // 采样率以第一帧为准
+      let timescale = track.timescale as i64;
+      let dts = pts * timescale / TS_TIMESCALE as i64;
+      self.writer.write_sample(track, payload, dts, 0, true)?;
+      pts += 1024 * TS_TIMESCALE as i64 / timescale;
+      self.audio_end = pts;
+    }
+    Ok(())
+  }
+
+  fn finish(self) -> BzResult<()> {
+    let tracks: Vec<&Mp4Track> =
+      self.video.iter().chain(self.audio.iter()).collect();
+    if tracks.is_empty() {
+      return Err(BzError::Remux {
+        reason: "no supported audio or video stream".to_string(),
+      });
+    }
+    for track in &tracks {
+      log::info!("remux track {} samples: {}", track.id, track.sample_count());
+    }
+    self.writer.finish(&tracks)
+  }
+}
+
+// 把 TS 分片转换为一个 MP4 文件 支持 H.264/H.265 和 AAC
+pub fn remux_ts_to_mp4(inputs: &[TsInput], dest: &Path) -> BzResult<()> {
+  let mut remuxer = Remuxer::new(dest)?;
+  for input in inputs {
+    remuxer.push_segment(input)?;
+  }
+  remuxer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const VIDEO_PID: u16 = 0x100;
+  const AUDIO_PID: u16 = 0x101;
+
+  fn packets(pid: u16, payload: &[u8], output: &mut Vec<u8>) {
+    for (index, chunk) in payload.chunks(184).enumerate() {
+      let start = if index == 0 { 0x40 } else { 0 };
+      output.extend_from_slice(&[0x47, start | (pid >> 8) as u8, pid as u8]);
+      match chunk.len() {
+        184 => output.push(0x10),
+        // 不足的部分用 adaptation field 填充
+        len => {
+          output.push(0x30);
+          let stuffing = 183 - len;
+          output.push(stuffing as u8);
+          if stuffing > 0 {
+            output.push(0);
+            output.extend(std::iter::repeat_n(0xFF, stuffing - 1));
+          }
+        }
+      }
+      output.extend_from_slice(chunk);
+    }
+  }
+
+  fn timestamp(prefix: u8, value: u64) -> [u8; 5] {
+    [
+      prefix << 4 | ((value >> 29) & 0x0E) as u8 | 1,
+      (value >> 22) as u8,
+      ((value >> 14) & 0xFE) as u8 | 1,
+      (value >> 7) as u8,
+      ((value << 1) & 0xFE) as u8 | 1,
+    ]
+  }
+
+  fn pes(stream_id: u8, pts: u64, data: &[u8]) -> Vec<u8> {
+    let mut pes = vec![0, 0, 1, stream_id, 0, 0, 0x80, 0x80, 5];
+    pes.extend_from_slice(&timestamp(2, pts));
+    pes.extend_from_slice(data);
+    pes
+  }
+
+  fn segment(start: u64) -> Vec<u8> {
+    segment_with_audio(start, 0x0F)
+  }
+
+  // 每个分片两帧视频 一帧音频 起始时间戳为 start
+  fn segment_with_audio(start: u64, audio_type: u8) -> Vec<u8> {
+    let mut output = Vec::new();
+    packets(
+      0,
+      &[0, 0, 0, 0x0D, 0, 1, 0, 0, 0, 0, 1, 0xF0, 0, 0, 0, 0, 0],
+      &mut output,
+    );
+    let pmt = [
+      0, 2, 0xB0, 0x17, 0, 1, 0, 0, 0, 0xE1, 0x00, 0xF0, 0, 0x1B, 0xE1, 0x00,
+      0xF0, 0, audio_type, 0xE1, 0x01, 0xF0, 0, 0, 0, 0, 0,
+    ];
+    packets(0x1000, &pmt, &mut output);
+    let mut keyframe = vec![0, 0, 0, 1];
+    keyframe.extend(codec::tests::avc_sps());
+    keyframe.extend_from_slice(&[0, 0, 0, 1, 0x68, 0xCE, 0x38, 0x80]);
+    keyframe.extend_from_slice(&[0, 0, 0, 1, 0x65]);
+    keyframe.extend(std::iter::repeat_n(0xAB, 300));
+    packets(VIDEO_PID, &pes(0xE0, start, &keyframe), &mut output);
+    packets(
+      VIDEO_PID,
+      &pes(0xE0, start + 3600, &[0, 0, 1, 0x41, 0x9A]),
+      &mut output,
+    );
+    let adts = [0xFF, 0xF1, 0x50, 0x80, 0x01, 0x3F, 0xFC, 0xAA, 0xBB];
+    packets(AUDIO_PID, &pes(0xC0, start, &adts), &mut output);
+    output
+  }
+
+  fn find_box<'a>(data: &'a [u8], name: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut position = 0;
+    while position + 8 <= data.len() {
+      let mut size =
+        u32::from_be_bytes(data[position..position + 4].try_into().unwrap())
+          as usize;
+      let header = if size == 1 {
+        size = u64::from_be_bytes(
+          data[position + 8..position + 16].try_into().unwrap(),
+        ) as usize;
+        16
+      } else {
+        8
+      };
+      if &data[position + 4..position + 8] == name {
+        return data.get(position + header..position + size);
+      }
+      position += size;
+    }
+    None
+  }
+
+  #[test]
+  fn test_remux_ts_to_mp4() {
+    let dir =
+      std::env::temp_dir().join(format!("bz_remux_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut inputs = Vec::new();
+    // 第二个分片接着第一个 第三个分片时间戳重新开始
+    for (index, (start, discontinuity)) in
+      [(900000, false), (907200, false), (0, true)]
+        .into_iter()
+        .enumerate()
+    {
+      let path = dir.join(format!("{}.seg", index));
+      std::fs::write(&path, segment(start)).unwrap();
+      inputs.push(TsInput {
+        path,
+        discontinuity,
+      });
+    }
+    let dest = dir.join("out.mp4");
+    remux_ts_to_mp4(&inputs, &dest).unwrap();
+
+    let data = std::fs::read(&dest).unwrap();
+    assert!(find_box(&data, b"ftyp").is_some());
+    let mdat = find_box(&data, b"mdat").unwrap();
+    // 参数集不写入样本 每个分片 IDR 305 + P帧 6 + 音频 2
+    assert_eq!(mdat.len(), 3 * (305 + 6 + 2));
+    let moov = find_box(&data, b"moov").unwrap();
+    let trak = find_box(moov, b"trak").unwrap();
+    let stbl = find_box(
+      find_box(find_box(trak, b"mdia").unwrap(), b"minf").unwrap(),
+      b"stbl",
+    )
+    .unwrap();
+    assert!(
+      find_box(find_box(stbl, b"stsd").unwrap().get(8..).unwrap(), b"avc1")
+        .is_some()
+    );
+    // 不连续处的帧时长和前面一致
+    let stts = find_box(stbl, b"stts").unwrap();
+    assert_eq!(&stts[4..], &[0, 0, 0, 1, 0, 0, 0, 6, 0, 0, 0x0E, 0x10]);
+    let stss = find_box(stbl, b"stss").unwrap();
+    assert_eq!(&stss[4..8], &[0, 0, 0, 3]);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_remux_parameter_sets_change() {
+    let dir =
+      std::env::temp_dir().join(format!("bz_remux_pps_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut inputs = Vec::new();
+    for (index, start) in [0, 7200, 14400].into_iter().enumerate() {
+      let mut data = segment(start);
+      // 第二个分片开始切换到新的 PPS
+      if index > 0 {
+        let pps = [0x68, 0xCE, 0x38, 0x80];
+        let position =
+          data.windows(4).position(|window| window == pps).unwrap();
+        data[position + 2] = 0x3C;
+      }
+      let path = dir.join(format!("{}.seg", index));
+      std::fs::write(&path, data).unwrap();
+      inputs.push(TsInput {
+        path,
+        discontinuity: false,
+      });
+    }
+    let dest = dir.join("out.mp4");
+    remux_ts_to_mp4(&inputs, &dest).unwrap();
+
+    let data = std::fs::read(&dest).unwrap();
+    let moov = find_box(&data, b"moov").unwrap();
+    let trak = find_box(moov, b"trak").unwrap();
+    let stbl = find_box(
+      find_box(find_box(trak, b"mdia").unwrap(), b"minf").unwrap(),
+      b"stbl",
+    )
+    .unwrap();
+    let stsd = find_box(stbl, b"stsd").unwrap();
+    assert_eq!(&stsd[4..8], &[0, 0, 0, 2]);
+    // 前两个样本使用第一个 entry 之后的样本使用第二个
+    let stsc = find_box(stbl, b"stsc").unwrap();
+    assert_eq!(
+      &stsc[4..],
+      &[
+        0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 1,
+        0, 0, 0, 2
+      ]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_remux_unsupported_audio() {
+    let dir =
+      std::env::temp_dir().join(format!("bz_remux_mp3_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("0.seg");
+    // PMT 中的音频为 MP3 不能静默丢弃
+    std::fs::write(&path, segment_with_audio(0, 0x03)).unwrap();
+    let inputs = [TsInput {
+      path,
+      discontinuity: false,
+    }];
+    let res = remux_ts_to_mp4(&inputs, &dir.join("out.mp4"));
+    assert!(
+      matches!(&res, Err(BzError::Remux { reason }) if reason.contains("MPEG-1 Audio")),
+      "{res:?}"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
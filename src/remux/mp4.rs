@@ -0,0 +1,478 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::error::BzResult;
+
+// moov 中使用的时间单位
+const MOVIE_TIMESCALE: u32 = 1000;
+// PES 时间戳的单位
+pub const TS_TIMESCALE: u32 = 90000;
+
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrackConfig {
+  Avc {
+    width: u32,
+    height: u32,
+    config: Vec<u8>,
+  },
+  Hevc {
+    width: u32,
+    height: u32,
+    config: Vec<u8>,
+  },
+  Aac {
+    sample_rate: u32,
+    channels: u16,
+    config: [u8; 2],
+  },
+}
+
+#[derive(Debug, Clone)]
+struct Mp4Sample {
+  offset: u64,
+  size: u32,
+  // 解码时间 单位为轨道的 timescale
+  dts: i64,
+  // pts - dts
+  cts: i64,
+  keyframe: bool,
+  // 使用的 sample entry 从1开始
+  description: u32,
+}
+
+#[derive(Debug)]
+pub struct Mp4Track {
+  pub id: u32,
+  pub timescale: u32,
+  // 参数集变化时增加新的 sample entry 之后的样本使用新的 entry
+  configs: Vec<TrackConfig>,
+  // 第一帧的显示时间 单位 90kHz 用于生成 edit list 对齐音视频
+  pub start: u64,
+  samples: Vec<Mp4Sample>,
+}
+
+impl Mp4Track {
+  pub fn new(id: u32, timescale: u32) -> Self {
+    Self {
+      id,
+      timescale,
+      configs: Vec::new(),
+      start: 0,
+      samples: Vec::new(),
+    }
+  }
+
+  pub fn set_config(&mut self, config: TrackConfig) {
+    if self.configs.last() != Some(&config) {
+      self.configs.push(config);
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.samples.is_empty()
+  }
+
+  pub fn sample_count(&self) -> usize {
+    self.samples.len()
+  }
+
+  fn is_video(&self) -> bool {
+    !matches!(self.configs.first(), Some(TrackConfig::Aac { .. }))
+  }
+
+  // 每个样本的时长 最后一个样本沿用前一个的时长
+  fn durations(&self) -> Vec<u32> {
+    let mut durations: Vec<u32> = self
+      .samples
+      .windows(2)
+      .map(|pair| (pair[1].dts - pair[0].dts).clamp(0, u32::MAX as i64) as u32)
+      .collect();
+    let last = durations.last().copied().unwrap_or(match self.is_video() {
+      true => self.timescale / 25,
+      false => 1024,
+    });
+    durations.push(last);
+    durations
+  }
+}
+
+fn write_box(
+  buf: &mut Vec<u8>, name: &[u8; 4], content: impl FnOnce(&mut Vec<u8>),
+) {
+  let start = buf.len();
+  buf.extend_from_slice(&[0; 4]);
+  buf.extend_from_slice(name);
+  content(buf);
+  let size = (buf.len() - start) as u32;
+  buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(
+  buf: &mut Vec<u8>, name: &[u8; 4], version: u8, flags: u32,
+  content: impl FnOnce(&mut Vec<u8>),
+) {
+  write_box(buf, name, |buf| {
+    buf.extend_from_slice(&((version as u32) << 24 | flags).to_be_bytes());
+    content(buf);
+  });
+}
+
+trait Put {
+  fn u16(&mut self, value: u16);
+  fn u32(&mut self, value: u32);
+  fn u64(&mut self, value: u64);
+}
+
+impl Put for Vec<u8> {
+  fn u16(&mut self, value: u16) {
+    self.extend_from_slice(&value.to_be_bytes());
+  }
+  fn u32(&mut self, value: u32) {
+    self.extend_from_slice(&value.to_be_bytes());
+  }
+  fn u64(&mut self, value: u64) {
+    self.extend_from_slice(&value.to_be_bytes());
+  }
+}
+
+// moov 放在文件末尾 样本数据边写边记录位置
+pub struct Mp4Writer {
+  file: BufWriter<File>,
+  position: u64,
+  mdat_start: u64,
+}
+
+impl Mp4Writer {
+  pub fn create(path: &Path) -> BzResult<Self> {
+    let mut head = Vec::new();
+    write_box(&mut head, b"ftyp", |buf| {
+      buf.extend_from_slice(b"isom");
+      buf.u32(0x200);
+      buf.extend_from_slice(b"isomiso2avc1mp41");
+    });
+    let mdat_start = head.len() as u64;
+    // mdat 使用64位长度 结束时回填
+    head.u32(1);
+    head.extend_from_slice(b"mdat");
+    head.u64(0);
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&head)?;
+    Ok(Self {
+      file,
+      position: head.len() as u64,
+      mdat_start,
+    })
+  }
+
+  pub fn write_sample(
+    &mut self, track: &mut Mp4Track, data: &[u8], dts: i64, cts: i64,
+    keyframe: bool,
+  ) -> BzResult<()> {
+    self.file.write_all(data)?;
+    track.samples.push(Mp4Sample {
+      offset: self.position,
+      size: data.len() as u32,
+      dts,
+      cts,
+      keyframe,
+      description: track.configs.len() as u32,
+    });
+    self.position += data.len() as u64;
+    Ok(())
+  }
+
+  pub fn finish(mut self, tracks: &[&Mp4Track]) -> BzResult<()> {
+    let mdat_size = self.position - self.mdat_start;
+    self.file.seek(SeekFrom::Start(self.mdat_start + 8))?;
+    self.file.write_all(&mdat_size.to_be_bytes())?;
+    self.file.seek(SeekFrom::End(0))?;
+    let mut moov = Vec::new();
+    write_moov(&mut moov, tracks);
+    self.file.write_all(&moov)?;
+    self.file.flush()?;
+    Ok(())
+  }
+}
+
+fn to_movie_time(value: u64, timescale: u32) -> u64 {
+  value * MOVIE_TIMESCALE as u64 / timescale as u64
+}
+
+fn write_moov(buf: &mut Vec<u8>, tracks: &[&Mp4Track]) {
+  let tracks: Vec<_> = tracks
+    .iter()
+    .filter(|track| !track.configs.is_empty() && !track.is_empty())
+    .collect();
+  let duration = tracks
+    .iter()
+    .map(|track| {
+      let media: u64 = track.durations().iter().map(|&d| d as u64).sum();
+      to_movie_time(track.start, TS_TIMESCALE)
+        + to_movie_time(media, track.timescale)
+    })
+    .max()
+    .unwrap_or_default();
+  let next_track_id =
+    tracks.iter().map(|track| track.id).max().unwrap_or(0) + 1;
+  write_box(buf, b"moov", |buf| {
+    write_full_box(buf, b"mvhd", 1, 0, |buf| {
+      buf.u64(0);
+      buf.u64(0);
+      buf.u32(MOVIE_TIMESCALE);
+      buf.u64(duration);
+      buf.u32(0x10000);
+      buf.u16(0x100);
+      buf.extend_from_slice(&[0; 10]);
+      MATRIX.iter().for_each(|&value| buf.u32(value));
+      buf.extend_from_slice(&[0; 24]);
+      buf.u32(next_track_id);
+    });
+    for track in tracks {
+      write_trak(buf, track);
+    }
+  });
+}
+
+fn write_trak(buf: &mut Vec<u8>, track: &Mp4Track) {
+  let config = &track.configs[0];
+  let durations = track.durations();
+  let media_duration: u64 = durations.iter().map(|&d| d as u64).sum();
+  let (width, height) = match config {
+    TrackConfig::Avc { width, height, .. }
+    | TrackConfig::Hevc { width, height, .. } => (*width, *height),
+    TrackConfig::Aac { .. } => (0, 0),
+  };
+  // 第一帧在 media 时间轴上的显示时间
+  let media_start = track.samples[0].cts.max(0) as u64;
+  let empty = to_movie_time(track.start, TS_TIMESCALE);
+  let shown = to_movie_time(
+    media_duration - media_start.min(media_duration),
+    track.timescale,
+  );
+  write_box(buf, b"trak", |buf| {
+    write_full_box(buf, b"tkhd", 1, 3, |buf| {
+      buf.u64(0);
+      buf.u64(0);
+      buf.u32(track.id);
+      buf.u32(0);
+      buf.u64(empty + shown);
+      buf.extend_from_slice(&[0; 8]);
+      buf.u16(0);
+      buf.u16(0);
+      buf.u16(if track.is_video() { 0 } else { 0x100 });
+      buf.u16(0);
+      MATRIX.iter().for_each(|&value| buf.u32(value));
+      buf.u32(width << 16);
+      buf.u32(height << 16);
+    });
+    if empty > 0 || media_start > 0 {
+      write_box(buf, b"edts", |buf| {
+        write_full_box(buf, b"elst", 1, 0, |buf| {
+          buf.u32(if empty > 0 { 2 } else { 1 });
+          if empty > 0 {
+            buf.u64(empty);
+            buf.u64(u64::MAX); // media_time = -1 空白
+            buf.u32(0x10000);
+          }
+          buf.u64(shown);
+          buf.u64(media_start);
+          buf.u32(0x10000);
+        });
+      });
+    }
+    write_box(buf, b"mdia", |buf| {
+      write_full_box(buf, b"mdhd", 1, 0, |buf| {
+        buf.u64(0);
+        buf.u64(0);
+        buf.u32(track.timescale);
+        buf.u64(media_duration);
+        buf.u16(0x55C4); // und
+        buf.u16(0);
+      });
+      let (handler, name) = match track.is_video() {
+        true => (b"vide", &b"VideoHandler\0"[..]),
+        false => (b"soun", &b"SoundHandler\0"[..]),
+      };
+      write_full_box(buf, b"hdlr", 0, 0, |buf| {
+        buf.u32(0);
+        buf.extend_from_slice(handler);
+        buf.extend_from_slice(&[0; 12]);
+        buf.extend_from_slice(name);
+      });
+      write_box(buf, b"minf", |buf| {
+        match track.is_video() {
+          true => write_full_box(buf, b"vmhd", 0, 1, |buf| {
+            buf.extend_from_slice(&[0; 8]);
+          }),
+          false => write_full_box(buf, b"smhd", 0, 0, |buf| {
+            buf.u32(0);
+          }),
+        }
+        write_box(buf, b"dinf", |buf| {
+          write_full_box(buf, b"dref", 0, 0, |buf| {
+            buf.u32(1);
+            write_full_box(buf, b"url ", 0, 1, |_| {});
+          });
+        });
+        write_stbl(buf, track, &durations);
+      });
+    });
+  });
+}
+
+fn write_visual_entry(
+  buf: &mut Vec<u8>, name: &[u8; 4], config_name: &[u8; 4], width: u32,
+  height: u32, config: &[u8],
+) {
+  write_box(buf, name, |buf| {
+    buf.extend_from_slice(&[0; 6]);
+    buf.u16(1); // data_reference_index
+    buf.extend_from_slice(&[0; 16]);
+    buf.u16(width as u16);
+    buf.u16(height as u16);
+    buf.u32(0x480000);
+    buf.u32(0x480000);
+    buf.u32(0);
+    buf.u16(1);
+    buf.extend_from_slice(&[0; 32]);
+    buf.u16(0x18);
+    buf.u16(0xFFFF);
+    write_box(buf, config_name, |buf| buf.extend_from_slice(config));
+  });
+}
+
+fn write_sample_entry(buf: &mut Vec<u8>, config: &TrackConfig) {
+  match config {
+    TrackConfig::Avc {
+      width,
+      height,
+      config,
+    } => write_visual_entry(buf, b"avc1", b"avcC", *width, *height, config),
+    // 参数集只放在 hvcC 中
+    TrackConfig::Hevc {
+      width,
+      height,
+      config,
+    } => write_visual_entry(buf, b"hvc1", b"hvcC", *width, *height, config),
+    TrackConfig::Aac {
+      sample_rate,
+      channels,
+      config,
+    } => {
+      write_box(buf, b"mp4a", |buf| {
+        buf.extend_from_slice(&[0; 6]);
+        buf.u16(1);
+        buf.extend_from_slice(&[0; 8]);
+        buf.u16(*channels);
+        buf.u16(16);
+        buf.u32(0);
+        buf.u32(sample_rate << 16);
+        write_full_box(buf, b"esds", 0, 0, |buf| {
+          // ES_Descriptor 包含 DecoderConfigDescriptor 和 SLConfigDescriptor
+          let decoder_specific =
+            [&[0x05, config.len() as u8][..], config].concat();
+          let mut decoder_config =
+            vec![0x04, 13 + decoder_specific.len() as u8];
+          decoder_config.push(0x40); // MPEG-4 Audio
+          decoder_config.push(0x15); // AudioStream
+          decoder_config.extend_from_slice(&[0; 11]);
+          decoder_config.extend_from_slice(&decoder_specific);
+          let sl_config = [0x06, 0x01, 0x02];
+          buf.push(0x03);
+          buf.push((3 + decoder_config.len() + sl_config.len()) as u8);
+          buf.u16(0);
+          buf.push(0);
+          buf.extend_from_slice(&decoder_config);
+          buf.extend_from_slice(&sl_config);
+        });
+      });
+    }
+  }
+}
+
+fn write_stbl(buf: &mut Vec<u8>, track: &Mp4Track, durations: &[u32]) {
+  write_box(buf, b"stbl", |buf| {
+    write_full_box(buf, b"stsd", 0, 0, |buf| {
+      buf.u32(track.configs.len() as u32);
+      for config in &track.configs {
+        write_sample_entry(buf, config);
+      }
+    });
+    let stts = run_length(durations.iter().copied());
+    write_full_box(buf, b"stts", 0, 0, |buf| {
+      buf.u32(stts.len() as u32);
+      for (count, delta) in stts {
+        buf.u32(count);
+        buf.u32(delta);
+      }
+    });
+    if track.samples.iter().any(|sample| sample.cts != 0) {
+      let ctts = run_length(
+        track
+          .samples
+          .iter()
+          .map(|sample| sample.cts.clamp(0, u32::MAX as i64) as u32),
+      );
+      write_full_box(buf, b"ctts", 0, 0, |buf| {
+        buf.u32(ctts.len() as u32);
+        for (count, offset) in ctts {
+          buf.u32(count);
+          buf.u32(offset);
+        }
+      });
+    }
+    if track.samples.iter().any(|sample| !sample.keyframe) {
+      let keyframes: Vec<_> = (1..)
+        .zip(&track.samples)
+        .filter(|(_, sample)| sample.keyframe)
+        .map(|(index, _)| index)
+        .collect();
+      write_full_box(buf, b"stss", 0, 0, |buf| {
+        buf.u32(keyframes.len() as u32);
+        keyframes.into_iter().for_each(|index| buf.u32(index));
+      });
+    }
+    // 每个样本单独作为一个 chunk sample entry 变化的地方另起一项
+    let mut descriptions: Vec<(u32, u32)> = Vec::new();
+    for (chunk, sample) in (1..).zip(&track.samples) {
+      if descriptions.last().map(|(_, last)| *last) != Some(sample.description)
+      {
+        descriptions.push((chunk, sample.description));
+      }
+    }
+    write_full_box(buf, b"stsc", 0, 0, |buf| {
+      buf.u32(descriptions.len() as u32);
+      for (first_chunk, description) in descriptions {
+        buf.u32(first_chunk);
+        buf.u32(1);
+        buf.u32(description);
+      }
+    });
+    write_full_box(buf, b"stsz", 0, 0, |buf| {
+      buf.u32(0);
+      buf.u32(track.samples.len() as u32);
+      track.samples.iter().for_each(|sample| buf.u32(sample.size));
+    });
+    write_full_box(buf, b"co64", 0, 0, |buf| {
+      buf.u32(track.samples.len() as u32);
+      track
+        .samples
+        .iter()
+        .for_each(|sample| buf.u64(sample.offset));
+    });
+  });
+}
+
+fn run_length(values: impl Iterator<Item = u32>) -> Vec<(u32, u32)> {
+  let mut runs: Vec<(u32, u32)> = Vec::new();
+  for value in values {
+    match runs.last_mut() {
+      Some((count, last)) if *last == value => *count += 1,
+      _ => runs.push((1, value)),
+    }
+  }
+  runs
+}
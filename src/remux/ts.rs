@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+pub const TS_PACKET_SIZE: usize = 188;
+pub const TS_SYNC_BYTE: u8 = 0x47;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamKind {
+  H264,
+  H265,
+  Aac,
+}
+
+impl StreamKind {
+  fn from_stream_type(stream_type: u8) -> Option<Self> {
+    match stream_type {
+      0x1B => Some(StreamKind::H264),
+      0x24 => Some(StreamKind::H265),
+      0x0F => Some(StreamKind::Aac),
+      _ => None,
+    }
+  }
+}
+
+// 无法转换的音视频流 忽略后输出的文件会缺少音频或者视频
+fn unsupported_media_name(stream_type: u8) -> Option<&'static str> {
+  match stream_type {
+    0x01 => Some("MPEG-1 Video"),
+    0x02 => Some("MPEG-2 Video"),
+    0x03 => Some("MPEG-1 Audio"),
+    0x04 => Some("MPEG-2 Audio"),
+    0x10 => Some("MPEG-4 Video"),
+    0x11 => Some("AAC LATM"),
+    0x81 => Some("AC-3"),
+    0x87 => Some("E-AC-3"),
+    _ => None,
+  }
+}
+
+// 一个完整的 PES 包 时间戳单位为 90kHz
+#[derive(Debug, Clone)]
+pub struct Pes {
+  pub kind: StreamKind,
+  pub pts: Option<u64>,
+  pub dts: Option<u64>,
+  pub data: Vec<u8>,
+}
+
+// 只处理 PAT/PMT 和支持的音视频流 其余的 PID 直接忽略
+#[derive(Debug, Default)]
+pub struct TsDemuxer {
+  pmt_pid: Option<u16>,
+  streams: HashMap<u16, StreamKind>,
+  // 正在拼接的 PES
+  buffers: HashMap<u16, Vec<u8>>,
+  // 已经提示过不支持的 stream_type
+  unsupported: Vec<u8>,
+  // PMT 中出现的不支持的音视频流
+  pub unsupported_media: Option<(u8, &'static str)>,
+}
+
+// PSI 的 section 数据 跳过 pointer_field
+fn psi_section(payload: &[u8], start: bool) -> Option<&[u8]> {
+  let payload = match start {
+    true => payload.get(1 + *payload.first()? as usize..)?,
+    false => return None,
+  };
+  let length =
+    ((payload.get(1)? & 0x0F) as usize) << 8 | *payload.get(2)? as usize;
+  // 从 table_id 开始 不包含最后4个字节的 CRC
+  payload.get(..(3 + length).checked_sub(4)?)
+}
+
+fn read_timestamp(data: &[u8]) -> u64 {
+  ((data[0] as u64 >> 1) & 0x07) << 30
+    | (data[1] as u64) << 22
+    | (data[2] as u64 >> 1) << 15
+    | (data[3] as u64) << 7
+    | data[4] as u64 >> 1
+}
+
+fn parse_pes(kind: StreamKind, data: &[u8]) -> Option<Pes> {
+  if data.len() < 9 || data[..3] != [0, 0, 1] {
+    return None;
+  }
+  let flags = data[7] >> 6;
+  let payload = 9 + data[8] as usize;
+  let pts = match flags & 2 {
+    0 => None,
+    _ => Some(read_timestamp(data.get(9..14)?)),
+  };
+  let dts = match flags {
+    3 => Some(read_timestamp(data.get(14..19)?)),
+    _ => None,
+  };
+  Some(Pes {
+    kind,
+    pts,
+    dts,
+    data: data.get(payload..)?.to_vec(),
+  })
+}
+
+impl TsDemuxer {
+  pub fn push(&mut self, data: &[u8], output: &mut Vec<Pes>) {
+    let mut position = 0;
+    while position + TS_PACKET_SIZE <= data.len() {
+      if data[position] != TS_SYNC_BYTE {
+        // 重新寻找同步字节
+        position += 1;
+        continue;
+      }
+      self.push_packet(&data[position..position + TS_PACKET_SIZE], output);
+      position += TS_PACKET_SIZE;
+    }
+  }
+
+  fn push_packet(&mut self, packet: &[u8], output: &mut Vec<Pes>) {
+    let start = packet[1] & 0x40 != 0;
+    let pid = ((packet[1] as u16 & 0x1F) << 8) | packet[2] as u16;
+    let adaptation = (packet[3] >> 4) & 0x03;
+    let mut offset = 4;
+    if adaptation & 2 != 0 {
+      offset += 1 + packet[4] as usize;
+    }
+    if adaptation & 1 == 0 || offset >= packet.len() {
+      return;
+    }
+    let payload = &packet[offset..];
+
+    if pid == 0 {
+      if let Some(section) = psi_section(payload, start) {
+        // 跳过8字节的表头 每个节目4个字节
+        for program in section.get(8..).unwrap_or_default().chunks_exact(4) {
+          let number = u16::from_be_bytes([program[0], program[1]]);
+          if number != 0 {
+            self.pmt_pid =
+              Some(((program[2] as u16 & 0x1F) << 8) | program[3] as u16);
+            break;
+          }
+        }
+      }
+    } else if Some(pid) == self.pmt_pid {
+      if let Some(section) = psi_section(payload, start) {
+        self.parse_pmt(section);
+      }
+    } else if let Some(&kind) = self.streams.get(&pid) {
+      if start {
+        if let Some(pes) = self.buffers.remove(&pid) {
+          output.extend(parse_pes(kind, &pes));
+        }
+        self.buffers.insert(pid, payload.to_vec());
+      } else if let Some(buffer) = self.buffers.get_mut(&pid) {
+        buffer.extend_from_slice(payload);
+      }
+    }
+  }
+
+  fn parse_pmt(&mut self, section: &[u8]) {
+    if section.len() < 12 {
+      return;
+    }
+    let info_length =
+      ((section[10] as usize & 0x0F) << 8) | section[11] as usize;
+    let mut position = 12 + info_length;
+    while position + 5 <= section.len() {
+      let stream_type = section[position];
+      let pid = ((section[position + 1] as u16 & 0x1F) << 8)
+        | section[position + 2] as u16;
+      let es_info_length = ((section[position + 3] as usize & 0x0F) << 8)
+        | section[position + 4] as usize;
+      position += 5 + es_info_length;
+      match StreamKind::from_stream_type(stream_type) {
+        Some(kind) => {
+          self.streams.insert(pid, kind);
+        }
+        None if !self.unsupported.contains(&stream_type) => {
+          log::warn!("ignore unsupported ts stream type: {:#04x}", stream_type);
+          self.unsupported.push(stream_type);
+          if let Some(name) = unsupported_media_name(stream_type) {
+            self.unsupported_media.get_or_insert((stream_type, name));
+          }
+        }
+        None => {}
+      }
+    }
+  }
+
+  // 分片结束时把还没有输出的 PES 输出
+  pub fn flush(&mut self, output: &mut Vec<Pes>) {
+    let mut pids: Vec<_> = self.buffers.keys().copied().collect();
+    pids.sort();
+    for pid in pids {
+      let pes = self.buffers.remove(&pid).unwrap_or_default();
+      output.extend(parse_pes(self.streams[&pid], &pes));
+    }
+  }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashSet;
+
+// X-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000
+// 返回 MPEGTS 和 LOCAL 的差值 单位秒
+fn timestamp_map(line: &str) -> Option<f64> {
+  let value = line.strip_prefix("X-TIMESTAMP-MAP=")?;
+  let mut mpegts = 0.0;
+  let mut local = 0.0;
+  for item in value.split(',') {
+    match item.split_once(':')? {
+      ("MPEGTS", ts) => {
+        mpegts = ts.trim().parse::<u64>().ok()? as f64 / 90000.0
+      }
+      ("LOCAL", time) => local = parse_time(time)?,
+      _ => {}
+    }
+  }
+  Some(mpegts - local)
+}
+
+// hh:mm:ss.ttt 或者 mm:ss.ttt
+fn parse_time(value: &str) -> Option<f64> {
+  value.trim().split(':').try_fold(0.0, |total, part| {
+    Some(total * 60.0 + part.parse::<f64>().ok()?)
+  })
+}
+
+fn format_time(seconds: f64) -> String {
+  let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+  format!(
+    "{:02}:{:02}:{:02}.{:03}",
+    millis / 3_600_000,
+    millis / 60_000 % 60,
+    millis / 1000 % 60,
+    millis % 1000
+  )
+}
+
+// 平移 cue 的时间 "00:00:01.000 --> 00:00:02.000 align:start"
+fn shift_cue(block: &str, shift: f64) -> String {
+  block
+    .lines()
+    .map(|line| {
+      let Some((start, rest)) = line.split_once("-->") else {
+        return line.to_string();
+      };
+      let rest = rest.trim_start();
+      let (end, settings) = rest.split_once(' ').unwrap_or((rest, ""));
+      match (parse_time(start), parse_time(end)) {
+        (Some(start), Some(end)) => format!(
+          "{} --> {} {}",
+          format_time(start + shift),
+          format_time(end + shift),
+          settings
+        )
+        .trim_end()
+        .to_string(),
+        _ => line.to_string(),
+      }
+    })
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+// 每个分片都是完整的 WebVTT 文件 合并后只保留一个文件头
+// 分片的 X-TIMESTAMP-MAP 不同时 按照和第一个分片的差值平移时间
+// 跨越分片边界的 cue 会在相邻的分片中重复出现 只保留一个
+pub fn merge_webvtt(segments: &[String]) -> String {
+  let mut output = String::from("WEBVTT\n");
+  let mut base: Option<f64> = None;
+  let mut seen = HashSet::new();
+  for segment in segments {
+    let segment = segment.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    let (header, body) = segment.split_once("\n\n").unwrap_or((&segment, ""));
+    let offset = header.lines().find_map(timestamp_map).unwrap_or(0.0);
+    let shift = offset - *base.get_or_insert(offset);
+    for block in body.split("\n\n") {
+      let block = block.trim_matches('\n');
+      // STYLE 和 REGION 只能出现在第一个 cue 之前
+      let header_block = ["NOTE", "STYLE", "REGION"]
+        .iter()
+        .any(|name| block.starts_with(name));
+      if block.is_empty() || (header_block && !seen.is_empty()) {
+        continue;
+      }
+      let block = shift_cue(block, shift);
+      if seen.insert(block.clone()) {
+        output.push('\n');
+        output.push_str(&block);
+        output.push('\n');
+      }
+    }
+  }
+  output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_merge_webvtt() {
+    let segments = [
+      "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\n1\n00:00:01.000 --> 00:00:02.500 align:start\nHello\n\n00:00:05.000 --> 00:00:07.000\nacross\n",
+      "WEBVTT\r\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\r\n\r\n00:00:05.000 --> 00:00:07.000\r\nacross\r\n\r\n00:00:08.000 --> 00:00:09.000\r\nWorld\r\n",
+      "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:1800000,LOCAL:00:00:00.000\n\n00:01.000 --> 00:02.000\nShifted\n",
+    ]
+    .map(str::to_string);
+    assert_eq!(
+      merge_webvtt(&segments),
+      "WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.500 align:start\nHello\n\n00:00:05.000 --> 00:00:07.000\nacross\n\n00:00:08.000 --> 00:00:09.000\nWorld\n\n00:00:11.000 --> 00:00:12.000\nShifted\n"
+    );
+  }
+}
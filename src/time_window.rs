@@ -0,0 +1,184 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BzError, BzResult};
+
+// 允许下载的时间段 例如工作日 01:00-07:00
+// 结束时间早于开始时间时跨过午夜 星期按照开始的那一天
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BzTimeWindow {
+  // 为空表示每天
+  pub days: Vec<Weekday>,
+  pub start: NaiveTime,
+  pub end: NaiveTime,
+}
+
+impl BzTimeWindow {
+  fn on_day(&self, day: Weekday) -> bool {
+    self.days.is_empty() || self.days.contains(&day)
+  }
+
+  pub fn contains(&self, now: NaiveDateTime) -> bool {
+    let (day, time) = (now.weekday(), now.time());
+    match self.start.cmp(&self.end) {
+      std::cmp::Ordering::Less => {
+        self.on_day(day) && self.start <= time && time < self.end
+      }
+      // 开始和结束相同表示一整天
+      std::cmp::Ordering::Equal => self.on_day(day),
+      std::cmp::Ordering::Greater => {
+        (self.on_day(day) && time >= self.start)
+          || (self.on_day(day.pred()) && time < self.end)
+      }
+    }
+  }
+}
+
+impl std::fmt::Display for BzTimeWindow {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    // 连续的星期合并成 1-5
+    let mut days = self
+      .days
+      .iter()
+      .map(|day| day.number_from_monday())
+      .collect::<Vec<_>>();
+    days.sort();
+    days.dedup();
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for day in days {
+      match ranges.last_mut() {
+        Some((_, end)) if *end + 1 == day => *end = day,
+        _ => ranges.push((day, day)),
+      }
+    }
+    let ranges = ranges
+      .into_iter()
+      .map(|(start, end)| match start == end {
+        true => format!("{}", start),
+        false => format!("{}-{}", start, end),
+      })
+      .collect::<Vec<_>>();
+    if !ranges.is_empty() {
+      write!(f, "{} ", ranges.join(","))?;
+    }
+    write!(
+      f,
+      "{}-{}",
+      self.start.format("%H:%M"),
+      self.end.format("%H:%M")
+    )
+  }
+}
+
+fn parse_error(window: &str, reason: &str) -> BzError {
+  BzError::Parse {
+    reason: format!("invalid time window {}: {}", window, reason),
+  }
+}
+
+fn parse_day(window: &str, day: &str) -> BzResult<u32> {
+  match day.trim().parse::<u32>() {
+    Ok(day @ 1..=7) => Ok(day),
+    _ => Err(parse_error(window, &format!("invalid weekday {}", day))),
+  }
+}
+
+// 星期用 1-7 表示周一到周日 例如 "1-5 01:00-07:00"
+// 省略星期表示每天
+fn parse_time_window(window: &str) -> BzResult<BzTimeWindow> {
+  let (days, times) = match window.trim().rsplit_once(' ') {
+    Some((days, times)) => (days.trim(), times),
+    None => ("", window.trim()),
+  };
+  let mut weekdays = Vec::new();
+  for item in days.split(',').filter(|item| !item.trim().is_empty()) {
+    let (first, last) = match item.split_once('-') {
+      Some((first, last)) => {
+        (parse_day(window, first)?, parse_day(window, last)?)
+      }
+      None => {
+        let day = parse_day(window, item)?;
+        (day, day)
+      }
+    };
+    if first > last {
+      return Err(parse_error(window, &format!("invalid range {}", item)));
+    }
+    weekdays.extend(
+      (first..=last).map(|day| Weekday::try_from(day as u8 - 1).unwrap()),
+    );
+  }
+  let (start, end) = times
+    .split_once('-')
+    .ok_or_else(|| parse_error(window, "expect HH:MM-HH:MM"))?;
+  let parse_time = |time: &str| {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+      .map_err(|err| parse_error(window, &err.to_string()))
+  };
+  Ok(BzTimeWindow {
+    days: weekdays,
+    start: parse_time(start)?,
+    end: parse_time(end)?,
+  })
+}
+
+// 多个时间段用分号分隔
+pub fn parse_time_windows(content: &str) -> BzResult<Vec<BzTimeWindow>> {
+  content
+    .split(';')
+    .filter(|window| !window.trim().is_empty())
+    .map(parse_time_window)
+    .collect()
+}
+
+pub fn format_time_windows(windows: &[BzTimeWindow]) -> String {
+  windows
+    .iter()
+    .map(|window| window.to_string())
+    .collect::<Vec<_>>()
+    .join("; ")
+}
+
+// 没有设置时间段时随时可以下载
+pub fn is_open(windows: &[BzTimeWindow], now: NaiveDateTime) -> bool {
+  windows.is_empty() || windows.iter().any(|window| window.contains(now))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn at(date: &str) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap()
+  }
+
+  #[test]
+  fn test_time_windows() {
+    let windows =
+      parse_time_windows("1-5 01:00-07:00; 6,7 22:00-02:00").unwrap();
+    assert_eq!(windows[0].days.len(), 5);
+    assert_eq!(
+      format_time_windows(&windows),
+      "1-5 01:00-07:00; 6-7 22:00-02:00"
+    );
+    // 2025-03-03 是周一
+    assert!(is_open(&windows, at("2025-03-03 01:00")));
+    assert!(!is_open(&windows, at("2025-03-03 07:00")));
+    assert!(!is_open(&windows, at("2025-03-03 23:00")));
+    // 周日 22:00 开始的时间段到周一 02:00 结束
+    assert!(is_open(&windows, at("2025-03-09 23:30")));
+    assert!(is_open(&windows, at("2025-03-10 00:30")));
+    // 周五晚上不在时间段内
+    assert!(!is_open(&windows, at("2025-03-07 23:30")));
+    assert!(!is_open(&windows, at("2025-03-08 01:30")));
+
+    let every_day = parse_time_windows("00:00-00:00").unwrap();
+    assert!(every_day[0].days.is_empty());
+    assert!(is_open(&every_day, at("2025-03-05 12:00")));
+    assert!(is_open(&[], at("2025-03-05 12:00")));
+
+    assert!(parse_time_windows("8 01:00-07:00").is_err());
+    assert!(parse_time_windows("5-1 01:00-07:00").is_err());
+    assert!(parse_time_windows("1-5 01:00").is_err());
+  }
+}
@@ -6,21 +6,33 @@ use iced::futures::stream::Stream;
 use tray_icon::TrayIcon;
 use tray_icon::{
   TrayIconBuilder,
-  menu::{Menu, MenuId, MenuItem},
+  menu::{CheckMenuItem, Menu, MenuId, MenuItem, Submenu},
 };
 
 use crate::bz_downloader::Message;
+use crate::rate_limit::BzSpeedLimit;
 
 #[derive(Clone)]
 pub struct TrayState {
   pub tray_icon: TrayIcon,
   pub menuids: MenuIdCollection,
+  // 限速子菜单 只勾选当前的限速
+  pub speed_limit_items: Vec<(BzSpeedLimit, CheckMenuItem)>,
+}
+
+impl TrayState {
+  pub fn check_speed_limit(&self, speed_limit: BzSpeedLimit) {
+    for (limit, item) in &self.speed_limit_items {
+      item.set_checked(*limit == speed_limit);
+    }
+  }
 }
 
 #[derive(Clone)]
 pub enum BzMenuType {
   Display,
   Hide,
+  SpeedLimit(BzSpeedLimit),
   Exit,
   Unknown,
 }
@@ -46,31 +58,53 @@ impl MenuIdCollection {
   }
 }
 
-pub fn init_tray_menu() -> (Menu, MenuIdCollection) {
+pub fn init_tray_menu()
+-> (Menu, MenuIdCollection, Vec<(BzSpeedLimit, CheckMenuItem)>) {
   let tray_menu = Menu::new();
   let item1 = MenuItem::new("显示", true, None);
   let item2 = MenuItem::new("隐藏", true, None);
   let item3 = MenuItem::new("退出", true, None);
+  let mut menuids = MenuIdCollection::new();
+
+  let speed_limit_menu = Submenu::new("限速", true);
+  let speed_limit_items = BzSpeedLimit::PRESETS
+    .into_iter()
+    .map(|limit| {
+      let item = CheckMenuItem::new(
+        limit.to_string(),
+        true,
+        limit == BzSpeedLimit::default(),
+        None,
+      );
+      speed_limit_menu.append(&item).unwrap();
+      menuids.insert(item.id().clone(), BzMenuType::SpeedLimit(limit));
+      (limit, item)
+    })
+    .collect();
 
   tray_menu.append(&item1).unwrap();
   tray_menu.append(&item2).unwrap();
+  tray_menu.append(&speed_limit_menu).unwrap();
   tray_menu.append(&item3).unwrap();
-  let mut menuids = MenuIdCollection::new();
   menuids.insert(item1.id().clone(), BzMenuType::Display);
   menuids.insert(item2.id().clone(), BzMenuType::Hide);
   menuids.insert(item3.id().clone(), BzMenuType::Exit);
-  (tray_menu, menuids)
+  (tray_menu, menuids, speed_limit_items)
 }
 
 pub fn init_tray_icon() -> TrayState {
-  let (tray_menu, menuids) = init_tray_menu();
+  let (tray_menu, menuids, speed_limit_items) = init_tray_menu();
   let tray_icon = TrayIconBuilder::new()
     .with_menu(Box::new(tray_menu.clone()))
     .with_tooltip("BzDownloader")
     // .with_icon(icon)
     .build()
     .unwrap();
-  TrayState { tray_icon, menuids }
+  TrayState {
+    tray_icon,
+    menuids,
+    speed_limit_items,
+  }
 }
 
 pub fn tray_subscription() -> impl Stream<Item = Message> {
@@ -1,9 +1,9 @@
 use std::time::Duration;
 
 use crate::bz_task::{
-  BzTaskControl, BzTaskFeedBack, BzTaskId, BzTaskInfo,
-  BzTaskInfoFeedBackMessage, Task, TaskProgress,
+  BzSpeedMeter, BzTaskController, BzTaskInfo, Task, TaskProgress,
 };
+use crate::error::BzResult;
 
 pub struct ZfsTaskProgress {
   pub downloaded: Vec<String>,
@@ -67,31 +67,33 @@ impl Task for ZfsTask {
     Self::new(task_info)
   }
 
-  async fn prepare(&mut self) {}
+  async fn prepare(&mut self) -> BzResult<()> {
+    Ok(())
+  }
 
-  async fn start(
-    &mut self, task_id: BzTaskId,
-    _control_receiver: tokio::sync::mpsc::Receiver<BzTaskControl>,
-    feedback_sender: tokio::sync::mpsc::Sender<BzTaskFeedBack>,
-  ) -> bool {
+  async fn start(&mut self, controller: &mut BzTaskController) -> BzResult<()> {
     let mut i = 0;
+    let meter = BzSpeedMeter::new();
     loop {
       if i == 10 {
-        return true;
+        return Ok(());
       }
 
       i = i + 1;
       tokio::time::sleep(Duration::from_secs(2)).await;
-      let _ = feedback_sender
-        .send(BzTaskFeedBack::TaskInfo(BzTaskInfoFeedBackMessage {
-          task_id: task_id.clone(),
-          progress: (i as f32 / 10.0),
-        }))
+      controller
+        .send_info(meter.feedback(
+          controller.task_id(),
+          i as f32 / 10.0,
+          0,
+          None,
+          controller.is_paused(),
+        ))
         .await;
     }
   }
 
-  async fn finish(&mut self) {
+  async fn finish(&mut self) -> BzResult<()> {
     todo!()
   }
 }